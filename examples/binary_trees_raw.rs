@@ -6,14 +6,13 @@ arbitrary_self_types, // Unfortunately this is required for methods on Gc refs
 //! See [BinaryTrees] for more information.
 use slog::{Logger, Drain, o};
 use std::cell::Cell;
-use mps::format::{RawFormatMethods, ScanState, ObjectFormat};
-use std::ffi::{c_void, CStr};
+use mps::format::{ObjectFormat, MpsFormat, Fixable, ScanFixState};
+use std::ffi::c_void;
 use mps::arena::{VirtualMemoryArenaClass, Arena};
 use mps::pools::Pool;
 use mps::pools::mark_sweep::{AutoMarkSweep};
 use mps::MpsError;
 use mps::alloc::AllocationPoint;
-use std::alloc::Layout;
 
 use argh::FromArgs;
 use std::str::FromStr;
@@ -24,92 +23,30 @@ struct Tree<'gc> {
     // NOTE: This is horribly unsafe
     children: Cell<Option<(&'gc Tree<'gc>, &'gc Tree<'gc>)>>,
 }
+unsafe impl Fixable for Tree<'_> {
+    unsafe fn fix(&mut self, state: &mut ScanFixState) -> Result<(), i32> {
+        self.children.fix(state)
+    }
+}
 /// The special object format we use
+#[derive(MpsFormat)]
+#[repr(usize)]
 enum TreeObject {
+    #[mps(forward)]
     Forwarding {
         new: *mut TreeObject,
         size: usize
     },
-    Tree(Tree<'static>),
+    Tree(#[mps(ref)] Tree<'static>),
+    #[mps(pad)]
     Padding {
         size: usize
     }
 }
-impl TreeObject {
-    fn size(&self) -> usize {
-        let res = match *self {
-            TreeObject::Forwarding { size, .. } => size,
-            TreeObject::Tree(Tree { .. }) => Layout::new::<TreeObject>().pad_to_align().size(),
-            TreeObject::Padding { size } => size,
-        };
-        debug_assert!(res >= std::mem::size_of::<TreeObject>());
-        res
-    }
-}
-unsafe impl RawFormatMethods for TreeObject {
-    type Obj = TreeObject;
-    const ALIGNMENT: usize = std::mem::align_of::<Self>();
-
-    unsafe extern fn class_ptr(obj: *mut Self::Obj) -> *mut c_void {
-        match *obj {
-            TreeObject::Forwarding { .. } | TreeObject::Padding { .. } => std::ptr::null_mut(),
-            TreeObject::Tree(_) => CStr::from_bytes_with_nul(b"Tree\0").unwrap().as_ptr() as *mut c_void
-        }
-    }
-
-    unsafe extern fn forward(old: *mut Self::Obj, new: *mut Self::Obj) {
-        old.write(TreeObject::Forwarding {
-            new, size: (*old).size()
-        })
-    }
-
-    unsafe extern fn is_forwarded(old: *mut Self::Obj) -> *mut Self::Obj {
-        match old.read() {
-            TreeObject::Forwarding { new, .. } => new,
-            _ => std::ptr::null_mut()
-        }
-    }
-
-    unsafe extern fn pad(addr: *mut Self::Obj, size: usize) {
-        addr.write(TreeObject::Padding {
-            size: size
-        })
-    }
-
-    unsafe extern fn scan(mut state: ScanState, mut base: *mut Self::Obj, limit: *mut Self::Obj) -> i32 {
-        state.fix_with(|state| {
-            while base < limit {
-                let obj: *mut TreeObject = base;
-                match *obj {
-                    TreeObject::Forwarding { ref mut new, size: _ } => {
-                        // Forwarding objects must be scanned
-                        state.fix(new)?;
-                    },
-                    TreeObject::Tree(Tree { ref mut children }) => {
-                        if let Some((left, right)) = children.get() {
-                            let mut left = left as *const _ as *mut Tree;
-                            let mut right = right as *const _ as *mut Tree;
-                            state.fix(&mut left)?;
-                            state.fix(&mut right)?;
-                            children.set(Some((&*left, &*right)));
-                        }
-                    }
-                    TreeObject::Padding { size: _ } => {},
-                }
-                base = base.add(1);
-            }
-            Ok(())
-        })
-    }
-
-    unsafe extern fn skip(addr: *mut Self::Obj) -> *mut Self::Obj {
-        (addr as *mut u8).add(addr.read().size()) as *mut Self
-    }
-}
 
 pub struct RawMpsCollector<'arena> {
     arena: &'arena Arena,
-    allocation_point: AllocationPoint,
+    allocation_point: AllocationPoint<'arena>,
 }
 
 fn item_check(tree: &Tree) -> i32 {
@@ -250,7 +187,7 @@ fn main() {
     });
 
     println!("long lived tree of depth {}\t check: {}", max_depth, item_check(&long_lived_tree));
-    drop(gc.allocation_point);
+    drop(gc);
     drop(root);
     drop(thread);
     drop(pool);
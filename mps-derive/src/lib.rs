@@ -0,0 +1,550 @@
+//! Derive macros for the [mps](https://github.com/DuckLogic/rust-mps) crate.
+//!
+//! See `#[derive(MpsFormat)]` for details.
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Ident, Span, TokenStream as TokenStream2};
+use quote::{quote, format_ident};
+use syn::ext::IdentExt;
+use syn::parse::ParseStream;
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, Attribute, Data, DeriveInput, Error,
+    Expr, Index, Lit, Member, Meta, NestedMeta, Token, Type
+};
+
+/// Derive the format methods for an enum object model
+///
+/// This always implements `RawFormatMethods` and `ClassFormatMethods`.
+/// If there is a `#[mps(forward)]` variant, it implements `MovingFormatMethods`,
+/// and if there is a `#[mps(pad)]` variant it implements `PaddingFormatMethods`.
+///
+/// ## Attributes
+/// - `#[mps(align = N)]` on the enum overrides the format's alignment
+///   (by default this is `align_of::<Self>()`, or the minimum padding size if there's a padding variant).
+///   This must be a power of two, and at least `align_of::<Self>()`.
+/// - `#[mps(forward)]` marks the forwarding variant,
+///   which must have exactly two named fields `new: *mut Self` and `size: usize`
+/// - `#[mps(pad)]` marks the padding variant,
+///   which must have exactly one named field `size: usize`.
+///   This requires a `#[repr(<int>)]` enum.
+/// - `#[mps(ref)]` marks a field holding references,
+///   which must implement `mps::format::Fixable`
+/// - `#[mps(trailing = len)]` marks a final `[T; 0]` field as a variable-sized
+///   trailing array, whose length is given by the field `len` of the same variant.
+///   This requires a `#[repr(C)]` or `#[repr(<int>)]` enum.
+///
+/// The MPS can request padding objects as small as the alignment,
+/// which may be smaller than the whole enum.
+/// So padding objects only consist of the tag and the `size` field,
+/// which are accessed through raw pointers (before the object is ever referenced as a whole enum).
+/// The alignment must be at least twice the larger of `size_of::<usize>()` and `align_of::<Self>()`,
+/// which is enough to hold them.
+#[proc_macro_derive(MpsFormat, attributes(mps))]
+pub fn derive_mps_format(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match impl_derive_mps_format(&input) {
+        Ok(res) => res.into(),
+        Err(err) => err.to_compile_error().into()
+    }
+}
+
+/// A single option in a `#[mps(...)]` attribute
+struct MpsOption {
+    name: Ident,
+    value: Option<Expr>
+}
+
+fn parse_options(attrs: &[Attribute]) -> Result<Vec<MpsOption>, Error> {
+    let mut res = Vec::new();
+    for attr in attrs {
+        if !attr.path.is_ident("mps") { continue }
+        attr.parse_args_with(|input: ParseStream| {
+            while !input.is_empty() {
+                // NOTE: Need `parse_any` since `ref` is a keyword
+                let name = Ident::parse_any(input)?;
+                let value = if input.peek(Token![=]) {
+                    input.parse::<Token![=]>()?;
+                    Some(input.parse::<Expr>()?)
+                } else {
+                    None
+                };
+                res.push(MpsOption { name, value });
+                if input.is_empty() { break }
+                input.parse::<Token![,]>()?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(res)
+}
+
+fn expect_flag(opt: &MpsOption) -> Result<(), Error> {
+    match opt.value {
+        None => Ok(()),
+        Some(ref value) => Err(Error::new(
+            value.span(),
+            format!("Option `{}` doesn't take a value", opt.name)
+        ))
+    }
+}
+
+const INT_REPRS: &[&str] = &[
+    "u8", "u16", "u32", "u64", "usize",
+    "i8", "i16", "i32", "i64", "isize"
+];
+
+fn has_c_layout(attrs: &[Attribute]) -> Result<bool, Error> {
+    for attr in attrs {
+        if !attr.path.is_ident("repr") { continue }
+        if let Meta::List(list) = attr.parse_meta()? {
+            for nested in &list.nested {
+                if let NestedMeta::Meta(Meta::Path(ref path)) = *nested {
+                    if path.is_ident("C") || INT_REPRS.iter().any(|repr| path.is_ident(repr)) {
+                        return Ok(true)
+                    }
+                }
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// The integer type of the tag, given by `#[repr(<int>)]`
+fn int_repr(attrs: &[Attribute]) -> Result<Option<Ident>, Error> {
+    for attr in attrs {
+        if !attr.path.is_ident("repr") { continue }
+        if let Meta::List(list) = attr.parse_meta()? {
+            for nested in &list.nested {
+                if let NestedMeta::Meta(Meta::Path(ref path)) = *nested {
+                    if let Some(ident) = path.get_ident() {
+                        if INT_REPRS.iter().any(|int| ident == int) {
+                            return Ok(Some(ident.clone()))
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum VariantKind {
+    Normal,
+    Forward,
+    Pad
+}
+
+struct FieldInfo {
+    member: Member,
+    binding: Ident,
+    is_ref: bool,
+    /// The length field, if this is a trailing array
+    trailing: Option<Member>
+}
+
+struct VariantInfo {
+    ident: Ident,
+    kind: VariantKind,
+    fields: Vec<FieldInfo>
+}
+impl VariantInfo {
+    fn find_field(&self, member: &Member) -> Option<&FieldInfo> {
+        self.fields.iter().find(|field| field.member == *member)
+    }
+    fn named_field(&self, name: &str) -> Option<&FieldInfo> {
+        self.find_field(&Member::Named(Ident::new(name, Span::call_site())))
+    }
+    fn expect_fields(&self, names: &[&str], span: Span) -> Result<(), Error> {
+        let matches = self.fields.len() == names.len() && names.iter()
+            .all(|name| self.named_field(name).is_some());
+        if !matches {
+            let kind = if self.kind == VariantKind::Forward { "forward" } else { "pad" };
+            return Err(Error::new(span, format!(
+                "A `#[mps({})]` variant must have exactly the named fields: {}",
+                kind, names.join(", ")
+            )))
+        }
+        if self.fields.iter().any(|field| field.is_ref || field.trailing.is_some()) {
+            return Err(Error::new(span, "Forwarding and padding variants can't have `#[mps(...)]` fields"))
+        }
+        Ok(())
+    }
+    /// A pattern binding only the specified fields (by reference)
+    fn pattern<'a>(&self, fields: impl IntoIterator<Item=&'a FieldInfo>) -> TokenStream2 {
+        let ident = &self.ident;
+        let bindings = fields.into_iter().map(|field| {
+            let member = &field.member;
+            let binding = &field.binding;
+            quote!(#member: #binding)
+        });
+        quote!(Self::#ident { #(#bindings,)* .. })
+    }
+}
+
+/// Whether the type is an array of length zero (`[T; 0]`)
+fn is_empty_array(ty: &Type) -> bool {
+    match *ty {
+        Type::Array(ref array) => match array.len {
+            Expr::Lit(ref lit) => match lit.lit {
+                Lit::Int(ref len) => len.base10_parse::<usize>().is_ok_and(|len| len == 0),
+                _ => false
+            },
+            _ => false
+        },
+        _ => false
+    }
+}
+
+fn parse_member(expr: &Expr) -> Result<Member, Error> {
+    match *expr {
+        Expr::Path(ref path) => {
+            if let Some(ident) = path.path.get_ident() {
+                return Ok(Member::Named(ident.clone()))
+            }
+        },
+        Expr::Lit(ref lit) => {
+            if let Lit::Int(ref index) = lit.lit {
+                return Ok(Member::Unnamed(Index {
+                    index: index.base10_parse()?,
+                    span: index.span()
+                }))
+            }
+        },
+        _ => {}
+    }
+    Err(Error::new(expr.span(), "Expected the name of a length field"))
+}
+
+fn impl_derive_mps_format(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let data = match input.data {
+        Data::Enum(ref data) => data,
+        _ => return Err(Error::new(
+            input.span(), "MpsFormat can only be derived for enums"
+        ))
+    };
+    let mut alignment = None;
+    for opt in parse_options(&input.attrs)? {
+        if opt.name == "align" {
+            match opt.value {
+                Some(ref value) => alignment = Some(quote!(#value)),
+                None => return Err(Error::new(opt.name.span(), "Expected `align = <value>`"))
+            }
+        } else {
+            return Err(Error::new(opt.name.span(), format!("Unknown option: {}", opt.name)))
+        }
+    }
+    let c_layout = has_c_layout(&input.attrs)?;
+    let tag_type = int_repr(&input.attrs)?;
+    let mut variants = Vec::new();
+    for variant in &data.variants {
+        let mut kind = VariantKind::Normal;
+        for opt in parse_options(&variant.attrs)? {
+            let new_kind = if opt.name == "forward" {
+                VariantKind::Forward
+            } else if opt.name == "pad" {
+                VariantKind::Pad
+            } else {
+                return Err(Error::new(opt.name.span(), format!("Unknown variant option: {}", opt.name)))
+            };
+            expect_flag(&opt)?;
+            if kind != VariantKind::Normal {
+                return Err(Error::new(opt.name.span(), "Conflicting variant options"))
+            }
+            kind = new_kind;
+        }
+        let mut fields = Vec::new();
+        let num_fields = variant.fields.len();
+        for (index, field) in variant.fields.iter().enumerate() {
+            let member = match field.ident {
+                Some(ref ident) => Member::Named(ident.clone()),
+                None => Member::Unnamed(Index { index: index as u32, span: field.span() })
+            };
+            let mut is_ref = false;
+            let mut trailing = None;
+            for opt in parse_options(&field.attrs)? {
+                if opt.name == "ref" {
+                    expect_flag(&opt)?;
+                    is_ref = true;
+                } else if opt.name == "trailing" {
+                    let len = match opt.value {
+                        Some(ref value) => parse_member(value)?,
+                        None => return Err(Error::new(opt.name.span(), "Expected `trailing = <length field>`"))
+                    };
+                    if !c_layout {
+                        return Err(Error::new(
+                            opt.name.span(),
+                            "Trailing arrays require `#[repr(C)]` or `#[repr(<int>)]`"
+                        ))
+                    }
+                    if index + 1 != num_fields {
+                        return Err(Error::new(field.span(), "A trailing array must be the last field"))
+                    }
+                    if !is_empty_array(&field.ty) {
+                        return Err(Error::new(field.ty.span(), "A trailing array must have type `[T; 0]`"))
+                    }
+                    trailing = Some(len);
+                } else {
+                    return Err(Error::new(opt.name.span(), format!("Unknown field option: {}", opt.name)))
+                }
+            }
+            fields.push(FieldInfo {
+                member, is_ref, trailing,
+                binding: format_ident!("__mps_field_{}", index),
+            });
+        }
+        let info = VariantInfo { ident: variant.ident.clone(), kind, fields };
+        match kind {
+            VariantKind::Forward => info.expect_fields(&["new", "size"], variant.span())?,
+            VariantKind::Pad => {
+                info.expect_fields(&["size"], variant.span())?;
+                if tag_type.is_none() {
+                    return Err(Error::new(
+                        variant.span(),
+                        "Padding variants require `#[repr(<int>)]`"
+                    ))
+                }
+            },
+            VariantKind::Normal => {
+                for field in &info.fields {
+                    if let Some(ref len) = field.trailing {
+                        if info.find_field(len).is_none() {
+                            return Err(Error::new(len.span(), "Unknown length field"))
+                        }
+                    }
+                }
+            }
+        }
+        if kind != VariantKind::Normal && variants.iter().any(|v: &VariantInfo| v.kind == kind) {
+            return Err(Error::new(variant.span(), "Duplicate forwarding/padding variant"))
+        }
+        variants.push(info);
+    }
+    let forward = variants.iter().find(|v| v.kind == VariantKind::Forward);
+    let pad = variants.iter().find(|v| v.kind == VariantKind::Pad);
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let format = quote!(::mps::format);
+    let raw_methods = quote!(<Self as #format::RawFormatMethods>);
+
+    let size_arms = variants.iter().map(|variant| {
+        match variant.kind {
+            VariantKind::Forward | VariantKind::Pad => {
+                let size = variant.named_field("size").unwrap();
+                let pattern = variant.pattern(Some(size));
+                let binding = &size.binding;
+                quote!(#pattern => *#binding)
+            },
+            VariantKind::Normal => {
+                match variant.fields.iter().find(|field| field.trailing.is_some()) {
+                    Some(array) => {
+                        let len = variant.find_field(array.trailing.as_ref().unwrap()).unwrap();
+                        let pattern = variant.pattern(vec![array, len]);
+                        let (array, len) = (&array.binding, &len.binding);
+                        quote!(#pattern => {
+                            let elems = #format::__trailing_elems(addr, #array.as_ptr());
+                            let end = elems.add(*#len as usize) as usize;
+                            round(::std::cmp::max(end - (addr as usize), ::std::mem::size_of::<Self>()))
+                        })
+                    },
+                    None => {
+                        let pattern = variant.pattern(None);
+                        quote!(#pattern => round(::std::mem::size_of::<Self>()))
+                    }
+                }
+            }
+        }
+    });
+    let scan_arms = variants.iter().filter(|variant| variant.kind == VariantKind::Normal)
+        .filter(|variant| variant.fields.iter().any(|field| field.is_ref))
+        .map(|variant| {
+            let mut needed = Vec::new();
+            let mut fixes = Vec::new();
+            for field in variant.fields.iter().filter(|field| field.is_ref) {
+                let binding = &field.binding;
+                match field.trailing {
+                    Some(ref len) => {
+                        let len = variant.find_field(len).unwrap();
+                        let len_binding = &len.binding;
+                        fixes.push(quote! {
+                            let elems = #format::__trailing_elems(base, #binding.as_ptr());
+                            for index in 0..(*#len_binding as usize) {
+                                #format::Fixable::fix(&mut *elems.add(index), state)?;
+                            }
+                        });
+                        if !needed.iter().any(|f: &&FieldInfo| f.member == len.member) {
+                            needed.push(len);
+                        }
+                    },
+                    None => {
+                        fixes.push(quote!(#format::Fixable::fix(#binding, state)?;));
+                    }
+                }
+                if !needed.iter().any(|f: &&FieldInfo| f.member == field.member) {
+                    needed.push(field);
+                }
+            }
+            let pattern = variant.pattern(needed);
+            quote!(#pattern => { #(#fixes)* })
+        });
+    let class_arms = variants.iter().map(|variant| {
+        let pattern = variant.pattern(None);
+        match variant.kind {
+            VariantKind::Forward | VariantKind::Pad => quote!(#pattern => ::std::ptr::null_mut()),
+            VariantKind::Normal => {
+                let class_name = format!("{}\0", variant.ident);
+                quote!(#pattern => #class_name.as_ptr() as *mut ::std::ffi::c_void)
+            }
+        }
+    });
+    // The minimum size of a padding object (see the docs)
+    let min_pad = quote!({
+        let word = ::std::mem::size_of::<usize>();
+        let align = ::std::mem::align_of::<Self>();
+        2 * if word > align { word } else { align }
+    });
+    let alignment = match alignment {
+        Some(alignment) => alignment,
+        None if pad.is_some() => min_pad.clone(),
+        None => quote!(::std::mem::align_of::<Self>())
+    };
+    let check_pad_alignment = if pad.is_some() {
+        quote!(assert!(align >= #min_pad, "Alignment is too small for padding objects");)
+    } else {
+        quote!()
+    };
+    // NOTE: Padding objects may be smaller than the whole enum, so they must be checked for first
+    let (scan_pad, skip_pad, null_pad) = match pad {
+        Some(_) => (
+            quote!(if Self::__mps_pad_size(base).is_some() {
+                base = #raw_methods::skip(base);
+                continue;
+            }),
+            quote!(if let Some(size) = Self::__mps_pad_size(addr) {
+                return (addr as *mut u8).add(size) as *mut Self;
+            }),
+            quote!(if Self::__mps_pad_size(obj).is_some() {
+                return ::std::ptr::null_mut();
+            })
+        ),
+        None => (quote!(), quote!(), quote!())
+    };
+    let mut res = quote! {
+        unsafe impl #impl_generics #format::RawFormatMethods for #name #ty_generics #where_clause {
+            type Obj = Self;
+            const ALIGNMENT: usize = {
+                let align: usize = #alignment;
+                assert!(
+                    align.is_power_of_two() && align >= ::std::mem::align_of::<Self>(),
+                    "Alignment must be a power of two, and at least the alignment of the enum"
+                );
+                #check_pad_alignment
+                align
+            };
+            unsafe extern "C" fn scan(
+                mut state: #format::ScanState,
+                mut base: *mut Self,
+                limit: *mut Self
+            ) -> ::mps::__sys::mps_res_t {
+                state.fix_with(|state| {
+                    while base < limit {
+                        #scan_pad
+                        #[allow(unreachable_patterns)]
+                        match &mut *base {
+                            #(#scan_arms,)*
+                            _ => {}
+                        }
+                        base = #raw_methods::skip(base);
+                    }
+                    Ok(())
+                })
+            }
+            unsafe extern "C" fn skip(addr: *mut Self) -> *mut Self {
+                #skip_pad
+                let align = #raw_methods::ALIGNMENT;
+                #[allow(unused)]
+                let round = |size: usize| (size + align - 1) & !(align - 1);
+                let size: usize = match &*addr {
+                    #(#size_arms,)*
+                };
+                (addr as *mut u8).add(size) as *mut Self
+            }
+        }
+        unsafe impl #impl_generics #format::ClassFormatMethods for #name #ty_generics #where_clause {
+            unsafe extern "C" fn class_ptr(obj: *mut Self) -> *mut ::std::ffi::c_void {
+                #null_pad
+                match &*obj {
+                    #(#class_arms,)*
+                }
+            }
+        }
+    };
+    if let Some(forward) = forward {
+        let ident = &forward.ident;
+        let pattern = forward.pattern(forward.named_field("new"));
+        let new_binding = &forward.named_field("new").unwrap().binding;
+        res.extend(quote! {
+            unsafe impl #impl_generics #format::MovingFormatMethods for #name #ty_generics #where_clause {
+                unsafe extern "C" fn forward(old: *mut Self, new: *mut Self) {
+                    // NOTE: Must be the same size as the original
+                    let size = (#raw_methods::skip(old) as usize) - (old as usize);
+                    old.write(Self::#ident { new, size });
+                }
+                unsafe extern "C" fn is_forwarded(obj: *mut Self) -> *mut Self {
+                    #null_pad
+                    match &*obj {
+                        #pattern => *#new_binding,
+                        _ => ::std::ptr::null_mut()
+                    }
+                }
+            }
+        });
+    }
+    if let Some(pad) = pad {
+        let ident = &pad.ident;
+        let tag_type = tag_type.as_ref().unwrap();
+        res.extend(quote! {
+            impl #impl_generics #name #ty_generics #where_clause {
+                /// The tag of a padding object, and the offset of its size
+                #[doc(hidden)]
+                #[inline(always)]
+                fn __mps_pad_layout() -> (#tag_type, usize) {
+                    let template = Self::#ident { size: 0 };
+                    let base = &template as *const Self;
+                    #[allow(unreachable_patterns)]
+                    let offset = match template {
+                        Self::#ident { ref size } => (size as *const usize as usize) - (base as usize),
+                        _ => unreachable!()
+                    };
+                    // NOTE: With `#[repr(<int>)]`, every variant starts with the tag
+                    (unsafe { *(base as *const #tag_type) }, offset)
+                }
+                /// The size of the object if it's a padding object
+                ///
+                /// This only reads the tag and the size, so it's valid for any object.
+                #[doc(hidden)]
+                #[inline(always)]
+                unsafe fn __mps_pad_size(obj: *const Self) -> Option<usize> {
+                    let (tag, offset) = Self::__mps_pad_layout();
+                    if *(obj as *const #tag_type) == tag {
+                        Some(*((obj as *const u8).add(offset) as *const usize))
+                    } else {
+                        None
+                    }
+                }
+            }
+            unsafe impl #impl_generics #format::PaddingFormatMethods for #name #ty_generics #where_clause {
+                unsafe extern "C" fn pad(addr: *mut Self, size: usize) {
+                    // NOTE: Only write the tag and the size, which fit in the minimum alignment
+                    let (tag, offset) = Self::__mps_pad_layout();
+                    (addr as *mut #tag_type).write(tag);
+                    ((addr as *mut u8).add(offset) as *mut usize).write(size);
+                }
+            }
+        });
+    }
+    Ok(res)
+}
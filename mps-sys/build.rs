@@ -1,5 +1,6 @@
 use std::env;
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
 
 fn should_debug() -> bool {
     match env::var_os("DEBUG") {
@@ -8,18 +9,193 @@ fn should_debug() -> bool {
     }
 }
 
-fn main() {
-    let mut cc = cc::Build::new();
-    cc.file("mps/code/mps.c");
-    if should_debug() {
-        cc.define("CONFIG_VAR_COOL", None);
+/// Determine the [variety](https://www.ravenbrook.com/project/mps/master/manual/html/topic/error.html#varieties)
+/// of the MPS to build, from the `variety-*` features.
+///
+/// If none of the features are enabled, the `DEBUG` environment variable
+/// selects the cool variety, and otherwise the hot variety is used.
+///
+/// When linking against a system-installed MPS, this can't be checked
+/// and should match the variety of the library.
+fn variety() -> &'static str {
+    let selected: Vec<&'static str> = ["cool", "hot", "rash"].iter().copied()
+        .filter(|variety| {
+            let feature = format!("CARGO_FEATURE_VARIETY_{}", variety.to_uppercase());
+            env::var_os(feature).is_some()
+        })
+        .collect();
+    match *selected {
+        [] if should_debug() => "cool",
+        [] => "hot",
+        [variety] => variety,
+        _ => panic!(
+            "The variety-* features are mutually exclusive, but got: {}",
+            selected.join(", ")
+        )
+    }
+}
+
+/// Where to get the MPS from
+enum MpsSource {
+    /// Build `mps/code/mps.c` from the submodule
+    Bundled,
+    /// Link against an existing library
+    System {
+        include_dir: PathBuf,
+        /// The version of the library (like `1.118.0`), if it could be determined
+        version: Option<String>
+    }
+}
+
+/// Look for a system-installed MPS,
+/// using `MPS_LIB_DIR`/`MPS_INCLUDE_DIR` or (with the `system-mps` feature) pkg-config.
+///
+/// This emits the linker flags for the library.
+fn find_system_mps() -> Option<MpsSource> {
+    println!("cargo:rerun-if-env-changed=MPS_LIB_DIR");
+    println!("cargo:rerun-if-env-changed=MPS_INCLUDE_DIR");
+    match (env::var_os("MPS_LIB_DIR"), env::var_os("MPS_INCLUDE_DIR")) {
+        (Some(lib_dir), Some(include_dir)) => {
+            let lib_dir = PathBuf::from(lib_dir);
+            println!("cargo:rustc-link-search=native={}", lib_dir.display());
+            println!("cargo:rustc-link-lib=mps");
+            return Some(MpsSource::System {
+                include_dir: include_dir.into(),
+                version: library_version(&[lib_dir])
+            })
+        },
+        (None, None) => {},
+        _ => panic!("MPS_LIB_DIR and MPS_INCLUDE_DIR must be specified together")
+    }
+    probe_pkg_config()
+}
+#[cfg(feature = "system-mps")]
+fn probe_pkg_config() -> Option<MpsSource> {
+    let library = pkg_config::Config::new()
+        .probe("mps")
+        .expect("Unable to find the MPS with pkg-config");
+    let include_dir = library.include_paths.first()
+        .cloned()
+        .unwrap_or_else(|| PathBuf::from("/usr/include"));
+    let version = library_version(&library.link_paths)
+        .or(Some(library.version));
+    Some(MpsSource::System { include_dir, version })
+}
+#[cfg(not(feature = "system-mps"))]
+fn probe_pkg_config() -> Option<MpsSource> {
+    None
+}
+
+/// Find the version of the MPS library in one of the specified directories
+///
+/// The library contains the release name (like `release/1.118.0`)
+/// in the string returned by `MPSVersion`.
+fn library_version(dirs: &[PathBuf]) -> Option<String> {
+    const MARKER: &[u8] = b"release/";
+    let names = ["libmps.a", "libmps.so", "libmps.dylib", "mps.lib"];
+    for path in dirs.iter().flat_map(|dir| names.iter().map(move |name| dir.join(name))) {
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(_) => continue
+        };
+        let start = match data.windows(MARKER.len()).position(|window| window == MARKER) {
+            Some(index) => index + MARKER.len(),
+            None => continue
+        };
+        let version: String = data[start..].iter()
+            .take_while(|&&b| b.is_ascii_digit() || b == b'.')
+            .map(|&b| b as char)
+            .collect();
+        if !version.is_empty() {
+            return Some(version)
+        }
+    }
+    None
+}
+
+/// Find the version of the MPS headers (like `1.118`)
+///
+/// Released headers have an `$Id` line like
+/// `//info.ravenbrook.com/project/mps/version/1.118/code/mps.h`.
+/// Headers from the master branch don't have a version.
+fn header_version(include_dir: &Path) -> Option<String> {
+    const MARKER: &str = "/project/mps/version/";
+    let header = fs::read_to_string(include_dir.join("mps.h")).ok()?;
+    let start = header.find(MARKER)? + MARKER.len();
+    let version: String = header[start..].chars()
+        .take_while(|&c| c.is_ascii_digit() || c == '.')
+        .collect();
+    if version.is_empty() { None } else { Some(version) }
+}
+
+/// The version of the MPS headers that the pre-generated bindings (in `src/bindings`)
+/// must be generated from
+const PREGENERATED_VERSION: &str = "1.118";
+
+/// The version of the MPS that the bindings in use correspond to
+///
+/// Bindgen generates the bindings from the headers, so this is the version of the headers.
+/// Otherwise it's the version of the pre-generated bindings.
+fn bindings_version(include_dir: &Path) -> Option<String> {
+    if cfg!(feature = "bindgen") {
+        header_version(include_dir)
+    } else {
+        Some(PREGENERATED_VERSION.into())
     }
-    cc.compile("mps");
+}
+
+/// Check that the library has the same (major and minor) version as the bindings,
+/// and that the headers (if they have a version) match the bindings too.
+fn check_version(include_dir: &Path, library_version: Option<&str>) {
+    let bindings_version = bindings_version(include_dir);
+    if let (Some(headers), Some(bindings)) = (header_version(include_dir), bindings_version.as_deref()) {
+        if headers != bindings {
+            panic!(
+                "The MPS headers in {} (version {}) don't match the pre-generated bindings (version {}), \
+                enable the `bindgen` feature to generate bindings for them",
+                include_dir.display(), headers, bindings
+            );
+        }
+    }
+    match (bindings_version.as_deref(), library_version) {
+        (Some(bindings), Some(library)) => {
+            let library_release = library.split('.').take(2).collect::<Vec<_>>().join(".");
+            if library_release != bindings {
+                panic!(
+                    "The MPS library (version {}) doesn't match the bindings (version {})",
+                    library, bindings
+                );
+            }
+        },
+        (bindings, library) => {
+            println!(
+                "cargo:warning=Unable to check the MPS library version (library: {}, bindings: {})",
+                library.unwrap_or("unknown"), bindings.unwrap_or("unknown")
+            );
+        }
+    }
+}
+
+/// The checked-in bindings for the current target
+fn pregenerated_bindings() -> PathBuf {
+    let target = env::var("TARGET").unwrap();
+    println!("cargo:rustc-env=MPS_SYS_TARGET={}", target);
+    Path::new("src/bindings").join(format!("{}.rs", target))
+}
+
+/// Generate the bindings with bindgen (which requires libclang)
+///
+/// If the `MPS_SYS_UPDATE_BINDINGS` environment variable is set,
+/// this also updates the checked-in bindings for the current target.
+#[cfg(feature = "bindgen")]
+fn write_bindings(include_dir: &Path, out: &Path) {
     let bindings = bindgen::Builder::default()
-        .header("mps/code/mps.h")
-        .header("mps/code/mpsavm.h") // VM arena
-        .header("mps/code/mpscams.h") // Pool: Automatic Mark/Sweep
-        .header("mps/code/mpscamc.h") // Pool: Automatic Mostly Copying
+        .header(include_dir.join("mps.h").to_str().unwrap())
+        .header(include_dir.join("mpsavm.h").to_str().unwrap()) // VM arena
+        .header(include_dir.join("mpscams.h").to_str().unwrap()) // Pool: Automatic Mark/Sweep
+        .header(include_dir.join("mpscamc.h").to_str().unwrap()) // Pool: Automatic Mostly Copying
+        .header(include_dir.join("mpscawl.h").to_str().unwrap()) // Pool: Automatic Weak Linked
+        .header(include_dir.join("mpscmvff.h").to_str().unwrap()) // Pool: Manual Variable First Fit
         .parse_callbacks(Box::new(bindgen::CargoCallbacks))
         .allowlist_type("mps_.*")
         .allowlist_function("mps_.*")
@@ -32,7 +208,63 @@ fn main() {
         .rustfmt_bindings(true)
         .generate()
         .expect("Unable to generate automatic bindings");
-    bindings.write_to_file(Path::new(&env::var_os("OUT_DIR").unwrap())
-        .join("mps_auto.rs"))
+    bindings.write_to_file(out)
         .expect("Failed to write bindings");
+    let pregenerated = pregenerated_bindings();
+    println!("cargo:rerun-if-env-changed=MPS_SYS_UPDATE_BINDINGS");
+    if env::var_os("MPS_SYS_UPDATE_BINDINGS").is_some() {
+        fs::create_dir_all(pregenerated.parent().unwrap()).unwrap();
+        fs::copy(out, &pregenerated).expect("Failed to update pre-generated bindings");
+    }
+}
+/// Use the checked-in bindings for the current target
+#[cfg(not(feature = "bindgen"))]
+fn write_bindings(_include_dir: &Path, out: &Path) {
+    let pregenerated = pregenerated_bindings();
+    if !pregenerated.exists() {
+        panic!(
+            "No pre-generated bindings for {} (expected {}), enable the `bindgen` feature",
+            env::var("TARGET").unwrap(), pregenerated.display()
+        );
+    }
+    println!("cargo:rerun-if-changed={}", pregenerated.display());
+    fs::copy(&pregenerated, out).expect("Failed to copy pre-generated bindings");
+}
+
+fn main() {
+    let source = find_system_mps().unwrap_or(MpsSource::Bundled);
+    let variety = variety();
+    let include_dir = match source {
+        MpsSource::Bundled => PathBuf::from("mps/code"),
+        MpsSource::System { ref include_dir, ref version } => {
+            check_version(include_dir, version.as_deref());
+            include_dir.clone()
+        }
+    };
+    let mut cc = cc::Build::new();
+    if let MpsSource::Bundled = source {
+        cc.file("mps/code/mps.c");
+    }
+    // Exports the layout of telemetry events (see src/events.c)
+    cc.file("src/events.c");
+    cc.include(&include_dir);
+    if !include_dir.join("eventdef.h").exists() {
+        // Installed headers don't include the internal event definitions
+        cc.define("MPS_SYS_NO_EVENT_TABLE", None);
+    }
+    cc.define(&format!("CONFIG_VAR_{}", variety.to_uppercase()), None);
+    println!("cargo:rerun-if-env-changed=DEBUG");
+    println!("cargo:rerun-if-changed=src/events.c");
+    match source {
+        MpsSource::Bundled => cc.compile("mps"),
+        // NOTE: Don't shadow the system library
+        MpsSource::System { .. } => cc.compile("mps_sys_events")
+    }
+    let out_dir = env::var_os("OUT_DIR").unwrap();
+    write_bindings(&include_dir, &Path::new(&out_dir).join("mps_auto.rs"));
+    fs::write(Path::new(&out_dir).join("variety.rs"), format!(
+        "/// The variety of the MPS that was built (`\"cool\"`, `\"hot\"` or `\"rash\"`)\n\
+        pub const MPS_VARIETY: &str = {:?};\n",
+        variety
+    )).expect("Failed to write variety");
 }
@@ -0,0 +1,74 @@
+/*
+ * Exports the layout of the MPS telemetry events,
+ * so the event stream can be parsed from Rust.
+ *
+ * The table is generated from the same X-macros (in eventdef.h)
+ * that define the event structures, so it always matches
+ * the version of the MPS that we're built against.
+ */
+#include <stddef.h>
+
+typedef struct mps_sys_event_param_s {
+  const char *name;   /* name of the parameter, or NULL at the end */
+  const char *sort;   /* one of "P", "A", "W", "U", "S", "D" or "B" */
+  size_t offset;      /* offset of the field in the event structure */
+} mps_sys_event_param_s;
+
+typedef struct mps_sys_event_s {
+  unsigned code;
+  const char *name;
+  size_t size;        /* size of the event structure */
+  const mps_sys_event_param_s *params;
+} mps_sys_event_s;
+
+typedef struct mps_sys_event_layout_s {
+  size_t header_size;
+  size_t code_offset, code_size;
+  size_t size_offset, size_size;
+  size_t clock_offset, clock_size;
+  /* Sizes of the fixed-size parameter sorts */
+  size_t word_size, unsigned_size, double_size, bool_size;
+} mps_sys_event_layout_s;
+
+#ifndef MPS_SYS_NO_EVENT_TABLE
+
+#include "mpm.h"
+#include "event.h"
+
+/* NOTE: Older versions of eventdef.h don't have a doc string */
+#define PARAM_ENTRY(name, index, sort, ident, ...) \
+  { #ident, #sort, offsetof(Event##name##Struct, f##index) },
+#define EVENT_PARAMS(X, name, code, ...) \
+  static const mps_sys_event_param_s params_##name[] = { \
+    EVENT_##name##_PARAMS(PARAM_ENTRY, name) \
+    { NULL, NULL, 0 } \
+  };
+EVENT_LIST(EVENT_PARAMS, X)
+
+#define EVENT_ENTRY(X, name, code, ...) \
+  { code, #name, sizeof(Event##name##Struct), params_##name },
+const mps_sys_event_s mps_sys_events[] = {
+  EVENT_LIST(EVENT_ENTRY, X)
+};
+const size_t mps_sys_event_count = sizeof(mps_sys_events) / sizeof(mps_sys_events[0]);
+
+const mps_sys_event_layout_s mps_sys_event_layout = {
+  sizeof(EventAnyStruct),
+  offsetof(EventAnyStruct, code), sizeof(EventCode),
+  offsetof(EventAnyStruct, size), sizeof(EventSize),
+  offsetof(EventAnyStruct, clock), sizeof(EventClock),
+  sizeof(EventFW), sizeof(EventFU), sizeof(EventFD), sizeof(EventFB)
+};
+
+#else /* MPS_SYS_NO_EVENT_TABLE */
+
+/*
+ * The internal event definitions aren't available
+ * (for example when linking against an installed MPS),
+ * so the event stream can't be parsed.
+ */
+const mps_sys_event_s mps_sys_events[1] = { { 0, NULL, 0, NULL } };
+const size_t mps_sys_event_count = 0;
+const mps_sys_event_layout_s mps_sys_event_layout = { 0 };
+
+#endif /* MPS_SYS_NO_EVENT_TABLE */
@@ -12,6 +12,7 @@
 
 //! Automatically generated bindings to MPS
 include!(concat!(env!("OUT_DIR"), "/mps_auto.rs"));
+include!(concat!(env!("OUT_DIR"), "/variety.rs"));
 
 /// An unsigned word-sized integer
 pub type mps_word_t = usize;
@@ -61,10 +62,69 @@ mps_arg_val_from!(
     mps_fmt_skip_t => fmt_skip,
     mps_fmt_fwd_t => fmt_fwd,
     mps_fmt_pad_t => fmt_pad,
-    *mut mps_pool_debug_option_s => pool_debug_options
+    *mut mps_pool_debug_option_s => pool_debug_options,
+    mps_rank_t => rank
     // mps_fmt_class_t => fmt_class
+    // mps_awl_find_dependent_t => awl_find_dependent (same type as mps_fmt_skip_t)
 );
 
+/// A parameter of a telemetry event, from the table in `src/events.c`
+///
+/// The last parameter of each event has a null `name`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct mps_sys_event_param_s {
+    pub name: *const std::os::raw::c_char,
+    pub sort: *const std::os::raw::c_char,
+    pub offset: usize
+}
+/// A telemetry event, from the table in `src/events.c`
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct mps_sys_event_s {
+    pub code: std::os::raw::c_uint,
+    pub name: *const std::os::raw::c_char,
+    pub size: usize,
+    pub params: *const mps_sys_event_param_s
+}
+/// The layout of the header shared by all telemetry events
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct mps_sys_event_layout_s {
+    pub header_size: usize,
+    pub code_offset: usize,
+    pub code_size: usize,
+    pub size_offset: usize,
+    pub size_size: usize,
+    pub clock_offset: usize,
+    pub clock_size: usize,
+    pub word_size: usize,
+    pub unsigned_size: usize,
+    pub double_size: usize,
+    pub bool_size: usize
+}
+extern "C" {
+    pub static mps_sys_events: [mps_sys_event_s; 0];
+    pub static mps_sys_event_count: usize;
+    pub static mps_sys_event_layout: mps_sys_event_layout_s;
+}
+
+/// A handler for assertion failures in the MPS,
+/// called with the file, line number and condition of the failed assertion.
+///
+/// Declared in `mpslib.h`, which isn't included in the generated bindings.
+pub type mps_lib_assert_fail_t = Option<unsafe extern "C" fn(
+    file: *const std::os::raw::c_char,
+    line: std::os::raw::c_uint,
+    condition: *const std::os::raw::c_char
+)>;
+extern "C" {
+    /// Install a handler for assertion failures, returning the previous handler
+    ///
+    /// Part of the [plinth](https://www.ravenbrook.com/project/mps/master/manual/html/topic/plinth.html#c.mps_lib_assert_fail_install)
+    pub fn mps_lib_assert_fail_install(handler: mps_lib_assert_fail_t) -> mps_lib_assert_fail_t;
+}
+
 /// Rust imitation of `MPS_ARGS_BEGIN/END` marcos
 ///
 /// Very unsafe internally!
@@ -0,0 +1,44 @@
+//! Checks that the pre-generated bindings (in `src/bindings`)
+//! exist for every supported target, and haven't drifted from the MPS headers.
+//!
+//! Checking for drift only runs with the `bindgen` feature, which generates fresh bindings.
+use std::path::{Path, PathBuf};
+
+/// The targets that must have pre-generated bindings (see `src/bindings/README.md`)
+///
+/// NOTE: Add `x86_64-unknown-linux-gnu` and `aarch64-unknown-linux-gnu`
+/// once their bindings are generated (by the `bindings` CI job).
+const SUPPORTED_TARGETS: &[&str] = &[];
+
+fn pregenerated(target: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("src/bindings")
+        .join(format!("{}.rs", target))
+}
+
+#[test]
+fn supported_targets_have_bindings() {
+    let missing: Vec<&str> = SUPPORTED_TARGETS.iter().copied()
+        .filter(|target| !pregenerated(target).is_file())
+        .collect();
+    assert!(missing.is_empty(), "Missing pre-generated bindings for {:?}", missing);
+}
+
+#[test]
+#[cfg(feature = "bindgen")]
+fn pregenerated_bindings_match_headers() {
+    let target = env!("MPS_SYS_TARGET");
+    let generated = include_str!(concat!(env!("OUT_DIR"), "/mps_auto.rs"));
+    match std::fs::read_to_string(pregenerated(target)) {
+        Ok(pregenerated) => assert!(
+            pregenerated == generated,
+            "The pre-generated bindings for {} are out of date, \
+            rebuild with MPS_SYS_UPDATE_BINDINGS=1 to update them", target
+        ),
+        Err(e) if SUPPORTED_TARGETS.contains(&target) => panic!(
+            "Missing pre-generated bindings for {}, \
+            rebuild with MPS_SYS_UPDATE_BINDINGS=1 to create them: {}", target, e
+        ),
+        Err(_) => eprintln!("No pre-generated bindings for {} (not a supported target), skipping", target)
+    }
+}
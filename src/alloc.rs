@@ -66,10 +66,14 @@
 //! // Successful allocation
 //! assert_eq!(unsafe { (*obj).val }, 5);
 //! ````
-use mps_sys::{mps_ap_t, mps_addr_t};
+use mps_sys::{mps_ap_t, mps_addr_t, mps_alloc_pattern_t};
 
 use crate::err::MpsError;
+use crate::retry::RetryPolicy;
+use crate::arena::Arena;
+use crate::registry::{Registration, HandleKind};
 use std::alloc::Layout;
+use std::cell::Cell;
 use std::ffi::c_void;
 
 /// Whether or not to debug the allocation points
@@ -82,35 +86,166 @@ use std::ffi::c_void;
 /// By default this is controlled by a feature flag `cfg!(feature="debug-mps-alloc") && cfg!(debug_assertions)`
 pub const DEBUG_ALLOCATION_POINTS: bool = cfg!(feature = "debug-mps-alloc") && cfg!(debug_assertions);
 
-/// An allocation point.
+/// An owned allocation point, with no state besides the raw pointer.
 ///
 /// This is represented as a pointer to a [mps_ap_s](::mps_sys::mps_ap_s). This
 /// representation can be safely relied upon for FFI. In other words, it's safe to transmute
 /// back and forth.
+///
+/// Before [AllocationPoint] tracked its pool's alignment (and its ramps, policies and registration),
+/// it had this representation itself. FFI code that relied on that should use this type instead,
+/// converting with [AllocationPoint::into_raw] and [AllocationPoint::from_raw_handle].
 #[repr(transparent)]
-pub struct AllocationPoint {
+pub struct RawAllocationPoint {
     raw: mps_ap_t
 }
+impl !Send for RawAllocationPoint {}
+impl !Sync for RawAllocationPoint {}
+impl RawAllocationPoint {
+    /// Take ownership of the specified raw allocation point
+    ///
+    /// Undefined behavior if the allocation point is invalid.
+    #[inline(always)]
+    pub const unsafe fn from_raw(raw: mps_ap_t) -> RawAllocationPoint {
+        RawAllocationPoint { raw }
+    }
+    /// Get the raw pointer to the underlying allocation point
+    #[inline(always)]
+    pub const fn as_raw(&self) -> mps_ap_t {
+        self.raw
+    }
+    /// Give up ownership of the allocation point, without destroying it
+    #[inline(always)]
+    pub fn into_raw(self) -> mps_ap_t {
+        let raw = self.raw;
+        std::mem::forget(self);
+        raw
+    }
+}
+impl Drop for RawAllocationPoint {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { ::mps_sys::mps_ap_destroy(self.raw); }
+    }
+}
+
+/// An allocation point.
+///
+/// This wraps a pointer to a [mps_ap_s](::mps_sys::mps_ap_s),
+/// along with the alignment of the pool it allocates from.
+///
+/// The `'pool` lifetime borrows the pool, so the allocation point can't outlive it.
+///
+/// Unlike previous versions, this is no longer `#[repr(transparent)]`,
+/// so it must not be transmuted to or from a raw pointer.
+/// Use a [RawAllocationPoint] for FFI instead.
+pub struct AllocationPoint<'pool> {
+    raw: mps_ap_t,
+    alignment: usize,
+    /// The number of active [RampGuard]s
+    ramp_depth: Cell<usize>,
+    /// Incremented every time the allocation patterns are reset,
+    /// invalidating all active [RampGuard]s
+    ramp_generation: Cell<usize>,
+    /// What to do when a reservation runs out of memory.
+    ///
+    /// This is taken while the policy is running.
+    retry_policy: Cell<Option<Box<RetryPolicy<'pool>>>>,
+    /// Forces collections after committing (see [AllocationPoint::set_stress_mode]).
+    ///
+    /// This is taken while the collection is running.
+    #[cfg(feature = "stress")]
+    stress_mode: Cell<Option<Box<crate::stress::StressMode<'pool>>>>,
+    /// Keeps the allocation point in its arena's registry.
+    ///
+    /// Allocation points created with [AllocationPoint::from_raw] (or [AllocationPoint::from_raw_aligned]) are not registered.
+    registration: Option<Registration>
+}
 /// An allocation point is not thread safe.
 ///
 /// Each thread must create its own allocation point or points.
-impl !Send for AllocationPoint {}
-impl !Sync for AllocationPoint {}
-impl AllocationPoint {
+impl !Send for AllocationPoint<'_> {}
+impl !Sync for AllocationPoint<'_> {}
+impl<'pool> AllocationPoint<'pool> {
     /// Create an allocation point from the specified raw pointer.
     ///
     /// This function takes ownership of the allocation point.
+    /// The pool is assumed to have the alignment of a pointer,
+    /// use [AllocationPoint::from_raw_aligned] if it is larger.
     ///
-    /// Undefined behavior if the allocation point is invalid.
+    /// Undefined behavior if the allocation point is invalid,
+    /// or if its pool doesn't outlive `'pool`.
     #[inline(always)]
-    pub const unsafe fn from_raw(raw: mps_ap_t) -> AllocationPoint {
-        AllocationPoint { raw }
+    pub const unsafe fn from_raw(raw: mps_ap_t) -> AllocationPoint<'pool> {
+        AllocationPoint::from_raw_aligned(raw, std::mem::align_of::<*mut u8>())
+    }
+    /// Create an allocation point from the specified raw pointer,
+    /// allocating from a pool with the specified alignment.
+    ///
+    /// This function takes ownership of the allocation point.
+    /// The `alignment` must be the alignment of the pool (or its object format).
+    ///
+    /// Undefined behavior if the allocation point is invalid,
+    /// or if its pool doesn't outlive `'pool`.
+    #[inline(always)]
+    pub const unsafe fn from_raw_aligned(raw: mps_ap_t, alignment: usize) -> AllocationPoint<'pool> {
+        AllocationPoint {
+            raw, alignment,
+            ramp_depth: Cell::new(0),
+            ramp_generation: Cell::new(0),
+            retry_policy: Cell::new(None),
+            #[cfg(feature = "stress")]
+            stress_mode: Cell::new(None),
+            registration: None
+        }
+    }
+    /// Create an allocation point from a [RawAllocationPoint],
+    /// allocating from a pool with the specified alignment.
+    ///
+    /// See [AllocationPoint::from_raw_aligned] for the requirements on `alignment`.
+    #[inline]
+    pub unsafe fn from_raw_handle(raw: RawAllocationPoint, alignment: usize) -> AllocationPoint<'pool> {
+        AllocationPoint::from_raw_aligned(raw.into_raw(), alignment)
+    }
+    /// Convert this into a [RawAllocationPoint], which is safe to pass to FFI.
+    ///
+    /// This unregisters the allocation point from its arena,
+    /// and discards its [retry policy](AllocationPoint::set_retry_policy).
+    ///
+    /// Panics if there are any active [RampGuard]s.
+    pub fn into_raw(self) -> RawAllocationPoint {
+        assert_eq!(self.ramp_depth.get(), 0, "Active ramps");
+        let mut this = std::mem::ManuallyDrop::new(self);
+        drop(this.registration.take());
+        drop(this.retry_policy.take());
+        #[cfg(feature = "stress")]
+        drop(this.stress_mode.take());
+        RawAllocationPoint { raw: this.raw }
+    }
+    /// Register this allocation point with the specified arena,
+    /// giving it the specified name (if any)
+    #[inline]
+    pub(crate) fn registered(mut self, arena: &Arena, name: Option<&str>) -> AllocationPoint<'pool> {
+        debug_assert!(self.registration.is_none());
+        let registration = arena.register(HandleKind::AllocationPoint, None, self.raw as *mut c_void);
+        if let Some(name) = name {
+            registration.set_name(name);
+        }
+        self.registration = Some(registration);
+        self
     }
     /// Get the raw pointer to the underlying allocation point
     #[inline(always)]
     pub const fn as_raw(&self) -> mps_ap_t {
         self.raw
     }
+    /// The alignment of the pool this allocation point allocates from
+    ///
+    /// All reserved sizes must be a multiple of this.
+    #[inline]
+    pub const fn alignment(&self) -> usize {
+        self.alignment
+    }
     /// Allocate a block of memory of the specified size, initializing it with the specified closure
     ///
     /// This functions as essentially a loop [AllocationPoint::reserve] + [AllocationPoint::commit]
@@ -120,15 +255,137 @@ impl AllocationPoint {
     /// - The size/alignment of the specified type must meet the requirements of the type
     #[inline]
     pub unsafe fn alloc_with<T, F: FnMut(*mut T)>(&self, mut func: F) -> Result<*mut T, MpsError> {
-        let layout = Layout::new::<T>();
+        self.alloc_layout(Layout::new::<T>(), |ptr| func(ptr as *mut T))
+            .map(|ptr| ptr as *mut T)
+    }
+    /// Allocate a block of memory with the specified layout,
+    /// initializing it with the specified closure.
+    ///
+    /// The size is rounded up to a multiple of the pool's [alignment](AllocationPoint::alignment),
+    /// so the closure may see a block that is larger than `layout.size()`.
+    ///
+    /// Panics if `layout.align()` is greater than the pool's alignment,
+    /// since the MPS can't give a stricter alignment than that.
+    ///
+    /// ## Safety
+    /// - Once initialized, the memory must be able to be properly traced.
+    #[inline]
+    pub unsafe fn alloc_layout<F: FnMut(*mut u8)>(&self, layout: Layout, mut init: F) -> Result<*mut u8, MpsError> {
+        assert!(
+            layout.align() <= self.alignment,
+            "Alignment {} exceeds pool alignment {}",
+            layout.align(), self.alignment
+        );
+        let size = self.aligned_size(layout.size())?;
         loop {
-            let ptr = self.reserve(layout.size())? as *mut T;
-            func(ptr);
-            if self.commit(ptr as *mut c_void, layout.size()) {
+            let ptr = self.reserve(size)? as *mut u8;
+            init(ptr);
+            if self.commit(ptr as *mut c_void, size) {
                 return Ok(ptr)
             }
         }
     }
+    /// Allocate an array of `len` elements,
+    /// initializing it with the specified closure.
+    ///
+    /// The closure is given a pointer to the first element.
+    /// See [AllocationPoint::alloc_layout] for details on the alignment requirements.
+    ///
+    /// ## Safety
+    /// - Once initialized, the memory must be able to be properly traced.
+    #[inline]
+    pub unsafe fn alloc_slice<T, F: FnMut(*mut T)>(&self, len: usize, mut init: F) -> Result<*mut [T], MpsError> {
+        let layout = Layout::array::<T>(len).map_err(|_| MpsError::InvalidParam)?;
+        let ptr = self.alloc_layout(layout, |ptr| init(ptr as *mut T))?;
+        Ok(std::ptr::slice_from_raw_parts_mut(ptr as *mut T, len))
+    }
+    /// Round the specified size up to a (non-zero) multiple of the pool's alignment
+    #[inline]
+    fn aligned_size(&self, size: usize) -> Result<usize, MpsError> {
+        debug_assert!(self.alignment.is_power_of_two());
+        size.max(1).checked_add(self.alignment - 1)
+            .map(|size| size & !(self.alignment - 1))
+            .ok_or(MpsError::InvalidParam)
+    }
+    /// Set the policy used to recover when a reservation
+    /// fails with [MpsError::CommitLimit] or [MpsError::Memory].
+    ///
+    /// By default there is no policy, and the error is returned immediately.
+    /// Returns the old policy (if any).
+    #[inline]
+    pub fn set_retry_policy(&self, policy: Option<RetryPolicy<'pool>>) -> Option<RetryPolicy<'pool>> {
+        self.retry_policy.replace(policy.map(Box::new)).map(|old| *old)
+    }
+    /// Force collections (and optionally verify the heap) after committing,
+    /// to flush out missing roots.
+    ///
+    /// This is only intended for tests (see the [stress](crate::stress) module).
+    /// Returns the old mode (if any).
+    #[cfg(feature = "stress")]
+    #[inline]
+    pub fn set_stress_mode(&self, mode: Option<crate::stress::StressMode<'pool>>) -> Option<crate::stress::StressMode<'pool>> {
+        self.stress_mode.replace(mode.map(Box::new)).map(|old| *old)
+    }
+    /// Begin a [ramp allocation pattern](https://www.ravenbrook.com/project/mps/master/manual/html/topic/pattern.html#ramp-allocation),
+    /// which tells the MPS that most blocks allocated until the returned guard is dropped
+    /// will be dead by then.
+    ///
+    /// The MPS may defer collections during the ramp, and collect the blocks allocated
+    /// during the ramp afterwards.
+    ///
+    /// Ramps may be nested, but the guards must be dropped in reverse order
+    /// (dropping them out of order panics).
+    ///
+    /// Corresponds to C function [mps_ap_alloc_pattern_begin](https://www.ravenbrook.com/project/mps/master/manual/html/topic/pattern.html#c.mps_ap_alloc_pattern_begin)
+    /// with [mps_alloc_pattern_ramp](https://www.ravenbrook.com/project/mps/master/manual/html/topic/pattern.html#c.mps_alloc_pattern_ramp)
+    #[inline]
+    pub fn begin_ramp(&self) -> Result<RampGuard<'_, 'pool>, MpsError> {
+        unsafe { self.begin_pattern(::mps_sys::mps_alloc_pattern_ramp()) }
+    }
+    /// Begin a ramp allocation pattern,
+    /// requesting a full collection (instead of a collection of the ramp's generation)
+    /// once the ramp ends.
+    ///
+    /// See [AllocationPoint::begin_ramp] for details.
+    #[inline]
+    pub fn begin_ramp_collect_all(&self) -> Result<RampGuard<'_, 'pool>, MpsError> {
+        unsafe { self.begin_pattern(::mps_sys::mps_alloc_pattern_ramp_collect_all()) }
+    }
+    /// The number of ramps that are currently active
+    ///
+    /// This counts every [RampGuard] that hasn't been dropped,
+    /// unless the patterns have been [reset](AllocationPoint::reset_alloc_patterns) since.
+    #[inline]
+    pub fn ramp_depth(&self) -> usize {
+        self.ramp_depth.get()
+    }
+    unsafe fn begin_pattern(&self, pattern: mps_alloc_pattern_t) -> Result<RampGuard<'_, 'pool>, MpsError> {
+        handle_mps_res!(::mps_sys::mps_ap_alloc_pattern_begin(self.raw, pattern))?;
+        let depth = self.ramp_depth.get() + 1;
+        self.ramp_depth.set(depth);
+        Ok(RampGuard {
+            ap: self, pattern, depth,
+            generation: self.ramp_generation.get()
+        })
+    }
+    /// End all active allocation patterns (including ramps),
+    /// as if every [RampGuard] had been dropped.
+    ///
+    /// This is useful on error paths, where it is
+    /// not practical to end every pattern in the correct order.
+    /// Any active guards will do nothing when they are dropped.
+    ///
+    /// Corresponds to C function [mps_ap_alloc_pattern_reset](https://www.ravenbrook.com/project/mps/master/manual/html/topic/pattern.html#c.mps_ap_alloc_pattern_reset)
+    #[inline]
+    pub fn reset_alloc_patterns(&self) {
+        unsafe {
+            // NOTE: This can't really fail
+            let res = handle_mps_res!(::mps_sys::mps_ap_alloc_pattern_reset(self.raw));
+            debug_assert!(res.is_ok(), "Failed to reset patterns: {:?}", res);
+        }
+        self.ramp_depth.set(0);
+        self.ramp_generation.set(self.ramp_generation.get().wrapping_add(1));
+    }
     /// Reserve a block of memory from this allocation point.
     ///
     /// The size of the block to allocate must be a multiple of the alignment of the pool
@@ -169,9 +426,10 @@ impl AllocationPoint {
                 self._fill(size)
             }
         } else {
-            let mut res = std::ptr::null_mut();
-            handle_mps_res!(::mps_sys::mps_reserve(&mut res, self.raw, size))?;
-            Ok(res)
+            match self._reserve_out_of_line(size) {
+                Ok(res) => Ok(res),
+                Err(cause) => self._retry(size, cause)
+            }
         }
     }
     /// Commit a previously reserved block on an allocation point.
@@ -192,7 +450,7 @@ impl AllocationPoint {
     #[cfg_attr(debug_assertions, inline)]
     pub unsafe fn commit(&self, p: mps_addr_t, size: usize) -> bool {
         // https://github.com/Ravenbrook/mps/blob/e198a504f3ba2197686c55e048996/code/mps.h#L640
-        if !DEBUG_ALLOCATION_POINTS {
+        let committed = if !DEBUG_ALLOCATION_POINTS {
             (*self.raw).init = (*self.raw).alloc;
             if !(*self.raw).limit.is_null() {
                 true
@@ -201,6 +459,22 @@ impl AllocationPoint {
             }
         } else {
             ::mps_sys::mps_commit(self.raw, p, size) != 0
+        };
+        #[cfg(feature = "stress")]
+        if committed {
+            self._stress();
+        }
+        committed
+    }
+    /// Run the [stress mode](AllocationPoint::set_stress_mode) (if any) after a successful commit
+    #[cfg(feature = "stress")]
+    #[cold]
+    #[inline(never)]
+    fn _stress(&self) {
+        // NOTE: Commits during the collection (by finalizers etc) don't trigger another one
+        if let Some(mut mode) = self.stress_mode.take() {
+            mode.after_commit();
+            self.stress_mode.set(Some(mode));
         }
     }
     /// Rserve a block of memory on an allocation point,
@@ -213,8 +487,33 @@ impl AllocationPoint {
         let mut res: mps_addr_t = std::ptr::null_mut();
         match ::mps_sys::mps_ap_fill(&mut res, self.raw, size) {
             0 => Ok(res),
-            code => Err(MpsError::from_code(code))
+            code => self._retry(size, MpsError::from_code(code))
+        }
+    }
+    /// Reserve a block of memory, without any inline fast path
+    ///
+    /// Corresponds to C function [mps_reserve](https://www.ravenbrook.com/project/mps/master/manual/html/topic/allocation.html#c.mps_reserve)
+    #[inline]
+    unsafe fn _reserve_out_of_line(&self, size: usize) -> Result<mps_addr_t, MpsError> {
+        let mut res = std::ptr::null_mut();
+        handle_mps_res!(::mps_sys::mps_reserve(&mut res, self.raw, size))?;
+        Ok(res)
+    }
+    /// Attempt to recover from a failed reservation, using the [RetryPolicy] (if any)
+    #[cold]
+    #[inline(never)]
+    unsafe fn _retry(&self, size: usize, cause: MpsError) -> Result<mps_addr_t, MpsError> {
+        if !RetryPolicy::is_recoverable(&cause) {
+            return Err(cause)
         }
+        // NOTE: Reservations made by the policy itself won't be retried
+        let mut policy = match self.retry_policy.take() {
+            Some(policy) => policy,
+            None => return Err(cause)
+        };
+        let res = policy.retry(size, cause, || self._reserve_out_of_line(size));
+        self.retry_policy.set(Some(policy));
+        res
     }
     /// Tests whether a reserved block was successfully committed when an allocation point was trapped.
     ///
@@ -225,8 +524,61 @@ impl AllocationPoint {
         ::mps_sys::mps_ap_trip(self.raw, ptr, size) != 0
     }
 }
-impl Drop for AllocationPoint {
+impl Drop for AllocationPoint<'_> {
     fn drop(&mut self) {
+        if let Some(ref mut registration) = self.registration {
+            registration.unregister();
+        }
         unsafe { ::mps_sys::mps_ap_destroy(self.raw); }
     }
-}
\ No newline at end of file
+}
+
+/// Ends a ramp allocation pattern when dropped
+///
+/// Created by [AllocationPoint::begin_ramp]
+#[must_use = "The ramp ends as soon as the guard is dropped"]
+pub struct RampGuard<'ap, 'pool> {
+    ap: &'ap AllocationPoint<'pool>,
+    pattern: mps_alloc_pattern_t,
+    /// The nesting depth of this ramp (starting at one)
+    depth: usize,
+    generation: usize
+}
+impl<'ap, 'pool> RampGuard<'ap, 'pool> {
+    /// The allocation point this ramp belongs to
+    #[inline]
+    pub fn allocation_point(&self) -> &'ap AllocationPoint<'pool> {
+        self.ap
+    }
+    /// The nesting depth of this ramp (the outermost ramp is one)
+    #[inline]
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+    /// Reset all the allocation patterns of the allocation point,
+    /// including this one and any it is nested in.
+    ///
+    /// See [AllocationPoint::reset_alloc_patterns]
+    #[inline]
+    pub fn reset(self) {
+        self.ap.reset_alloc_patterns();
+        // NOTE: Drop does nothing now that the generation has changed
+    }
+}
+impl Drop for RampGuard<'_, '_> {
+    fn drop(&mut self) {
+        if self.generation != self.ap.ramp_generation.get() {
+            return // Already reset
+        }
+        // NOTE: Ending the wrong pattern would corrupt the MPS's ramp state
+        assert_eq!(
+            self.ap.ramp_depth.get(), self.depth,
+            "Nested ramps must end in reverse order"
+        );
+        self.ap.ramp_depth.set(self.depth - 1);
+        unsafe {
+            let res = handle_mps_res!(::mps_sys::mps_ap_alloc_pattern_end(self.ap.raw, self.pattern));
+            debug_assert!(res.is_ok(), "Failed to end ramp: {:?}", res);
+        }
+    }
+}
@@ -4,19 +4,57 @@ use arrayvec::ArrayVec;
 use crate::err::MpsError;
 use std::ffi::c_void;
 use std::marker::PhantomData;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::stats::ArenaStats;
+use crate::telemetry::TelemetryCategories;
+use crate::registry::{Registry, Registration, HandleKind, HandleInfo};
 
 /// A MPS Arena, for allocating raw memory from the operating system
 ///
 /// Generally you want to use a ["Virtual memory" arena](https://www.ravenbrook.com/project/mps/master/manual/html/topic/arena.html#virtual-memory-arenas),
 /// to use the OS's virtual memory system
 pub struct Arena {
-    raw: mps_arena_t
+    raw: mps_arena_t,
+    registry: Registry,
+    control: ArenaControl
 }
 impl Arena {
     #[inline]
     pub(crate) fn as_raw(&self) -> mps_arena_t {
         self.raw
     }
+    #[inline]
+    pub(crate) fn control(&self) -> &ArenaControl {
+        &self.control
+    }
+    #[inline]
+    pub(crate) fn registry(&self) -> &Registry {
+        &self.registry
+    }
+    /// Record a newly created handle, so that it shows up in [Arena::handles]
+    #[inline]
+    pub(crate) fn register(&self, kind: HandleKind, class: Option<&'static str>, raw: *mut c_void) -> Registration {
+        self.registry.register(kind, class, raw)
+    }
+    /// List the handles (pools, formats, roots, threads and allocation points)
+    /// that are currently alive in this arena, in order of creation.
+    pub fn handles(&self) -> Vec<HandleInfo> {
+        self.registry.with_entries(|entries| {
+            entries.iter().map(|entry| entry.info.clone()).collect()
+        })
+    }
+    /// Take a snapshot of the arena's statistics,
+    /// including the sizes of all of its pools.
+    ///
+    /// The values are read one at a time,
+    /// so they may be slightly inconsistent if other threads are allocating.
+    pub fn stats(&self) -> ArenaStats {
+        self.registry.with_entries(|entries| unsafe {
+            ArenaStats::collect(self.raw, entries)
+        })
+    }
     /// The number of collections in which objects might have been moved.
     ///
     /// If you're using a non-moving pool, this will return zero.
@@ -121,19 +159,157 @@ impl Arena {
     /// Returns `Ok(())` if collection successfully
     /// begins and an error if it is not.
     /// Generally, errors from this method are non fatal (and can be safely ignored).
+    ///
+    /// In [debug mode](VirtualMemoryArenaBuilder::debug_mode),
+    /// this does a full collection instead.
     #[inline]
     pub fn begin_collection(&self) -> Result<(), MpsError> {
-        unsafe {
-            handle_mps_res!(mps_arena_start_collect(self.raw))
-        }
+        self.control.start_collect()
     }
     /// Begin a full collection, blocking until completion
     ///
     /// Contrast with [Arena::begin_collection], which asynchronously
     /// requests a collection, without blocking until completion.
+    ///
+    /// This leaves the arena [parked](Arena::park).
+    /// In [debug mode](VirtualMemoryArenaBuilder::debug_mode),
+    /// the arena is also [exposed](Arena::expose) afterwards.
     #[inline]
     pub fn full_collection(&self) {
-        unsafe { mps_arena_collect(self.raw); }
+        self.control.collect();
+    }
+    /// Request the MPS to do some collection work,
+    /// using up to `interval` seconds of "idle time".
+    ///
+    /// The `multiplier` is the number of further similar calls
+    /// the client program expects to make before it is next idle.
+    ///
+    /// Returns true if any work was done.
+    /// In [debug mode](VirtualMemoryArenaBuilder::debug_mode), this never does any work.
+    ///
+    /// Corresponds to C function [mps_arena_step](https://www.ravenbrook.com/project/mps/master/manual/html/topic/arena.html#c.mps_arena_step)
+    #[inline]
+    pub fn step(&self, interval: f64, multiplier: f64) -> bool {
+        assert!(interval >= 0.0, "Invalid interval: {}", interval);
+        assert!(multiplier >= 0.0, "Invalid multiplier: {}", multiplier);
+        self.control.step(interval, multiplier)
+    }
+    /// Put the arena into the "parked" state,
+    /// waiting for any collections in progress to finish
+    /// and preventing any new collections from starting.
+    ///
+    /// Collections only happen when explicitly requested
+    /// (by [Arena::full_collection] or [Arena::step]) until the arena is [released](Arena::release).
+    #[inline]
+    pub fn park(&self) {
+        self.control.park()
+    }
+    /// Put the arena into the "clamped" state,
+    /// where no object motion occurs and the staleness of location dependencies does not change.
+    ///
+    /// Unlike [Arena::park] this doesn't wait for collections in progress to finish.
+    #[inline]
+    pub fn clamp(&self) {
+        self.control.clamp()
+    }
+    /// Put the arena back into the "unclamped" state,
+    /// allowing collections to happen at any time.
+    ///
+    /// This is the default state.
+    ///
+    /// In [debug mode](VirtualMemoryArenaBuilder::debug_mode),
+    /// this does nothing and the arena stays parked.
+    #[inline]
+    pub fn release(&self) {
+        self.control.release()
+    }
+    /// The current state of the arena
+    ///
+    /// This is tracked by the methods that change the state
+    /// ([Arena::park], [Arena::clamp], [Arena::release], [Arena::step] and the collections),
+    /// since the MPS has no way to query it.
+    #[inline]
+    pub fn state(&self) -> ArenaState {
+        self.control.state()
+    }
+    /// Put the arena into the "postmortem" state,
+    /// releasing all of its locks and removing all memory protection,
+    /// so that its memory can be inspected (by a debugger or in a core dump).
+    ///
+    /// This is intended for use when the process is about to crash.
+    /// See [install_panic_hook](crate::postmortem::install_panic_hook).
+    ///
+    /// Corresponds to C function [mps_arena_postmortem](https://www.ravenbrook.com/project/mps/master/manual/html/topic/arena.html#c.mps_arena_postmortem)
+    ///
+    /// ## Safety
+    /// Nothing may use the MPS afterwards, on any thread:
+    /// not this arena, its pools and allocation points, or any other arena.
+    /// The arena (and its handles) must not be dropped either,
+    /// so the process should exit (or abort) without running any more MPS code.
+    #[inline]
+    pub unsafe fn postmortem(&self) {
+        mps_arena_postmortem(self.raw)
+    }
+    /// Remove the memory protection (read and write barriers) from all the arena's memory,
+    /// so that it can be inspected without triggering any barriers.
+    ///
+    /// This is expensive, and is intended for debugging.
+    /// The protection is restored gradually as collections happen,
+    /// or immediately by [Arena::unsafe_restore_protection].
+    /// In [debug mode](VirtualMemoryArenaBuilder::debug_mode), this is done after every collection.
+    ///
+    /// Corresponds to C function [mps_arena_expose](https://www.ravenbrook.com/project/mps/master/manual/html/topic/arena.html#c.mps_arena_expose)
+    #[inline]
+    pub fn expose(&self) {
+        unsafe { mps_arena_expose(self.raw) }
+    }
+    /// Restore the memory protection that was removed by [Arena::expose]
+    ///
+    /// Corresponds to C function [mps_arena_unsafe_restore_protection](https://www.ravenbrook.com/project/mps/master/manual/html/topic/arena.html#c.mps_arena_unsafe_restore_protection)
+    ///
+    /// ## Safety
+    /// None of the arena's memory may have been modified since [Arena::expose] was called
+    /// (including by allocating), because the MPS relies on the protection to notice changes.
+    /// Otherwise, the MPS may miss references and free (or move) objects that are still alive.
+    #[inline]
+    pub unsafe fn unsafe_restore_protection(&self) {
+        mps_arena_unsafe_restore_protection(self.raw)
+    }
+    /// Whether this arena was created in [debug mode](VirtualMemoryArenaBuilder::debug_mode)
+    #[inline]
+    pub fn is_debug_mode(&self) -> bool {
+        self.control.debug_mode
+    }
+    /// The categories of events that are currently written to the telemetry stream
+    ///
+    /// NOTE: The telemetry settings are global, shared by all arenas.
+    ///
+    /// Corresponds to C function [mps_telemetry_get](https://www.ravenbrook.com/project/mps/master/manual/html/topic/telemetry.html#c.mps_telemetry_get)
+    #[inline]
+    pub fn telemetry(&self) -> TelemetryCategories {
+        TelemetryCategories::from_bits(unsafe { mps_telemetry_get() })
+    }
+    /// Start writing the specified categories of events to the telemetry stream
+    ///
+    /// Corresponds to C function [mps_telemetry_set](https://www.ravenbrook.com/project/mps/master/manual/html/topic/telemetry.html#c.mps_telemetry_set)
+    #[inline]
+    pub fn enable_telemetry(&self, categories: TelemetryCategories) {
+        unsafe { mps_telemetry_set(categories.bits()) }
+    }
+    /// Stop writing the specified categories of events to the telemetry stream
+    ///
+    /// Corresponds to C function [mps_telemetry_reset](https://www.ravenbrook.com/project/mps/master/manual/html/topic/telemetry.html#c.mps_telemetry_reset)
+    #[inline]
+    pub fn disable_telemetry(&self, categories: TelemetryCategories) {
+        unsafe { mps_telemetry_reset(categories.bits()) }
+    }
+    /// Flush the telemetry buffers to the output file,
+    /// so the stream can be read while the program is running.
+    ///
+    /// Corresponds to C function [mps_telemetry_flush](https://www.ravenbrook.com/project/mps/master/manual/html/topic/telemetry.html#c.mps_telemetry_flush)
+    #[inline]
+    pub fn flush_telemetry(&self) {
+        unsafe { mps_telemetry_flush() }
     }
 
     /// Registers the currently running thread with this arena.
@@ -147,12 +323,41 @@ impl Arena {
             // TODO: Should this be unsafe?
             let mut res: mps_thr_t = std::ptr::null_mut();
             handle_mps_res!(::mps_sys::mps_thread_reg(&mut res, self.raw))?;
-            Ok(MpsThread { raw: res, arena: self })
+            let registration = self.register(HandleKind::Thread, None, res as *mut c_void);
+            Ok(MpsThread { raw: res, arena: self, registration })
         }
     }
+    /// Register a table of `count` references starting at `base` as an ambiguous root.
+    ///
+    /// Corresponds to C function [mps_root_create_table](https://www.ravenbrook.com/project/mps/master/manual/html/topic/root.html#c.mps_root_create_table)
+    ///
+    /// ## Safety
+    /// The table must stay valid (and must not move) until the root is dropped.
+    pub(crate) unsafe fn register_ambiguous_table(&self, base: *mut mps_addr_t, count: usize) -> Result<MpsRoot, MpsError> {
+        let mut res: mps_root_t = std::ptr::null_mut();
+        handle_mps_res!(::mps_sys::mps_root_create_table(
+            &mut res, self.raw,
+            ::mps_sys::mps_rank_ambig(), 0,
+            base, count
+        ))?;
+        let registration = self.register(HandleKind::Root, None, res as *mut c_void);
+        Ok(MpsRoot { raw: res, registration })
+    }
 }
 impl Drop for Arena {
     fn drop(&mut self) {
+        if cfg!(debug_assertions) && !std::thread::panicking() {
+            let alive = self.handles();
+            if !alive.is_empty() {
+                let alive = alive.iter().map(|handle| handle.to_string())
+                    .collect::<Vec<_>>().join(", ");
+                panic!("Arena dropped while its handles are still alive: {}", alive);
+            }
+        }
+        crate::assertion::forget_arena(self.raw);
+        crate::postmortem::forget_arena(self.raw);
+        #[cfg(target_os = "linux")]
+        crate::signal::remove_arena(self.raw);
         unsafe {
             // NOTE: Everything else must be destroyed first
             mps_arena_destroy(self.raw);
@@ -190,7 +395,9 @@ impl VirtualMemoryArenaClass {
             arena_size: None,
             commit_limit: None,
             spare: None,
-            pause_time: None
+            pause_time: None,
+            cgroup_headroom: None,
+            debug_mode: false
         }
     }
 }
@@ -219,13 +426,52 @@ pub struct VirtualMemoryArenaBuilder {
     ///
     /// See [mps_arena_pause_time_set](https://www.ravenbrook.com/project/mps/master/manual/html/topic/arena.html#c.mps_arena_pause_time_set)
     pub pause_time: Option<f64>,
+    /// Derive the commit limit from the cgroup memory limit (if any),
+    /// leaving the specified fraction of it as headroom.
+    ///
+    /// If `commit_limit` is also given, the smaller of the two is used.
+    /// See the [cgroup](crate::cgroup) module for details.
+    ///
+    /// If the limit can't be read, [VirtualMemoryArenaBuilder::build] fails with [MpsError::Io].
+    /// Use [VirtualMemoryArenaBuilder::apply_cgroup_limit] instead to get the underlying error.
+    pub cgroup_headroom: Option<f64>,
+    /// Create the arena in "debug mode",
+    /// where the client program never hits the MPS memory protection (read and write barriers).
+    ///
+    /// The arena is kept [parked](Arena::park), so collections only happen
+    /// during explicit calls to [Arena::full_collection] (or [Arena::begin_collection]),
+    /// which collect the whole arena before returning.
+    /// The MPS still protects memory during a collection (and keeps its write barriers),
+    /// so afterwards all of the protection is removed with [Arena::expose].
+    /// As a result, there are no `SIGSEGV`s to confuse a debugger.
+    /// This also makes collections deterministic, which is useful for tests.
+    ///
+    /// Since nothing is collected automatically,
+    /// allocation fails with [MpsError::CommitLimit] instead of waiting for a collection.
+    /// This is much slower than an ordinary arena and shouldn't be used in production.
+    pub debug_mode: bool,
 }
 impl VirtualMemoryArenaBuilder {
+    /// Lower the `commit_limit` to the cgroup memory limit (if any),
+    /// leaving the specified fraction of it as headroom.
+    ///
+    /// This is what [VirtualMemoryArenaBuilder::build] does for the `cgroup_headroom`,
+    /// except that it returns the error if the limit can't be read.
+    pub fn apply_cgroup_limit(&mut self, headroom: f64) -> std::io::Result<()> {
+        if let Some(limit) = crate::cgroup::memory_limit()? {
+            let cgroup_limit = crate::cgroup::commit_limit_for(limit, headroom);
+            self.commit_limit = Some(self.commit_limit.map_or(cgroup_limit, |old| old.min(cgroup_limit)));
+        }
+        Ok(())
+    }
     /// Attempt to create a virtual memory arena with the current settings,
     /// returning an error on failure
-    pub fn build(self) -> Result<Arena, MpsError> {
+    pub fn build(mut self) -> Result<Arena, MpsError> {
+        if let Some(headroom) = self.cgroup_headroom.take() {
+            self.apply_cgroup_limit(headroom).map_err(|_| MpsError::Io)?;
+        }
         let VirtualMemoryArenaBuilder { class, arena_size,
-            commit_limit, spare, pause_time } = self;
+            commit_limit, spare, pause_time, cgroup_headroom: _, debug_mode } = self;
         let mut kws: ArrayVec<_, 5> = ArrayVec::new();
         unsafe {
             if let Some(size) = arena_size {
@@ -243,21 +489,143 @@ impl VirtualMemoryArenaBuilder {
                 kws.push(mps_kw_arg!(PAUSE_TIME => pause_time));
             }
             kws.push(mps_args_end());
-            let mut out: mps_arena_t = std::ptr::null_mut();
-            handle_mps_res!(mps_arena_create_k(
-                &mut out, class, kws.as_mut_ptr()
-            ))?;
+            let args = kws.as_mut_ptr();
+            let create = || {
+                let mut out: mps_arena_t = std::ptr::null_mut();
+                handle_mps_res!(mps_arena_create_k(&mut out, class, args)).map(|()| out)
+            };
+            // NOTE: The first arena installs the MPS protection handler
+            #[cfg(target_os = "linux")]
+            let out = crate::signal::watch_install(create)?;
+            #[cfg(not(target_os = "linux"))]
+            let out = create()?;
             assert!(!out.is_null());
-            Ok(Arena { raw: out })
+            #[cfg(target_os = "linux")]
+            crate::signal::add_arena(out);
+            let control = ArenaControl::new(out, debug_mode);
+            if debug_mode {
+                control.park();
+            }
+            Ok(Arena { raw: out, registry: Registry::default(), control })
         }
     }
 }
 
+/// The [state](https://www.ravenbrook.com/project/mps/master/manual/html/topic/arena.html#arena-states)
+/// of an arena, which controls when collections happen
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ArenaState {
+    /// The MPS collects whenever it wants (the default)
+    Unclamped,
+    /// No object motion occurs,
+    /// and collection work only happens when the arena is [stepped](Arena::step)
+    Clamped,
+    /// No collection is in progress, and no new ones start
+    Parked
+}
+
+/// Changes the state of an arena, keeping track of it
+///
+/// This is shared with objects that only hold the raw arena
+/// (like a [RetryPolicy](crate::retry::RetryPolicy)),
+/// so they can restore the state they found the arena in.
+#[derive(Clone)]
+pub(crate) struct ArenaControl {
+    raw: mps_arena_t,
+    state: Arc<AtomicU8>,
+    pub(crate) debug_mode: bool
+}
+impl ArenaControl {
+    fn new(raw: mps_arena_t, debug_mode: bool) -> ArenaControl {
+        ArenaControl {
+            raw, debug_mode,
+            state: Arc::new(AtomicU8::new(ArenaState::Unclamped as u8))
+        }
+    }
+    #[inline]
+    pub(crate) fn as_raw(&self) -> mps_arena_t {
+        self.raw
+    }
+    #[inline]
+    pub(crate) fn state(&self) -> ArenaState {
+        match self.state.load(Ordering::Acquire) {
+            0 => ArenaState::Unclamped,
+            1 => ArenaState::Clamped,
+            _ => ArenaState::Parked
+        }
+    }
+    #[inline]
+    fn set_state(&self, state: ArenaState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+    pub(crate) fn park(&self) {
+        unsafe { mps_arena_park(self.raw) }
+        self.set_state(ArenaState::Parked);
+    }
+    pub(crate) fn clamp(&self) {
+        unsafe { mps_arena_clamp(self.raw) }
+        self.set_state(ArenaState::Clamped);
+    }
+    /// In debug mode, this does nothing and the arena stays parked.
+    pub(crate) fn release(&self) {
+        if !self.debug_mode {
+            unsafe { mps_arena_release(self.raw) }
+            self.set_state(ArenaState::Unclamped);
+        }
+    }
+    /// Run a full collection, leaving the arena parked
+    ///
+    /// In debug mode, this removes the memory protection afterwards.
+    pub(crate) fn collect(&self) {
+        unsafe { mps_arena_collect(self.raw); }
+        if self.debug_mode {
+            unsafe { mps_arena_expose(self.raw); }
+        }
+        self.set_state(ArenaState::Parked);
+    }
+    /// In debug mode, this does a full collection instead.
+    pub(crate) fn start_collect(&self) -> Result<(), MpsError> {
+        if self.debug_mode {
+            self.collect();
+            return Ok(());
+        }
+        // NOTE: The arena is released once the collection starts
+        let res = unsafe { handle_mps_res!(mps_arena_start_collect(self.raw)) };
+        self.set_state(ArenaState::Unclamped);
+        res
+    }
+    /// In debug mode, this never does any work.
+    pub(crate) fn step(&self, interval: f64, multiplier: f64) -> bool {
+        if self.debug_mode {
+            return false;
+        }
+        let worked = unsafe { mps_arena_step(self.raw, interval, multiplier) != 0 };
+        // NOTE: Stepping a parked arena leaves it clamped
+        if self.state() == ArenaState::Parked {
+            self.set_state(ArenaState::Clamped);
+        }
+        worked
+    }
+    /// Put the arena back into a previous state
+    pub(crate) fn restore(&self, state: ArenaState) {
+        match state {
+            ArenaState::Unclamped => self.release(),
+            ArenaState::Clamped => self.clamp(),
+            ArenaState::Parked => self.park()
+        }
+    }
+}
+/// The MPS is thread safe
+unsafe impl Send for ArenaControl {}
+unsafe impl Sync for ArenaControl {}
+
 /// A garbage collection root that has been registered with the MPS
 ///
 /// Dropping this structure unregisters the root.
 pub struct MpsRoot {
-    raw: mps_root_t
+    raw: mps_root_t,
+    // Unregistered before the root is destroyed
+    registration: Registration
 }
 impl MpsRoot {
     /// Retrieve the raw pointer to the root
@@ -265,9 +633,19 @@ impl MpsRoot {
     pub fn as_raw(&self) -> mps_root_t {
         self.raw
     }
+    /// Give the root a human-readable name,
+    /// which is used to label it in the telemetry stream.
+    ///
+    /// Roots are created directly (without a builder),
+    /// so they can only be named after they are registered.
+    #[inline]
+    pub fn set_name(&self, name: &str) {
+        self.registration.set_name(name)
+    }
 }
 impl Drop for MpsRoot {
     fn drop(&mut self) {
+        self.registration.unregister();
         unsafe {
             ::mps_sys::mps_root_destroy(self.raw)
         }
@@ -287,7 +665,9 @@ impl Drop for MpsRoot {
 /// this structure must be dropped to deregister it first.
 pub struct MpsThread<'arena> {
     raw: mps_thr_t,
-    arena: &'arena Arena
+    arena: &'arena Arena,
+    // Unregistered before the thread is deregistered
+    registration: Registration
 }
 impl<'arena> MpsThread<'arena> {
     /// Retrieve the raw identifier of the thread
@@ -322,7 +702,8 @@ impl<'arena> MpsThread<'arena> {
             self.raw,
             cold_addr
         ))?;
-        Ok(MpsRoot { raw: res })
+        let registration = self.arena.register(HandleKind::Root, None, res as *mut c_void);
+        Ok(MpsRoot { raw: res, registration })
     }
 }
 /// This is used both as a marker and as a logical guard for registration.
@@ -330,6 +711,7 @@ impl !Send for MpsThread<'_> {}
 impl !Sync for MpsThread<'_> {}
 impl Drop for MpsThread<'_> {
     fn drop(&mut self) {
+        self.registration.unregister();
         unsafe {
             ::mps_sys::mps_thread_dereg(self.raw)
         }
@@ -0,0 +1,144 @@
+//! Handling assertion failures inside the MPS
+//!
+//! By default, a failed assertion prints a C message and aborts the process.
+//! An [AssertionHandler] reports the failure through a Rust callback instead,
+//! along with a [Backtrace] and (optionally) the [ArenaStats] of an arena.
+//!
+//! The process is always aborted after the callback returns,
+//! because the MPS can't continue after an assertion fails.
+//!
+//! Corresponds to C function [mps_lib_assert_fail_install](https://www.ravenbrook.com/project/mps/master/manual/html/topic/plinth.html#c.mps_lib_assert_fail_install)
+use std::backtrace::Backtrace;
+use std::ffi::CStr;
+use std::fmt::{self, Display, Formatter};
+use std::os::raw::{c_char, c_uint};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+
+use mps_sys::mps_arena_t;
+
+use crate::arena::Arena;
+use crate::registry::Registry;
+use crate::stats::ArenaStats;
+
+/// The currently installed handler (if any)
+static HANDLER: Mutex<Option<AssertionHandler>> = Mutex::new(None);
+
+/// Details about an assertion that failed inside the MPS,
+/// passed to the callback of an [AssertionHandler]
+#[derive(Debug)]
+pub struct AssertionFailure {
+    /// The MPS source file containing the assertion
+    pub file: String,
+    /// The line number of the assertion
+    pub line: u32,
+    /// The condition that failed
+    pub condition: String,
+    /// The Rust backtrace, captured when the assertion failed
+    ///
+    /// This is captured even if `RUST_BACKTRACE` isn't set.
+    pub backtrace: Backtrace,
+    /// The statistics of the arena given to [AssertionHandler::dump_stats]
+    ///
+    /// The pool statistics are omitted if the arena's handles
+    /// were being modified when the assertion failed.
+    pub stats: Option<ArenaStats>
+}
+/// Describes the failure, followed by the arena statistics
+/// (in the Prometheus format) and the backtrace
+impl Display for AssertionFailure {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, "MPS assertion failed: {} ({}:{})", self.condition, self.file, self.line)?;
+        if let Some(ref stats) = self.stats {
+            write!(f, "{}", stats.prometheus())?;
+        }
+        write!(f, "{}", self.backtrace)
+    }
+}
+
+/// A Rust handler for assertion failures in the MPS
+pub struct AssertionHandler {
+    callback: Box<dyn Fn(&AssertionFailure) + Send + Sync>,
+    arena: Option<(mps_arena_t, Registry)>
+}
+impl AssertionHandler {
+    /// Create a handler that reports failures to the specified callback
+    ///
+    /// The callback shouldn't use the MPS,
+    /// which is in an inconsistent state when it's called.
+    #[inline]
+    pub fn new<F>(callback: F) -> Self
+        where F: Fn(&AssertionFailure) + Send + Sync + 'static {
+        AssertionHandler { callback: Box::new(callback), arena: None }
+    }
+    /// Create a handler that prints failures to stderr (see [AssertionFailure]'s `Display` impl)
+    #[inline]
+    pub fn stderr() -> Self {
+        AssertionHandler::new(|failure| eprintln!("{}", failure))
+    }
+    /// Include the statistics of the specified arena when an assertion fails
+    ///
+    /// To read the statistics, the arena is put into the postmortem state
+    /// (releasing its lock and removing its memory protection).
+    /// This is only done because the process is about to abort anyway.
+    ///
+    /// If the arena is dropped first, its statistics are no longer included.
+    #[inline]
+    pub fn dump_stats(mut self, arena: &Arena) -> Self {
+        self.arena = Some((arena.as_raw(), arena.registry().clone()));
+        self
+    }
+    /// Install this handler, replacing the previous one
+    pub fn install(self) {
+        *HANDLER.lock().unwrap_or_else(|e| e.into_inner()) = Some(self);
+        unsafe {
+            mps_sys::mps_lib_assert_fail_install(Some(handle_assertion));
+        }
+    }
+}
+/// The raw arena is only used once an assertion has failed
+unsafe impl Send for AssertionHandler {}
+
+/// Stop dumping the statistics of an arena that is being destroyed
+pub(crate) fn forget_arena(raw: mps_arena_t) {
+    // NOTE: Even if the lock is poisoned, the arena must be forgotten before it's destroyed
+    let mut handler = HANDLER.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(handler) = handler.as_mut() {
+        if matches!(handler.arena, Some((arena, _)) if arena == raw) {
+            handler.arena = None;
+        }
+    }
+}
+
+unsafe extern "C" fn handle_assertion(file: *const c_char, line: c_uint, condition: *const c_char) {
+    let string = |s: *const c_char| if s.is_null() {
+        String::from("<unknown>")
+    } else {
+        CStr::from_ptr(s).to_string_lossy().into_owned()
+    };
+    let (file, condition) = (string(file), string(condition));
+    // NOTE: The lock is already held if the callback fails an assertion itself
+    match HANDLER.try_lock() {
+        Ok(handler) => {
+            if let Some(ref handler) = *handler {
+                // NOTE: Panics can't unwind into C
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+                    let stats = handler.arena.as_ref().map(|&(raw, ref registry)| {
+                        mps_sys::mps_arena_postmortem(raw);
+                        registry.try_with_entries(|entries| ArenaStats::collect(raw, entries))
+                            .unwrap_or_else(|| ArenaStats::collect(raw, &[]))
+                    });
+                    (handler.callback)(&AssertionFailure {
+                        file: file.clone(),
+                        line,
+                        condition: condition.clone(),
+                        backtrace: Backtrace::force_capture(),
+                        stats
+                    });
+                }));
+            }
+        },
+        Err(_) => eprintln!("MPS assertion failed: {} ({}:{})", condition, file, line)
+    }
+    std::process::abort();
+}
@@ -0,0 +1,358 @@
+//! Inspects telemetry logs and heap snapshots written by the `mps` crate.
+//!
+//! This understands the crate's own formats,
+//! so the C `mpseventcnv`/`mpseventtxt` utilities aren't needed.
+//!
+//! Requires the `inspect` feature.
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use argh::FromArgs;
+use mps::snapshot::HeapSnapshot;
+use mps::telemetry::{EventReader, EventKind};
+
+/// Inspect MPS telemetry logs and heap snapshots
+#[derive(FromArgs)]
+struct Inspect {
+    #[argh(subcommand)]
+    command: Command
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Telemetry(TelemetryCommand),
+    Snapshot(SnapshotCommand),
+    Diff(DiffCommand)
+}
+
+/// summarize pause times and generation survival rates from a telemetry log
+#[derive(FromArgs)]
+#[argh(subcommand, name = "telemetry")]
+struct TelemetryCommand {
+    /// the telemetry log (usually `mpsio.log`)
+    #[argh(positional)]
+    log: PathBuf,
+    /// the number of pools to show
+    #[argh(option, default = "10")]
+    top: usize
+}
+
+/// show the largest classes in a heap snapshot
+#[derive(FromArgs)]
+#[argh(subcommand, name = "snapshot")]
+struct SnapshotCommand {
+    /// the heap snapshot
+    #[argh(positional)]
+    snapshot: PathBuf,
+    /// the number of classes to show
+    #[argh(option, default = "20")]
+    top: usize
+}
+
+/// compare two heap snapshots
+#[derive(FromArgs)]
+#[argh(subcommand, name = "diff")]
+struct DiffCommand {
+    /// the earlier snapshot
+    #[argh(positional)]
+    before: PathBuf,
+    /// the later snapshot
+    #[argh(positional)]
+    after: PathBuf,
+    /// the number of classes to show
+    #[argh(option, default = "20")]
+    top: usize
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Inspect = argh::from_env();
+    match args.command {
+        Command::Telemetry(cmd) => inspect_telemetry(&cmd),
+        Command::Snapshot(cmd) => inspect_snapshot(&cmd),
+        Command::Diff(cmd) => diff_snapshots(&cmd)
+    }
+}
+
+fn inspect_telemetry(cmd: &TelemetryCommand) -> Result<(), Box<dyn Error>> {
+    let mut clocks_per_second = None;
+    let mut strings: HashMap<u64, String> = HashMap::new();
+    let mut labels: HashMap<u64, u64> = HashMap::new();
+    let mut poll_starts: HashMap<u64, u64> = HashMap::new();
+    let mut pauses: Vec<u64> = Vec::new();
+    let mut collections = 0usize;
+    let mut generations: Vec<(u64, GenerationSurvival)> = Vec::new();
+    let mut allocated: HashMap<u64, u64> = HashMap::new();
+    let mut total_events = 0usize;
+    for event in EventReader::open(&cmd.log)? {
+        let event = event?;
+        total_events += 1;
+        match event.kind() {
+            EventKind::Init { clocks_per_second: clocks, .. } => clocks_per_second = Some(clocks),
+            EventKind::Intern { id, string } => { strings.insert(id, string); },
+            EventKind::Label { address, id } => { labels.insert(address, id); },
+            EventKind::PollBegin { arena } => { poll_starts.insert(arena, event.clock); },
+            EventKind::PollEnd { arena } => {
+                if let Some(start) = poll_starts.remove(&arena) {
+                    pauses.push(event.clock.saturating_sub(start));
+                }
+            },
+            EventKind::CollectionStart { .. } => collections += 1,
+            EventKind::GenerationEnd { generation, condemned, forwarded, preserved, .. } => {
+                let index = match generations.iter().position(|&(gen, _)| gen == generation) {
+                    Some(index) => index,
+                    None => {
+                        generations.push((generation, GenerationSurvival::default()));
+                        generations.len() - 1
+                    }
+                };
+                let survival = &mut generations[index].1;
+                survival.condemned += condemned;
+                survival.survived += forwarded + preserved;
+                survival.collections += 1;
+            },
+            EventKind::Alloc { pool, size, .. } => *allocated.entry(pool).or_insert(0) += size,
+            _ => {}
+        }
+    }
+    println!("{} events, {} collections", total_events, collections);
+    println!();
+    print_pauses(&mut pauses, clocks_per_second);
+    println!();
+    println!("Generation survival rates:");
+    if generations.is_empty() {
+        println!("  (no generation events, enable the TRACE category)");
+    }
+    for &(generation, ref survival) in &generations {
+        println!(
+            "  {}: {:>6.2}% of {} condemned over {} collections",
+            label_name(&labels, &strings, generation), survival.rate() * 100.0,
+            format_bytes(survival.condemned), survival.collections
+        );
+    }
+    if !allocated.is_empty() {
+        println!();
+        println!("Largest pools by allocation:");
+        let mut allocated: Vec<(u64, u64)> = allocated.into_iter().collect();
+        allocated.sort_by_key(|&(_, size)| std::cmp::Reverse(size));
+        for &(pool, size) in allocated.iter().take(cmd.top) {
+            println!("  {:>12}  {}", format_bytes(size), label_name(&labels, &strings, pool));
+        }
+    }
+    Ok(())
+}
+
+/// The interned label of an address, or the address itself if it isn't labelled
+fn label_name(labels: &HashMap<u64, u64>, strings: &HashMap<u64, String>, address: u64) -> String {
+    labels.get(&address)
+        .and_then(|id| strings.get(id))
+        .cloned()
+        .unwrap_or_else(|| format!("{:#x}", address))
+}
+
+#[derive(Default)]
+struct GenerationSurvival {
+    condemned: u64,
+    survived: u64,
+    collections: usize
+}
+impl GenerationSurvival {
+    fn rate(&self) -> f64 {
+        if self.condemned == 0 {
+            0.0
+        } else {
+            self.survived as f64 / self.condemned as f64
+        }
+    }
+}
+
+fn print_pauses(pauses: &mut [u64], clocks_per_second: Option<u64>) {
+    if pauses.is_empty() {
+        println!("Pause times: (no poll events, enable the ARENA category)");
+        return;
+    }
+    pauses.sort_unstable();
+    let format_pause = |ticks: u64| match clocks_per_second {
+        Some(clocks) if clocks > 0 => format!("{:.3}ms", ticks as f64 * 1000.0 / clocks as f64),
+        _ => format!("{} ticks", ticks)
+    };
+    println!("Pause times ({} pauses, {} total):", pauses.len(), format_pause(pauses.iter().sum()));
+    for &(label, p) in &[("min", 0.0), ("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("max", 1.0)] {
+        println!("  {:>4}: {}", label, format_pause(percentile(pauses, p)));
+    }
+    let buckets = histogram(pauses);
+    let widest = buckets.iter().map(|&(_, count)| count).max().unwrap_or(1);
+    for &(upper, count) in &buckets {
+        println!("  <= {:>14} {:>8} {}", format_pause(upper), count, bar(count, widest));
+    }
+}
+
+/// The value at the specified percentile (between 0 and 1) of a sorted, non-empty slice
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    sorted[((sorted.len() - 1) as f64 * p).round() as usize]
+}
+
+/// Count pauses in power of two buckets,
+/// returning the (inclusive) upper bound and the count of each non-empty bucket.
+fn histogram(pauses: &[u64]) -> Vec<(u64, usize)> {
+    let mut buckets: Vec<usize> = Vec::new();
+    for &pause in pauses {
+        let bucket = (64 - pause.leading_zeros()) as usize;
+        if buckets.len() <= bucket {
+            buckets.resize(bucket + 1, 0);
+        }
+        buckets[bucket] += 1;
+    }
+    buckets.iter().enumerate()
+        .filter(|&(_, &count)| count > 0)
+        .map(|(bucket, &count)| {
+            let upper = if bucket == 0 { 0 } else { u64::MAX >> (64 - bucket) };
+            (upper, count)
+        })
+        .collect()
+}
+
+/// A bar of up to 40 characters, proportional to `count`
+fn bar(count: usize, widest: usize) -> String {
+    "#".repeat((count * 40).div_ceil(widest.max(1)))
+}
+
+fn read_snapshot(path: &Path) -> Result<HeapSnapshot, Box<dyn Error>> {
+    let text = fs::read_to_string(path)?;
+    Ok(text.parse::<HeapSnapshot>().map_err(|e| format!("{}: {}", path.display(), e))?)
+}
+
+fn inspect_snapshot(cmd: &SnapshotCommand) -> Result<(), Box<dyn Error>> {
+    let snapshot = read_snapshot(&cmd.snapshot)?;
+    let stats = &snapshot.stats;
+    println!(
+        "Committed {} ({} spare) of {} limit, {} moving collections",
+        format_bytes(stats.committed as u64), format_bytes(stats.spare_committed as u64),
+        format_bytes(stats.commit_limit as u64), stats.moved_collections
+    );
+    println!();
+    println!("Pools:");
+    for pool in &stats.pools {
+        println!(
+            "  #{} {} {}: {} total, {} free",
+            pool.id, pool.class, pool.name.as_deref().unwrap_or(""),
+            format_bytes(pool.total_size as u64), format_bytes(pool.free_size as u64)
+        );
+    }
+    println!();
+    let total: u64 = snapshot.classes.iter().map(|class| class.size).sum();
+    println!("Largest classes ({} total):", format_bytes(total));
+    for class in snapshot.classes.iter().take(cmd.top) {
+        println!(
+            "  {:>12} {:>6.2}% {:>10} objects  {}",
+            format_bytes(class.size),
+            if total == 0 { 0.0 } else { class.size as f64 * 100.0 / total as f64 },
+            class.count, class.name
+        );
+    }
+    Ok(())
+}
+
+fn diff_snapshots(cmd: &DiffCommand) -> Result<(), Box<dyn Error>> {
+    let before = read_snapshot(&cmd.before)?;
+    let after = read_snapshot(&cmd.after)?;
+    println!(
+        "Committed: {} -> {} ({})",
+        format_bytes(before.stats.committed as u64),
+        format_bytes(after.stats.committed as u64),
+        format_change(after.stats.committed as i64 - before.stats.committed as i64)
+    );
+    println!();
+    println!("Largest changes:");
+    for diff in before.diff(&after).iter().take(cmd.top) {
+        println!(
+            "  {:>13} {:>+10} objects  {}",
+            format_change(diff.size_change), diff.count_change, diff.name
+        );
+    }
+    Ok(())
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+fn format_change(change: i64) -> String {
+    let sign = if change < 0 { '-' } else { '+' };
+    format!("{}{}", sign, format_bytes(change.unsigned_abs()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let pauses: Vec<u64> = (1..=101).collect();
+        assert_eq!(percentile(&pauses, 0.0), 1);
+        assert_eq!(percentile(&pauses, 0.5), 51);
+        assert_eq!(percentile(&pauses, 0.9), 91);
+        assert_eq!(percentile(&pauses, 0.99), 100);
+        assert_eq!(percentile(&pauses, 1.0), 101);
+        assert_eq!(percentile(&[7], 0.5), 7);
+    }
+
+    #[test]
+    fn histogram_buckets() {
+        assert_eq!(histogram(&[]), []);
+        assert_eq!(histogram(&[0, 1, 2, 3, 4, 7, 8, 1000]), [
+            (0, 1), (1, 1), (3, 2), (7, 2), (15, 1), (1023, 1)
+        ]);
+        assert_eq!(histogram(&[u64::MAX, 1 << 63]), [(u64::MAX, 2)]);
+    }
+
+    #[test]
+    fn bars() {
+        assert_eq!(bar(10, 10).len(), 40);
+        assert_eq!(bar(5, 10).len(), 20);
+        // Any non-zero count gets at least one character
+        assert_eq!(bar(1, 1000), "#");
+    }
+
+    #[test]
+    fn generation_survival() {
+        assert_eq!(GenerationSurvival::default().rate(), 0.0);
+        let survival = GenerationSurvival { condemned: 200, survived: 50, collections: 2 };
+        assert_eq!(survival.rate(), 0.25);
+    }
+
+    #[test]
+    fn bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1024), "1.0 KiB");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(5 << 20), "5.0 MiB");
+        assert_eq!(format_bytes(3 << 30), "3.0 GiB");
+        assert_eq!(format_bytes(2048 << 40), "2048.0 TiB");
+        assert_eq!(format_change(-2048), "-2.0 KiB");
+        assert_eq!(format_change(0), "+0 B");
+    }
+
+    #[test]
+    fn labels() {
+        let strings = HashMap::from([(1, "nursery".to_string())]);
+        let labels = HashMap::from([(0x1000, 1), (0x2000, 2)]);
+        assert_eq!(label_name(&labels, &strings, 0x1000), "nursery");
+        // Missing strings and labels fall back to the address
+        assert_eq!(label_name(&labels, &strings, 0x2000), "0x2000");
+        assert_eq!(label_name(&labels, &strings, 0x3000), "0x3000");
+    }
+}
@@ -0,0 +1,336 @@
+//! Deriving the arena's commit limit from the [cgroup](https://man7.org/linux/man-pages/man7/cgroups.7.html)
+//! memory limit.
+//!
+//! Inside a container, exceeding `memory.max` gets the process killed by the kernel.
+//! Setting the commit limit a bit lower than that
+//! lets the MPS collect (or fail gracefully) instead.
+//!
+//! Both cgroup v1 and v2 are supported.
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::arena::Arena;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Values at least this large are treated as "no limit" by cgroup v1
+///
+/// The kernel reports `PAGE_COUNTER_MAX` rounded down to a page,
+/// which is close to `i64::MAX`.
+const V1_UNLIMITED: u64 = 1 << 62;
+
+/// Read the memory limit of the current process's cgroup (in bytes)
+///
+/// The limits of the ancestors of the cgroup also apply,
+/// so this is the smallest limit in the hierarchy.
+/// Returns `None` if there is no limit,
+/// or if the process isn't in a cgroup with a memory controller.
+pub fn memory_limit() -> io::Result<Option<u64>> {
+    match read_optional(Path::new("/proc/self/cgroup"))? {
+        Some(cgroups) => memory_limit_in(Path::new(CGROUP_ROOT), &cgroups),
+        None => Ok(None)
+    }
+}
+
+/// Find the memory limit for the contents of `/proc/self/cgroup`,
+/// with the cgroup filesystem mounted at `root`
+fn memory_limit_in(root: &Path, cgroups: &str) -> io::Result<Option<u64>> {
+    for line in cgroups.lines() {
+        let mut parts = line.splitn(3, ':');
+        let (hierarchy, controllers, path) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(hierarchy), Some(controllers), Some(path)) => (hierarchy, controllers, path),
+            _ => continue
+        };
+        if hierarchy == "0" && controllers.is_empty() {
+            // cgroup v2 (unified hierarchy)
+            if let Some(limit) = hierarchy_limit(root, path, "memory.max", parse_v2_limit)? {
+                return Ok(limit)
+            }
+        } else if controllers.split(',').any(|controller| controller == "memory") {
+            // cgroup v1
+            let root = root.join("memory");
+            if let Some(limit) = hierarchy_limit(&root, path, "memory.limit_in_bytes", parse_v1_limit)? {
+                return Ok(limit)
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Find the smallest limit in the specified cgroup and all of its ancestors (up to `root`)
+///
+/// Returns `None` if none of them have a limit file.
+fn hierarchy_limit(
+    root: &Path, path: &str, file: &str,
+    parse: fn(&str) -> Option<u64>
+) -> io::Result<Option<Option<u64>>> {
+    let mut dir = join_cgroup_path(root, path);
+    if !dir.is_dir() {
+        // Inside a cgroup namespace, our cgroup is mounted at the root
+        dir = root.to_path_buf();
+    }
+    let mut found = false;
+    let mut limit: Option<u64> = None;
+    loop {
+        if let Some(s) = read_optional(&dir.join(file))? {
+            found = true;
+            if let Some(parsed) = parse(&s) {
+                limit = Some(limit.map_or(parsed, |limit| limit.min(parsed)));
+            }
+        }
+        if dir == root || !dir.pop() || !dir.starts_with(root) {
+            break
+        }
+    }
+    Ok(if found { Some(limit) } else { None })
+}
+
+/// Compute the commit limit for the specified cgroup memory limit,
+/// leaving the specified fraction of it as headroom for memory used outside the MPS.
+#[inline]
+pub fn commit_limit_for(memory_limit: u64, headroom: f64) -> usize {
+    assert!((0.0..1.0).contains(&headroom), "Invalid headroom: {}", headroom);
+    let limit = (memory_limit as f64 * (1.0 - headroom)) as u64;
+    usize::try_from(limit).unwrap_or(usize::MAX)
+}
+
+/// The commit limit to use for the specified cgroup memory limit,
+/// never exceeding the user's own `max_commit_limit`
+#[inline]
+fn target_commit_limit(memory_limit: Option<u64>, headroom: f64, max_commit_limit: usize) -> usize {
+    memory_limit.map_or(max_commit_limit, |limit| {
+        commit_limit_for(limit, headroom).min(max_commit_limit)
+    })
+}
+
+fn join_cgroup_path(root: &Path, path: &str) -> PathBuf {
+    root.join(path.trim_start_matches('/'))
+}
+
+fn parse_v2_limit(limit: &str) -> Option<u64> {
+    match limit.trim() {
+        "max" => None,
+        limit => limit.parse().ok()
+    }
+}
+
+fn parse_v1_limit(limit: &str) -> Option<u64> {
+    limit.trim().parse().ok().filter(|&limit| limit < V1_UNLIMITED)
+}
+
+fn read_optional(path: &Path) -> io::Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(s) => Ok(Some(s)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e)
+    }
+}
+
+/// A background thread that adjusts the arena's commit limit
+/// whenever the cgroup memory limit changes.
+///
+/// The thread is stopped when this is dropped.
+pub struct CgroupWatcher<'arena> {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+    marker: PhantomData<&'arena Arena>
+}
+impl<'arena> CgroupWatcher<'arena> {
+    /// Start watching the cgroup memory limit, checking it every `interval`.
+    ///
+    /// The commit limit is computed by [commit_limit_for] with the specified headroom,
+    /// but is never set above `max_commit_limit` (if any).
+    /// If the cgroup has no memory limit, the commit limit is set to `max_commit_limit`.
+    ///
+    /// If the new limit is lower than the memory already committed,
+    /// a collection is requested and the limit is set on a later check.
+    /// A parked (or clamped) arena is put back into that state afterwards,
+    /// which waits for the collection to finish if it was parked.
+    /// In [debug mode](crate::arena::VirtualMemoryArenaBuilder::debug_mode),
+    /// collections are only run when explicitly requested, so the watcher never starts one.
+    ///
+    /// ## Safety
+    /// The watcher must be dropped before the arena.
+    /// Leaking it (for example with [std::mem::forget])
+    /// leaves the thread using the arena after it is destroyed.
+    pub unsafe fn spawn(
+        arena: &'arena Arena, max_commit_limit: Option<usize>,
+        headroom: f64, interval: Duration
+    ) -> io::Result<CgroupWatcher<'arena>> {
+        assert!((0.0..1.0).contains(&headroom), "Invalid headroom: {}", headroom);
+        let max_commit_limit = max_commit_limit.unwrap_or(usize::MAX);
+        // The thread is joined when the watcher is dropped
+        let arena: &'static Arena = &*(arena as *const Arena);
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let handle = thread::Builder::new()
+            .name("mps-cgroup-watcher".into())
+            .spawn(move || {
+                let mut last_limit = None;
+                while !thread_stop.load(Ordering::Acquire) {
+                    // NOTE: Errors are transient (or permanent and harmless)
+                    if let Ok(limit) = memory_limit() {
+                        let commit_limit = target_commit_limit(limit, headroom, max_commit_limit);
+                        if last_limit != Some(commit_limit) {
+                            if arena.set_commit_limit(commit_limit).is_ok() {
+                                last_limit = Some(commit_limit);
+                            } else {
+                                make_room(arena);
+                            }
+                        }
+                    }
+                    thread::park_timeout(interval);
+                }
+            })?;
+        Ok(CgroupWatcher { stop, handle: Some(handle), marker: PhantomData })
+    }
+}
+/// Start a collection to free up memory for a lower commit limit,
+/// leaving the arena in its current state
+fn make_room(arena: &Arena) {
+    if arena.is_debug_mode() {
+        return
+    }
+    // NOTE: Another thread can still change the state meanwhile
+    let state = arena.state();
+    let _ = arena.begin_collection();
+    arena.control().restore(state);
+}
+impl Drop for CgroupWatcher<'_> {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A fake cgroup filesystem in a temporary directory
+    struct FakeCgroupRoot(PathBuf);
+    impl FakeCgroupRoot {
+        fn new(name: &str) -> FakeCgroupRoot {
+            let root = std::env::temp_dir()
+                .join(format!("mps-cgroup-test-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(&root).unwrap();
+            FakeCgroupRoot(root)
+        }
+        fn write(&self, path: &str, contents: &str) {
+            let path = self.0.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+    }
+    impl Drop for FakeCgroupRoot {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn v2_limit() {
+        assert_eq!(parse_v2_limit("536870912\n"), Some(536870912));
+        assert_eq!(parse_v2_limit("max\n"), None);
+        assert_eq!(parse_v2_limit("garbage"), None);
+    }
+
+    #[test]
+    fn v1_limit() {
+        assert_eq!(parse_v1_limit("536870912\n"), Some(536870912));
+        // PAGE_COUNTER_MAX rounded down to a 4K page
+        assert_eq!(parse_v1_limit("9223372036854771712\n"), None);
+        assert_eq!(parse_v1_limit("garbage"), None);
+    }
+
+    #[test]
+    fn v2_cgroup() {
+        let root = FakeCgroupRoot::new("v2");
+        root.write("user.slice/app.scope/memory.max", "1048576\n");
+        assert_eq!(memory_limit_in(&root.0, "0::/user.slice/app.scope\n").unwrap(), Some(1048576));
+        root.write("user.slice/app.scope/memory.max", "max\n");
+        assert_eq!(memory_limit_in(&root.0, "0::/user.slice/app.scope\n").unwrap(), None);
+    }
+
+    #[test]
+    fn v2_cgroup_namespace() {
+        // Our cgroup is mounted at the root, but /proc/self/cgroup still names it
+        let root = FakeCgroupRoot::new("v2-namespace");
+        root.write("memory.max", "2097152\n");
+        assert_eq!(memory_limit_in(&root.0, "0::/docker/abc123\n").unwrap(), Some(2097152));
+    }
+
+    #[test]
+    fn v2_ancestor_limit() {
+        let root = FakeCgroupRoot::new("v2-ancestor");
+        root.write("user.slice/memory.max", "1048576\n");
+        root.write("user.slice/app.scope/memory.max", "max\n");
+        assert_eq!(memory_limit_in(&root.0, "0::/user.slice/app.scope\n").unwrap(), Some(1048576));
+        // The tighter of the two limits applies
+        root.write("user.slice/app.scope/memory.max", "4096\n");
+        assert_eq!(memory_limit_in(&root.0, "0::/user.slice/app.scope\n").unwrap(), Some(4096));
+        root.write("user.slice/app.scope/memory.max", "2097152\n");
+        assert_eq!(memory_limit_in(&root.0, "0::/user.slice/app.scope\n").unwrap(), Some(1048576));
+    }
+
+    #[test]
+    fn v1_cgroup() {
+        let root = FakeCgroupRoot::new("v1");
+        root.write("memory/docker/abc123/memory.limit_in_bytes", "4194304\n");
+        let cgroups = "12:cpu,cpuacct:/docker/abc123\n\
+            11:memory:/docker/abc123\n\
+            1:name=systemd:/docker/abc123\n";
+        assert_eq!(memory_limit_in(&root.0, cgroups).unwrap(), Some(4194304));
+        root.write("memory/docker/abc123/memory.limit_in_bytes", "9223372036854771712\n");
+        assert_eq!(memory_limit_in(&root.0, cgroups).unwrap(), None);
+    }
+
+    #[test]
+    fn v1_ancestor_limit() {
+        let root = FakeCgroupRoot::new("v1-ancestor");
+        root.write("memory/memory.limit_in_bytes", "9223372036854771712\n");
+        root.write("memory/docker/memory.limit_in_bytes", "1048576\n");
+        root.write("memory/docker/abc123/memory.limit_in_bytes", "9223372036854771712\n");
+        assert_eq!(memory_limit_in(&root.0, "11:memory:/docker/abc123\n").unwrap(), Some(1048576));
+    }
+
+    #[test]
+    fn no_memory_controller() {
+        let root = FakeCgroupRoot::new("none");
+        assert_eq!(memory_limit_in(&root.0, "").unwrap(), None);
+        assert_eq!(memory_limit_in(&root.0, "3:cpu:/\nmalformed\n").unwrap(), None);
+        // No limit file for the cgroup
+        assert_eq!(memory_limit_in(&root.0, "0::/missing\n").unwrap(), None);
+    }
+
+    #[test]
+    fn commit_limit() {
+        assert_eq!(commit_limit_for(1000, 0.0), 1000);
+        assert_eq!(commit_limit_for(1000, 0.25), 750);
+        assert_eq!(commit_limit_for(4096, 0.5), 2048);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid headroom")]
+    fn invalid_headroom() {
+        commit_limit_for(1000, 1.0);
+    }
+
+    #[test]
+    fn user_cap() {
+        assert_eq!(target_commit_limit(Some(1000), 0.5, usize::MAX), 500);
+        assert_eq!(target_commit_limit(Some(1000), 0.5, 200), 200);
+        assert_eq!(target_commit_limit(None, 0.5, 200), 200);
+    }
+}
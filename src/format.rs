@@ -1,12 +1,18 @@
 //! Communicating object formats to the MPS
 use std::os::raw::c_void;
 use std::mem;
+use std::panic::{self, AssertUnwindSafe};
 
 use mps_sys::*;
 use std::marker::PhantomData;
 use crate::arena::Arena;
+use crate::registry::{Registration, HandleKind, FormatMethods};
 use crate::MpsError;
 use arrayvec::ArrayVec;
+use std::cell::Cell;
+use std::ptr::NonNull;
+
+pub use mps_derive::MpsFormat;
 
 /// An object format communicates the object's layout to the MPS.
 ///
@@ -14,62 +20,71 @@ use arrayvec::ArrayVec;
 /// format methods.
 /// This includes methods for tracing, "skipping" (determining size),
 /// relocating ("forwarding")
-pub struct ObjectFormat<'a> {
+///
+/// The `K` parameter records (at compile time) whether the format
+/// supports moving objects. Only a [Moving] format can be given
+/// to a moving pool like AMC.
+pub struct ObjectFormat<'a, K: FormatKind = Moving> {
     raw: mps_fmt_t,
     managed: bool,
-    _arena: PhantomData<&'a Arena>
+    alignment: usize,
+    _arena: PhantomData<&'a Arena>,
+    _kind: PhantomData<K>,
+    // Unregistered before the format is destroyed
+    registration: Registration
 }
 impl<'a> ObjectFormat<'a> {
-    #[inline]
-    pub(crate) fn as_raw(&self) -> mps_fmt_t {
-        self.raw
-    }
     /// Create a new object format for use with managed
     /// (garbage collected) pools
     ///
     /// The format methods are taken from associated methods
-    /// on `<M as RawFormatMethods>`
-    ///
-    /// The minimum alignment of an object is optionally given by `object_align`
+    /// on `<M as RawFormatMethods>` and all of the optional capability traits.
+    /// Use [ObjectFormat::builder] if some of these capabilities aren't supported.
     pub fn managed_with<M>(
         arena: &'a Arena,
     ) -> Result<ObjectFormat<'a>, MpsError>
+        where M: MovingFormatMethods + PaddingFormatMethods + ClassFormatMethods {
+        ObjectFormat::builder::<M>(arena)
+            .padding()
+            .class()
+            .moving()
+            .build()
+    }
+}
+impl<'a> ObjectFormat<'a, NonMoving> {
+    /// Create a new object format that never moves objects,
+    /// using only the required [RawFormatMethods].
+    ///
+    /// This is suitable for non-moving pools like AMS,
+    /// but can't be used with a moving pool.
+    pub fn non_moving_with<M>(
+        arena: &'a Arena,
+    ) -> Result<ObjectFormat<'a, NonMoving>, MpsError>
         where M: RawFormatMethods {
-        let mut args: ArrayVec<_, 8> = ArrayVec::new();
-        unsafe {
-            // TODO: HEADER_SIZE?
-            args.extend(mps_kw_args!(
-                FMT_ALIGN => M::ALIGNMENT,
-                FMT_SCAN => Some(mem::transmute::<
-                    unsafe extern "C" fn(ScanState, *mut M::Obj, *mut M::Obj) -> mps_res_t,
-                    unsafe extern "C" fn(*mut mps_ss_s, *mut c_void, *mut c_void) -> mps_res_t
-                    >(M::scan as unsafe extern "C" fn(_, _, _) -> _)),
-                FMT_SKIP => Some(mem::transmute::<
-                    unsafe extern "C" fn(*mut M::Obj) -> *mut M::Obj,
-                    unsafe extern "C" fn(*mut c_void) -> *mut c_void
-                >(M::skip as unsafe extern "C" fn(_) -> _)),
-                FMT_FWD => Some(mem::transmute::<
-                    unsafe extern "C" fn(*mut M::Obj, *mut M::Obj),
-                    unsafe extern "C" fn(*mut c_void, *mut c_void)
-                    >(M::forward as unsafe extern "C" fn(_, _) -> _)),
-                FMT_ISFWD => Some(mem::transmute::<
-                        unsafe extern "C" fn(*mut M::Obj) -> *mut M::Obj,
-                        unsafe extern "C" fn(*mut c_void) -> *mut c_void
-                    >(M::is_forwarded as unsafe extern "C" fn(_) -> _)),
-                FMT_PAD => Some(mem::transmute::<
-                    unsafe extern "C" fn(*mut M::Obj, usize),
-                    unsafe extern "C" fn(*mut c_void, usize)
-                    >(M::pad as unsafe extern "C" fn(_, _) -> _)),
-                FMT_CLASS => Some(mem::transmute::<
-                        unsafe extern "C" fn(*mut M::Obj) -> *mut c_void,
-                        unsafe extern "C" fn(*mut c_void) -> *mut c_void
-                    >(M::class_ptr as unsafe extern "C" fn(_) -> _))
-            ));
-            let mut fmt = std::ptr::null_mut();
-            handle_mps_res!(mps_fmt_create_k(&mut fmt, arena.as_raw(), args.as_mut_ptr()))?;
-            Ok(ObjectFormat { raw: fmt, managed: true, _arena: PhantomData })
+        ObjectFormat::builder::<M>(arena).build()
+    }
+    /// Begin to build an object format from the methods on `M`
+    ///
+    /// Initially only the required `scan` and `skip` methods are used.
+    /// Optional capabilities can be added with the builder's methods.
+    #[inline]
+    pub fn builder<M: RawFormatMethods>(arena: &'a Arena) -> ObjectFormatBuilder<'a, M, NonMoving> {
+        ObjectFormatBuilder {
+            arena,
+            header_size: None,
+            pad: None,
+            class: None,
+            moving: None,
+            name: None,
+            _marker: PhantomData
         }
     }
+}
+impl<'a, K: FormatKind> ObjectFormat<'a, K> {
+    #[inline]
+    pub(crate) fn as_raw(&self) -> mps_fmt_t {
+        self.raw
+    }
     /// Whether the created object format was 'managed'
     ///
     /// Managed object formats have a set of [RawFormatMethods]
@@ -77,22 +92,205 @@ impl<'a> ObjectFormat<'a> {
     pub fn managed(&self) -> bool {
         self.managed
     }
+    /// The alignment of objects belonging to this format
+    #[inline]
+    pub fn alignment(&self) -> usize {
+        self.alignment
+    }
+    /// Whether this format supports moving objects
+    #[inline]
+    pub fn is_moving(&self) -> bool {
+        K::MOVING
+    }
+    /// Forget whether this format supports moving objects.
+    ///
+    /// A moving format is always usable by a non-moving pool.
+    #[inline]
+    pub fn into_non_moving(self) -> ObjectFormat<'a, NonMoving> {
+        let this = mem::ManuallyDrop::new(self);
+        ObjectFormat {
+            raw: this.raw,
+            managed: this.managed,
+            alignment: this.alignment,
+            _arena: PhantomData,
+            _kind: PhantomData,
+            // NOTE: `this` is never dropped, so this moves the registration
+            registration: unsafe { std::ptr::read(&this.registration) }
+        }
+    }
 }
-unsafe impl Send for ObjectFormat<'_> {}
-unsafe impl Sync for ObjectFormat<'_> {}
-impl Drop for ObjectFormat<'_> {
+unsafe impl<K: FormatKind> Send for ObjectFormat<'_, K> {}
+unsafe impl<K: FormatKind> Sync for ObjectFormat<'_, K> {}
+impl<K: FormatKind> Drop for ObjectFormat<'_, K> {
     fn drop(&mut self) {
         /*
          * NOTE: Pool must die first
          * This is guarenteed on their end
          * since the wrapper owns a reference to us
          */
+        self.registration.unregister();
         unsafe { mps_fmt_destroy(self.raw) }
     }
 }
 
+/// Whether or not an [ObjectFormat] is capable of moving objects
+///
+/// This is sealed, and implemented only by [Moving] and [NonMoving].
+pub trait FormatKind: sealed::Sealed {
+    /// True if the format supports moving objects
+    const MOVING: bool;
+}
+/// Marks an [ObjectFormat] that supports moving objects
+/// (it has [MovingFormatMethods] and [PaddingFormatMethods]).
+pub enum Moving {}
+/// Marks an [ObjectFormat] that never moves objects
+pub enum NonMoving {}
+impl FormatKind for Moving {
+    const MOVING: bool = true;
+}
+impl FormatKind for NonMoving {
+    const MOVING: bool = false;
+}
+mod sealed {
+    pub trait Sealed {}
+    impl Sealed for super::Moving {}
+    impl Sealed for super::NonMoving {}
+}
+
+/// Builds an [ObjectFormat], selecting which optional
+/// format methods are given to the MPS.
+pub struct ObjectFormatBuilder<'a, M: RawFormatMethods, K: FormatKind> {
+    arena: &'a Arena,
+    header_size: Option<usize>,
+    pad: Option<mps_fmt_pad_t>,
+    class: Option<mps_fmt_class_t>,
+    moving: Option<(mps_fmt_fwd_t, mps_fmt_isfwd_t)>,
+    name: Option<String>,
+    _marker: PhantomData<(fn() -> M, K)>
+}
+impl<'a, M: RawFormatMethods, K: FormatKind> ObjectFormatBuilder<'a, M, K> {
+    /// Use an [in-band header](https://www.ravenbrook.com/project/mps/master/manual/html/topic/format.html#in-band-headers)
+    /// of the specified size, which must be a multiple of the alignment.
+    ///
+    /// References to objects (and the pointers given to the format methods) will point just after
+    /// the header. The exception is [PaddingFormatMethods::pad], which is given the base of the block.
+    #[inline]
+    pub fn header_size(mut self, size: usize) -> Self {
+        assert_eq!(size % M::ALIGNMENT, 0, "Header size must be aligned");
+        self.header_size = Some(size);
+        self
+    }
+    /// Give the format a human-readable name,
+    /// which is used to label it in the telemetry stream.
+    #[inline]
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+    /// Use the [PaddingFormatMethods] of this format
+    #[inline]
+    pub fn padding(mut self) -> Self where M: PaddingFormatMethods {
+        self.pad = Some(unsafe { Some(mem::transmute::<
+            unsafe extern "C" fn(*mut M::Obj, usize),
+            unsafe extern "C" fn(*mut c_void, usize)
+        >(M::pad as unsafe extern "C" fn(_, _) -> _)) });
+        self
+    }
+    /// Use the [ClassFormatMethods] of this format
+    #[inline]
+    pub fn class(mut self) -> Self where M: ClassFormatMethods {
+        self.class = Some(unsafe { Some(mem::transmute::<
+            unsafe extern "C" fn(*mut M::Obj) -> *mut c_void,
+            unsafe extern "C" fn(*mut c_void) -> *mut c_void
+        >(M::class_ptr as unsafe extern "C" fn(_) -> _)) });
+        self
+    }
+    /// Use the [MovingFormatMethods] of this format,
+    /// allowing it to be used with moving pools.
+    ///
+    /// Moving pools also need to create padding objects,
+    /// so this implies [ObjectFormatBuilder::padding].
+    #[inline]
+    pub fn moving(self) -> ObjectFormatBuilder<'a, M, Moving>
+        where M: MovingFormatMethods + PaddingFormatMethods {
+        let ObjectFormatBuilder { arena, header_size, pad, class, name, .. } = self.padding();
+        let moving = unsafe {(
+            Some(mem::transmute::<
+                unsafe extern "C" fn(*mut M::Obj, *mut M::Obj),
+                unsafe extern "C" fn(*mut c_void, *mut c_void)
+            >(M::forward as unsafe extern "C" fn(_, _) -> _)),
+            Some(mem::transmute::<
+                unsafe extern "C" fn(*mut M::Obj) -> *mut M::Obj,
+                unsafe extern "C" fn(*mut c_void) -> *mut c_void
+            >(M::is_forwarded as unsafe extern "C" fn(_) -> _))
+        )};
+        ObjectFormatBuilder {
+            arena, header_size, pad, class, name,
+            moving: Some(moving),
+            _marker: PhantomData
+        }
+    }
+    /// Create the object format,
+    /// returning an error on failure
+    pub fn build(self) -> Result<ObjectFormat<'a, K>, MpsError> {
+        let mut args: ArrayVec<_, 9> = ArrayVec::new();
+        unsafe {
+            args.push(mps_kw_arg!(FMT_ALIGN => M::ALIGNMENT));
+            if let Some(header_size) = self.header_size {
+                args.push(mps_kw_arg!(FMT_HEADER_SIZE => header_size));
+            }
+            let scan: mps_fmt_scan_t = Some(mem::transmute::<
+                unsafe extern "C" fn(ScanState, *mut M::Obj, *mut M::Obj) -> mps_res_t,
+                unsafe extern "C" fn(*mut mps_ss_s, *mut c_void, *mut c_void) -> mps_res_t
+            >(M::scan as unsafe extern "C" fn(_, _, _) -> _));
+            args.push(mps_kw_arg!(FMT_SCAN => scan));
+            let skip: mps_fmt_skip_t = Some(mem::transmute::<
+                unsafe extern "C" fn(*mut M::Obj) -> *mut M::Obj,
+                unsafe extern "C" fn(*mut c_void) -> *mut c_void
+            >(M::skip as unsafe extern "C" fn(_) -> _));
+            args.push(mps_kw_arg!(FMT_SKIP => skip));
+            if let Some((forward, is_forwarded)) = self.moving {
+                args.push(mps_kw_arg!(FMT_FWD => forward));
+                args.push(mps_kw_arg!(FMT_ISFWD => is_forwarded));
+            }
+            if let Some(pad) = self.pad {
+                args.push(mps_kw_arg!(FMT_PAD => pad));
+            }
+            if let Some(class) = self.class {
+                args.push(mps_kw_arg!(FMT_CLASS => class));
+            }
+            args.push(mps_args_end());
+            debug_assert_eq!(K::MOVING, self.moving.is_some());
+            let mut fmt = std::ptr::null_mut();
+            handle_mps_res!(mps_fmt_create_k(&mut fmt, self.arena.as_raw(), args.as_mut_ptr()))?;
+            let registration = self.arena.register(HandleKind::Format, None, fmt as *mut c_void);
+            registration.set_format_methods(FormatMethods {
+                #[cfg(feature = "stress")]
+                scan,
+                skip,
+                #[cfg(feature = "stress")]
+                is_forwarded: self.moving.and_then(|(_, is_forwarded)| is_forwarded),
+                class: self.class.flatten()
+            });
+            if let Some(ref name) = self.name {
+                registration.set_name(name);
+            }
+            Ok(ObjectFormat {
+                raw: fmt, managed: true,
+                alignment: M::ALIGNMENT,
+                _arena: PhantomData, _kind: PhantomData,
+                registration
+            })
+        }
+    }
+}
+
 /// MPS object format methods, for use with managed objects
 ///
+/// These are the methods required by every format.
+/// Pools that move objects additionally need [MovingFormatMethods]
+/// and [PaddingFormatMethods], while [ClassFormatMethods] is always optional.
+///
 /// ## Safety
 /// 1. MPS guarantees that format methods have exclusive access
 ///    to objects for the duration of the call. This may involve pausing user threads.
@@ -111,6 +309,9 @@ impl Drop for ObjectFormat<'_> {
 ///    1. Call library code
 ///    2. Perform a non-local exit (panic/exception/longjmp)
 ///    3. Call any MPS functions other than the special fixup/relocation functions
+///
+///    To avoid writing `extern "C"` methods (and guard against panics),
+///    implement [SafeFormatMethods] and use [CatchUnwind] instead.
 /// 6. However, given the above constraints are followed, format methods are free to:
 ///    1. Access memory inside the object/block they've been asked to examine
 ///    2. Access MPS memory that is in pools that doen't protect memory (unmanaged pools)
@@ -120,11 +321,35 @@ pub unsafe trait RawFormatMethods {
     type Obj;
     /// The alignment of objects belonging to this format
     const ALIGNMENT: usize;
-    /// Give an address related to the class of the object,
-    /// or a null pointer if none is available.
+    /// Called when the MPS needs to scan (and relocate) objects in a block of memory
+    /// that belong to this format.
     ///
-    /// Padding and forwarding objects should return null
-    unsafe extern "C" fn class_ptr(obj: *mut Self::Obj) -> *mut c_void;
+    /// Base points to the first formatted object in the block of memory (inclusive),
+    /// while limit is the location just beyond the end of the block (exclusive).
+    ///
+    /// The scan state must be passed to `ScanState::fix_with` before fixing references.
+    ///
+    /// If the object format is capable of creating forwarding objects or padding objects,
+    /// the scan method must be able to scan these objects.
+    /// The scan method must *never fixup forwarding objects*.
+    unsafe extern "C" fn scan(state: ScanState, base: *mut Self::Obj, limit: *mut Self::Obj) -> mps_res_t;
+    /// Return the address of the next object (implicitly computing its size).
+    ///
+    /// If this format has no headers, this is the address just past the end of the object.
+    ///
+    /// If the format does have in-band headers, they should be excluded.
+    ///
+    /// If this format creates forwarding or padding objects,
+    /// this method must be able to handle them.
+    ///
+    /// This method must be infallible.
+    unsafe extern "C" fn skip(addr: *mut Self::Obj) -> *mut Self::Obj;
+}
+/// Format methods needed by pools that move objects (like AMC).
+///
+/// ## Safety
+/// The same restrictions as [RawFormatMethods] apply.
+pub unsafe trait MovingFormatMethods: RawFormatMethods {
     /// The MPS calls the forward method for an object format when
     /// it has relocated an object belonging to that format.
     ///
@@ -140,6 +365,12 @@ pub unsafe trait RawFormatMethods {
     ///
     /// Otherwise return null.
     unsafe extern "C" fn is_forwarded(old: *mut Self::Obj) -> *mut Self::Obj;
+}
+/// Format methods for creating padding objects
+///
+/// ## Safety
+/// The same restrictions as [RawFormatMethods] apply.
+pub unsafe trait PaddingFormatMethods: RawFormatMethods {
     /// Create a padding object, to fill in otherwise unused space.
     ///
     /// This method must create a padding object of the specified size
@@ -150,30 +381,170 @@ pub unsafe trait RawFormatMethods {
     /// The MPS typically uses this to pack objects into fixed sized units
     /// (such as OS pages).
     unsafe extern "C" fn pad(addr: *mut Self::Obj, size: usize);
-    /// Called when the MPS needs to scan (and relocate) objects in a block of memory
-    /// that belong to this format.
-    ///
-    /// Base points to the first formatted object in the block of memory (inclusive),
-    /// while limit is the location just beyond the end of the block (exclusive).
-    ///
-    /// The scan state must be passed to `ScanState::fix_with` before fixing references.
+}
+/// Format methods for determining the class of an object
+///
+/// This is only used for debugging and telemetry.
+///
+/// ## Safety
+/// The same restrictions as [RawFormatMethods] apply.
+pub unsafe trait ClassFormatMethods: RawFormatMethods {
+    /// Give an address related to the class of the object,
+    /// or a null pointer if none is available.
     ///
-    /// If the object format is capable of creating forwarding objects or padding objects,
-    /// the scan method must be able to scan these objects.
-    /// The scan method must *never fixup forwarding objects*.
-    unsafe extern "C" fn scan(state: ScanState, base: *mut Self::Obj, limit: *mut Self::Obj) -> mps_res_t;
-    /// Return the address of the next object (implicitly computing its size).
+    /// Padding and forwarding objects should return null
+    unsafe extern "C" fn class_ptr(obj: *mut Self::Obj) -> *mut c_void;
+}
+/// What [CatchUnwind] does when a format method panics
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OnPanic {
+    /// Print a message (naming the method and format) and abort the process
+    Abort,
+    /// Return `MPS_RES_FAIL` to the MPS
     ///
-    /// If this format has no headers, this is the address just past the end of the object.
+    /// This is only possible for the scan method, since the others can't fail.
+    /// The MPS handles the failure like running out of memory during a collection,
+    /// and may retry the scan, so this is only useful if the panic is transient.
     ///
-    /// If the format does have in-band headers, they should be excluded.
+    /// There are two hazards to be aware of:
+    /// 1. The MPS retries a failed scan in "emergency mode", and must eventually succeed.
+    ///    If the panic happens every time the objects are scanned, the collector livelocks.
+    /// 2. Format methods may run inside the MPS `SIGSEGV` handler (when the client hits a barrier).
+    ///    Panicking and unwinding there isn't async-signal-safe
+    ///    (the panic machinery allocates, takes locks and runs the panic hook),
+    ///    so this can deadlock or corrupt the process.
+    Fail
+}
+
+/// Format methods written as ordinary Rust functions,
+/// which are given to the MPS through the [CatchUnwind] adapter.
+///
+/// Unlike [RawFormatMethods], these methods don't need to be `extern "C"`,
+/// and a panic won't unwind into the MPS (which is undefined behavior).
+/// Instead, the panic is caught and handled according to [OnPanic].
+///
+/// ## Safety
+/// All the restrictions of [RawFormatMethods] apply,
+/// except that panicking is allowed.
+pub unsafe trait SafeFormatMethods {
+    /// The type of object managed by these format methods
+    type Obj;
+    /// The alignment of objects belonging to this format
+    const ALIGNMENT: usize;
+    /// What to do if [SafeFormatMethods::scan] panics
+    const SCAN_PANIC: OnPanic = OnPanic::Abort;
+    /// Scan the objects between `base` (inclusive) and `limit` (exclusive)
     ///
-    /// If this format creates forwarding or padding objects,
-    /// this method must be able to handle them.
+    /// See [RawFormatMethods::scan]. Errors from fixing must be returned immediately.
+    unsafe fn scan(state: &mut ScanState, base: *mut Self::Obj, limit: *mut Self::Obj) -> Result<(), mps_res_t>;
+    /// Return the address of the next object
     ///
-    /// This method must be infallible.
-    unsafe extern "C" fn skip(addr: *mut Self::Obj) -> *mut Self::Obj;
+    /// See [RawFormatMethods::skip]
+    unsafe fn skip(addr: *mut Self::Obj) -> *mut Self::Obj;
+}
+/// The [MovingFormatMethods] of a [SafeFormatMethods] format
+///
+/// ## Safety
+/// The same restrictions as [SafeFormatMethods] apply.
+pub unsafe trait SafeMovingFormatMethods: SafeFormatMethods {
+    /// See [MovingFormatMethods::forward]
+    unsafe fn forward(old: *mut Self::Obj, new: *mut Self::Obj);
+    /// See [MovingFormatMethods::is_forwarded]
+    unsafe fn is_forwarded(old: *mut Self::Obj) -> *mut Self::Obj;
+}
+/// The [PaddingFormatMethods] of a [SafeFormatMethods] format
+///
+/// ## Safety
+/// The same restrictions as [SafeFormatMethods] apply.
+pub unsafe trait SafePaddingFormatMethods: SafeFormatMethods {
+    /// See [PaddingFormatMethods::pad]
+    unsafe fn pad(addr: *mut Self::Obj, size: usize);
+}
+/// The [ClassFormatMethods] of a [SafeFormatMethods] format
+///
+/// ## Safety
+/// The same restrictions as [SafeFormatMethods] apply.
+pub unsafe trait SafeClassFormatMethods: SafeFormatMethods {
+    /// See [ClassFormatMethods::class_ptr]
+    unsafe fn class_ptr(obj: *mut Self::Obj) -> *mut c_void;
+}
+
+/// Adapts [SafeFormatMethods] into [RawFormatMethods] (and the capability traits),
+/// catching any panics before they reach the MPS.
+///
+/// Use it wherever raw format methods are expected,
+/// like `ObjectFormat::managed_with::<CatchUnwind<MyFormat>>(&arena)`.
+///
+/// Panic hooks still run before the panic is caught.
+/// The hook installed by [install_panic_hook](crate::postmortem::install_panic_hook)
+/// aborts the process, so [OnPanic::Fail] has no effect while it's installed.
+pub struct CatchUnwind<M> {
+    _marker: PhantomData<fn() -> M>
+}
+impl<M> CatchUnwind<M> {
+    /// Call a format method, aborting if it panics
+    #[inline]
+    fn call<R>(method: &str, func: impl FnOnce() -> R) -> R {
+        match panic::catch_unwind(AssertUnwindSafe(func)) {
+            Ok(res) => res,
+            Err(_) => Self::abort(method)
+        }
+    }
+    #[cold]
+    fn abort(method: &str) -> ! {
+        eprintln!(
+            "The `{}` format method of {} panicked, aborting",
+            method, std::any::type_name::<M>()
+        );
+        std::process::abort()
+    }
 }
+unsafe impl<M: SafeFormatMethods> RawFormatMethods for CatchUnwind<M> {
+    type Obj = M::Obj;
+    const ALIGNMENT: usize = M::ALIGNMENT;
+    unsafe extern "C" fn scan(mut state: ScanState, base: *mut M::Obj, limit: *mut M::Obj) -> mps_res_t {
+        match panic::catch_unwind(AssertUnwindSafe(|| M::scan(&mut state, base, limit))) {
+            Ok(Ok(())) => mps_sys::MPS_RES_OK as mps_res_t,
+            Ok(Err(code)) => code,
+            Err(_) => match M::SCAN_PANIC {
+                OnPanic::Fail => mps_sys::MPS_RES_FAIL as mps_res_t,
+                OnPanic::Abort => Self::abort("scan")
+            }
+        }
+    }
+    unsafe extern "C" fn skip(addr: *mut M::Obj) -> *mut M::Obj {
+        Self::call("skip", || M::skip(addr))
+    }
+}
+unsafe impl<M: SafeMovingFormatMethods> MovingFormatMethods for CatchUnwind<M> {
+    unsafe extern "C" fn forward(old: *mut M::Obj, new: *mut M::Obj) {
+        Self::call("forward", || M::forward(old, new))
+    }
+    unsafe extern "C" fn is_forwarded(old: *mut M::Obj) -> *mut M::Obj {
+        Self::call("is_forwarded", || M::is_forwarded(old))
+    }
+}
+unsafe impl<M: SafePaddingFormatMethods> PaddingFormatMethods for CatchUnwind<M> {
+    unsafe extern "C" fn pad(addr: *mut M::Obj, size: usize) {
+        Self::call("pad", || M::pad(addr, size))
+    }
+}
+unsafe impl<M: SafeClassFormatMethods> ClassFormatMethods for CatchUnwind<M> {
+    unsafe extern "C" fn class_ptr(obj: *mut M::Obj) -> *mut c_void {
+        Self::call("class_ptr", || M::class_ptr(obj))
+    }
+}
+
+/// Find the elements of a trailing array field (see `#[mps(trailing = len)]`)
+///
+/// The reference to the `[T; 0]` field only covers zero bytes,
+/// so the pointer to the elements is derived from the object pointer instead.
+#[doc(hidden)] // Used by mps-derive
+#[inline(always)]
+pub unsafe fn __trailing_elems<O, T>(obj: *mut O, field: *const T) -> *mut T {
+    (obj as *mut u8).add(field as usize - obj as usize) as *mut T
+}
+
 /// The initial scan state passed to an object format
 #[repr(transparent)]
 pub struct ScanState {
@@ -181,6 +552,11 @@ pub struct ScanState {
     raw: mps_ss_t
 }
 impl ScanState {
+    #[cfg(feature = "format-check")]
+    #[inline]
+    pub(crate) fn from_raw(raw: mps_ss_t) -> ScanState {
+        ScanState { raw }
+    }
     /// Begin to setup the fix state to scan a set of objects.
     ///
     /// Within this closure, the `ScanFixState` is in a special state
@@ -250,6 +626,10 @@ impl ScanFixState {
     /// This corresponds to the C macro [`MPS_FIX2`](https://www.ravenbrook.com/project/mps/master/manual/html/topic/scanning.html#c.MPS_FIX2)
     #[inline(always)]
     pub unsafe fn force_fix<T>(&mut self, addr: &mut *mut T) -> Result<(), mps_res_t> {
+        #[cfg(feature = "format-check")]
+        if crate::format_check::record_fix(self.state.raw, addr as *mut *mut T as *mut *mut c_void) {
+            return Ok(());
+        }
         let res = ::mps_sys::_mps_fix2(self.state.raw, addr as *mut *mut T as *mut *mut c_void);
         if res == 0 {
             Ok(())
@@ -288,4 +668,156 @@ impl ScanFixState {
         self.ufs |= unsafe { (*self.state.raw)._ufs };
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// A value holding references that can be fixed while scanning
+///
+/// This is used by `#[derive(MpsFormat)]` for fields marked `#[mps(ref)]`.
+///
+/// ## Safety
+/// Every reference held by the value must be passed to the [ScanFixState],
+/// and the (possibly relocated) result must be stored back.
+pub unsafe trait Fixable {
+    /// Fix all the references in this value
+    ///
+    /// Errors must be returned immediately.
+    unsafe fn fix(&mut self, state: &mut ScanFixState) -> Result<(), mps_res_t>;
+}
+unsafe impl<T> Fixable for *mut T {
+    #[inline]
+    unsafe fn fix(&mut self, state: &mut ScanFixState) -> Result<(), mps_res_t> {
+        state.fix(self)
+    }
+}
+unsafe impl<T> Fixable for *const T {
+    #[inline]
+    unsafe fn fix(&mut self, state: &mut ScanFixState) -> Result<(), mps_res_t> {
+        let mut ptr = *self as *mut T;
+        state.fix(&mut ptr)?;
+        *self = ptr;
+        Ok(())
+    }
+}
+unsafe impl<T> Fixable for NonNull<T> {
+    #[inline]
+    unsafe fn fix(&mut self, state: &mut ScanFixState) -> Result<(), mps_res_t> {
+        let mut ptr = self.as_ptr();
+        state.fix(&mut ptr)?;
+        *self = NonNull::new_unchecked(ptr);
+        Ok(())
+    }
+}
+/// NOTE: This assumes the reference points into the MPS heap
+unsafe impl<T> Fixable for &T {
+    #[inline]
+    unsafe fn fix(&mut self, state: &mut ScanFixState) -> Result<(), mps_res_t> {
+        let mut ptr = *self as *const T as *mut T;
+        state.fix(&mut ptr)?;
+        *self = &*ptr;
+        Ok(())
+    }
+}
+unsafe impl<T: Fixable> Fixable for Option<T> {
+    #[inline]
+    unsafe fn fix(&mut self, state: &mut ScanFixState) -> Result<(), mps_res_t> {
+        match *self {
+            Some(ref mut val) => val.fix(state),
+            None => Ok(())
+        }
+    }
+}
+unsafe impl<T: Fixable> Fixable for Cell<T> {
+    #[inline]
+    unsafe fn fix(&mut self, state: &mut ScanFixState) -> Result<(), mps_res_t> {
+        self.get_mut().fix(state)
+    }
+}
+unsafe impl<A: Fixable, B: Fixable> Fixable for (A, B) {
+    #[inline]
+    unsafe fn fix(&mut self, state: &mut ScanFixState) -> Result<(), mps_res_t> {
+        self.0.fix(state)?;
+        self.1.fix(state)
+    }
+}
+unsafe impl<T: Fixable, const N: usize> Fixable for [T; N] {
+    #[inline]
+    unsafe fn fix(&mut self, state: &mut ScanFixState) -> Result<(), mps_res_t> {
+        for val in self.iter_mut() {
+            val.fix(state)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::process::Command;
+
+    /// A format whose methods always panic, and whose scan method fails if `FAIL` is set
+    struct Panicking<const FAIL: bool>;
+    unsafe impl<const FAIL: bool> SafeFormatMethods for Panicking<FAIL> {
+        type Obj = usize;
+        const ALIGNMENT: usize = std::mem::align_of::<usize>();
+        const SCAN_PANIC: OnPanic = if FAIL { OnPanic::Fail } else { OnPanic::Abort };
+        unsafe fn scan(_state: &mut ScanState, _base: *mut usize, _limit: *mut usize) -> Result<(), mps_res_t> {
+            panic!("scan panicked")
+        }
+        unsafe fn skip(_addr: *mut usize) -> *mut usize {
+            panic!("skip panicked")
+        }
+    }
+
+    /// Scan with a null scan state, which the panicking method never uses
+    unsafe fn scan<M: RawFormatMethods<Obj = usize>>() -> mps_res_t {
+        let mut object = 0usize;
+        let base: *mut usize = &mut object;
+        M::scan(ScanState { raw: std::ptr::null_mut() }, base, base.add(1))
+    }
+
+    #[test]
+    fn scan_panic_fails() {
+        let res = unsafe { scan::<CatchUnwind<Panicking<true>>>() };
+        assert_eq!(res, mps_sys::MPS_RES_FAIL as mps_res_t);
+    }
+
+    /// Set in the child process, to the method that should panic
+    const CHILD_VAR: &str = "MPS_TEST_CATCH_UNWIND_CHILD";
+
+    /// Call the method in a child process, and check that it aborts with a message
+    fn check_abort(test: &str, method: &str) {
+        match env::var(CHILD_VAR).as_deref() {
+            Ok("scan") => unsafe {
+                scan::<CatchUnwind<Panicking<false>>>();
+            },
+            Ok("skip") => unsafe {
+                let mut object = 0usize;
+                CatchUnwind::<Panicking<false>>::skip(&mut object);
+            },
+            _ => {
+                let output = Command::new(env::current_exe().unwrap())
+                    .args(["--exact", test, "--nocapture", "--test-threads=1"])
+                    .env(CHILD_VAR, method)
+                    .output()
+                    .unwrap();
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                assert!(!output.status.success(), "{}", stderr);
+                assert!(stderr.contains(&format!("The `{}` format method of ", method)), "{}", stderr);
+                assert!(stderr.contains("panicked, aborting"), "{}", stderr);
+                return;
+            }
+        }
+        unreachable!("{} didn't abort", method);
+    }
+
+    #[test]
+    fn scan_panic_aborts() {
+        check_abort("format::test::scan_panic_aborts", "scan");
+    }
+
+    #[test]
+    fn skip_panic_aborts() {
+        check_abort("format::test::skip_panic_aborts", "skip");
+    }
+}
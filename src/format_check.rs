@@ -0,0 +1,416 @@
+//! Checking that hand-written format methods follow the rules in the [RawFormatMethods] docs
+//!
+//! Bugs in format methods usually crash the MPS long after the fact,
+//! so it's worth testing them directly.
+//! A [FormatCheck] runs the methods on sample objects in a plain buffer
+//! (outside of the MPS), and reports any violations it finds.
+//!
+//! Scanning uses a fake scan state that records every reference passed to
+//! [ScanFixState::fix](crate::format::ScanFixState::fix) instead of fixing it.
+//! This is why the checker requires the `format-check` feature.
+//!
+//! Formats with in-band headers aren't supported.
+//!
+//! ```ignore
+//! let mut check = FormatCheck::<MyFormat>::new();
+//! unsafe {
+//!     check.sample(size_of::<Pair>(), |obj| {
+//!         ptr::write(obj, MyFormat::Pair(left, right));
+//!         vec![left as *mut c_void, right as *mut c_void]
+//!     });
+//! }
+//! check.padding().moving().class().assert_conforms();
+//! ```
+use std::alloc::{self, Layout};
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::fmt::{self, Display, Formatter};
+use std::ptr;
+
+use mps_sys::{mps_res_t, mps_ss_s, mps_ss_t};
+use thiserror::Error;
+
+use crate::format::{
+    ClassFormatMethods, MovingFormatMethods, PaddingFormatMethods,
+    RawFormatMethods, ScanState
+};
+
+/// A sample object, initialized by a closure
+struct Sample<M: RawFormatMethods> {
+    size: usize,
+    init: Box<dyn Fn(*mut M::Obj) -> Vec<*mut c_void>>
+}
+
+type ForwardMethod<M> = unsafe extern "C" fn(*mut <M as RawFormatMethods>::Obj, *mut <M as RawFormatMethods>::Obj);
+type IsForwardedMethod<M> = unsafe extern "C" fn(*mut <M as RawFormatMethods>::Obj) -> *mut <M as RawFormatMethods>::Obj;
+
+/// Checks the format methods of `M` against a set of sample objects
+///
+/// The basic checks (on [RawFormatMethods]) always run.
+/// The optional capabilities are checked after enabling them
+/// with [FormatCheck::padding], [FormatCheck::moving] and [FormatCheck::class].
+pub struct FormatCheck<M: RawFormatMethods> {
+    samples: Vec<Sample<M>>,
+    pad: Option<unsafe extern "C" fn(*mut M::Obj, usize)>,
+    moving: Option<(ForwardMethod<M>, IsForwardedMethod<M>)>,
+    class: Option<unsafe extern "C" fn(*mut M::Obj) -> *mut c_void>,
+    max_padding_size: Option<usize>
+}
+impl<M: RawFormatMethods> FormatCheck<M> {
+    /// Create a checker without any samples
+    #[inline]
+    pub fn new() -> Self {
+        FormatCheck {
+            samples: Vec::new(),
+            pad: None,
+            moving: None,
+            class: None,
+            max_padding_size: None
+        }
+    }
+    /// Add a sample object of the specified size (in bytes)
+    ///
+    /// The `init` closure is given zeroed memory (aligned to the format's alignment),
+    /// and must write the object there, returning the references the object holds.
+    /// It's called again for each check, so it must produce the same object every time.
+    ///
+    /// ## Safety
+    /// The closure must not write more than `size` bytes,
+    /// and the format methods must be safe to call on the object.
+    #[inline]
+    pub unsafe fn sample<F>(&mut self, size: usize, init: F) -> &mut Self
+        where F: Fn(*mut M::Obj) -> Vec<*mut c_void> + 'static {
+        assert_eq!(size % M::ALIGNMENT, 0, "Sample size must be aligned");
+        assert_ne!(size, 0, "Samples can't be empty");
+        self.samples.push(Sample { size, init: Box::new(init) });
+        self
+    }
+    /// Also check the [PaddingFormatMethods]
+    ///
+    /// Padding objects are created with every aligned size,
+    /// up to the largest sample (or [FormatCheck::max_padding_size]).
+    #[inline]
+    pub fn padding(&mut self) -> &mut Self where M: PaddingFormatMethods {
+        self.pad = Some(M::pad);
+        self
+    }
+    /// Also check the [MovingFormatMethods]
+    #[inline]
+    pub fn moving(&mut self) -> &mut Self where M: MovingFormatMethods {
+        self.moving = Some((M::forward, M::is_forwarded));
+        self
+    }
+    /// Also check the [ClassFormatMethods]
+    #[inline]
+    pub fn class(&mut self) -> &mut Self where M: ClassFormatMethods {
+        self.class = Some(M::class_ptr);
+        self
+    }
+    /// The largest padding object to check
+    #[inline]
+    pub fn max_padding_size(&mut self, size: usize) -> &mut Self {
+        self.max_padding_size = Some(size);
+        self
+    }
+    /// Run all the checks, returning every violation that was found
+    pub fn errors(&self) -> Vec<FormatCheckError> {
+        let mut errors = Vec::new();
+        unsafe {
+            for (index, sample) in self.samples.iter().enumerate() {
+                self.check_sample(index, sample, &mut errors);
+            }
+            self.check_all_samples(&mut errors);
+            if let Some(pad) = self.pad {
+                let max_size = self.max_padding_size.unwrap_or_else(|| {
+                    self.samples.iter().map(|sample| sample.size).max().unwrap_or(0)
+                }).max(M::ALIGNMENT);
+                for size in (M::ALIGNMENT..=max_size).step_by(M::ALIGNMENT) {
+                    self.check_padding(pad, size, &mut errors);
+                }
+            }
+        }
+        errors
+    }
+    /// Run all the checks, panicking if there were any violations
+    pub fn assert_conforms(&self) {
+        let errors = self.errors();
+        if !errors.is_empty() {
+            let errors = errors.iter().map(|error| format!("  {}", error))
+                .collect::<Vec<_>>().join("\n");
+            panic!("Format methods of {} don't conform:\n{}", std::any::type_name::<M>(), errors);
+        }
+    }
+    unsafe fn check_sample(&self, index: usize, sample: &Sample<M>, errors: &mut Vec<FormatCheckError>) {
+        let object = Object::of::<M>(ObjectKind::Sample(index));
+        let buffer = Buffer::new::<M>(sample.size);
+        let base = buffer.ptr as *mut M::Obj;
+        let expected = (sample.init)(base);
+        if check_skip::<M>(object, base, sample.size, errors) {
+            check_scan::<M>(object, base, sample.size, expected, errors);
+        }
+        if let Some((forward, is_forwarded)) = self.moving {
+            if !is_forwarded(base).is_null() {
+                errors.push(FormatCheckError::FalselyForwarded { object });
+            }
+            let copy = Buffer::new::<M>(sample.size);
+            ptr::copy_nonoverlapping(buffer.ptr, copy.ptr, sample.size);
+            forward(base, copy.ptr as *mut M::Obj);
+            let object = Object::of::<M>(ObjectKind::Forwarded(index));
+            let skipped = check_skip::<M>(object, base, sample.size, errors);
+            let actual = is_forwarded(base);
+            if actual != copy.ptr as *mut M::Obj {
+                errors.push(FormatCheckError::NotForwarded {
+                    object, expected: copy.ptr as *mut c_void, actual: actual as *mut c_void
+                });
+            }
+            if skipped {
+                check_scan::<M>(object, base, sample.size, Vec::new(), errors);
+            }
+            if let Some(class) = self.class {
+                check_null_class(object, class(base), errors);
+            }
+        }
+    }
+    /// Scan all the samples at once, to check that scan handles a block of several objects
+    unsafe fn check_all_samples(&self, errors: &mut Vec<FormatCheckError>) {
+        if self.samples.len() < 2 {
+            return;
+        }
+        let total_size = self.samples.iter().map(|sample| sample.size).sum();
+        let buffer = Buffer::new::<M>(total_size);
+        let mut expected = Vec::new();
+        let mut offset = 0;
+        for sample in &self.samples {
+            expected.extend((sample.init)(buffer.ptr.add(offset) as *mut M::Obj));
+            offset += sample.size;
+        }
+        if errors.iter().any(|error| matches!(error, FormatCheckError::WrongSize { .. })) {
+            return;
+        }
+        let object = Object::of::<M>(ObjectKind::AllSamples);
+        check_scan::<M>(object, buffer.ptr as *mut M::Obj, total_size, expected, errors);
+    }
+    unsafe fn check_padding(
+        &self, pad: unsafe extern "C" fn(*mut M::Obj, usize),
+        size: usize, errors: &mut Vec<FormatCheckError>
+    ) {
+        let object = Object::of::<M>(ObjectKind::Padding(size));
+        let buffer = Buffer::new::<M>(size);
+        let base = buffer.ptr as *mut M::Obj;
+        pad(base, size);
+        if check_skip::<M>(object, base, size, errors) {
+            check_scan::<M>(object, base, size, Vec::new(), errors);
+        }
+        if let Some(class) = self.class {
+            check_null_class(object, class(base), errors);
+        }
+        if let Some((_, is_forwarded)) = self.moving {
+            if !is_forwarded(base).is_null() {
+                errors.push(FormatCheckError::FalselyForwarded { object });
+            }
+        }
+    }
+}
+impl<M: RawFormatMethods> Default for FormatCheck<M> {
+    #[inline]
+    fn default() -> Self {
+        FormatCheck::new()
+    }
+}
+
+/// Check the size given by skip, returning whether it was correct
+///
+/// Scanning an object with the wrong size could overrun the buffer (or never finish),
+/// so the object isn't scanned unless this succeeds.
+unsafe fn check_skip<M: RawFormatMethods>(
+    object: Object, base: *mut M::Obj, expected: usize,
+    errors: &mut Vec<FormatCheckError>
+) -> bool {
+    let actual = (M::skip(base) as usize).wrapping_sub(base as usize);
+    if actual != expected {
+        errors.push(FormatCheckError::WrongSize { object, expected, actual });
+        false
+    } else {
+        true
+    }
+}
+unsafe fn check_scan<M: RawFormatMethods>(
+    object: Object, base: *mut M::Obj, size: usize,
+    mut expected: Vec<*mut c_void>, errors: &mut Vec<FormatCheckError>
+) {
+    let limit = (base as *mut u8).add(size) as *mut M::Obj;
+    let (res, mut visited) = record_scan(|ss| M::scan(ScanState::from_raw(ss), base, limit));
+    if res != mps_sys::MPS_RES_OK as mps_res_t {
+        errors.push(FormatCheckError::ScanFailed { object, code: res });
+        return;
+    }
+    expected.sort_unstable();
+    visited.sort_unstable();
+    if expected != visited {
+        errors.push(FormatCheckError::WrongReferences { object, expected, visited });
+    }
+}
+fn check_null_class(object: Object, class: *mut c_void, errors: &mut Vec<FormatCheckError>) {
+    if !class.is_null() {
+        errors.push(FormatCheckError::NonNullClass { object, class });
+    }
+}
+
+/// An object that failed a check
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Object {
+    /// The kind of object
+    pub kind: ObjectKind,
+    /// The name of the format
+    pub format: &'static str
+}
+impl Object {
+    fn of<M>(kind: ObjectKind) -> Object {
+        Object { kind, format: std::any::type_name::<M>() }
+    }
+}
+impl Display for Object {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.kind {
+            ObjectKind::Sample(index) => write!(f, "sample #{}", index),
+            ObjectKind::Forwarded(index) => write!(f, "forwarded sample #{}", index),
+            ObjectKind::Padding(size) => write!(f, "padding of {} bytes", size),
+            ObjectKind::AllSamples => f.write_str("block of all samples")
+        }
+    }
+}
+/// The kind of an [Object] that failed a check
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ObjectKind {
+    /// The sample with the specified index (in order of [FormatCheck::sample])
+    Sample(usize),
+    /// The sample with the specified index, after it was replaced by a forwarding object
+    Forwarded(usize),
+    /// A padding object of the specified size
+    Padding(usize),
+    /// A block containing all the samples, one after another
+    AllSamples
+}
+
+/// A violation of the format method rules, found by a [FormatCheck]
+#[derive(Error, Debug)]
+pub enum FormatCheckError {
+    /// `skip` gave the wrong size
+    #[error("skip gave the wrong size for {object} (expected {expected} bytes, got {actual})")]
+    WrongSize {
+        /// The object that was skipped
+        object: Object,
+        /// The expected size (in bytes)
+        expected: usize,
+        /// The size implied by `skip`
+        actual: usize
+    },
+    /// `scan` returned an error (without any failing fixes)
+    #[error("scan failed for {object} with code {code}")]
+    ScanFailed {
+        /// The object that was scanned
+        object: Object,
+        /// The error code returned by `scan`
+        code: mps_res_t
+    },
+    /// `scan` didn't visit exactly the expected references
+    #[error("scan visited the wrong references for {object} (expected {expected:?}, visited {visited:?})")]
+    WrongReferences {
+        /// The object that was scanned
+        object: Object,
+        /// The references the object holds (sorted)
+        expected: Vec<*mut c_void>,
+        /// The references that were fixed (sorted)
+        visited: Vec<*mut c_void>
+    },
+    /// `is_forwarded` didn't recognize a forwarding object
+    #[error("is_forwarded didn't recognize {object} (expected {expected:?}, got {actual:?})")]
+    NotForwarded {
+        /// The forwarding object
+        object: Object,
+        /// The address the object was forwarded to
+        expected: *mut c_void,
+        /// The address returned by `is_forwarded`
+        actual: *mut c_void
+    },
+    /// `is_forwarded` returned non-null for an object that isn't a forwarding object
+    #[error("is_forwarded claims {object} is a forwarding object")]
+    FalselyForwarded {
+        /// The object that isn't a forwarding object
+        object: Object
+    },
+    /// `class_ptr` returned non-null for a padding or forwarding object
+    #[error("class_ptr gave {class:?} for {object} (expected null)")]
+    NonNullClass {
+        /// The padding or forwarding object
+        object: Object,
+        /// The class returned by `class_ptr`
+        class: *mut c_void
+    }
+}
+
+/// A zeroed buffer, aligned to the format's alignment
+struct Buffer {
+    ptr: *mut u8,
+    layout: Layout
+}
+impl Buffer {
+    fn new<M: RawFormatMethods>(size: usize) -> Buffer {
+        let layout = Layout::from_size_align(size, M::ALIGNMENT).unwrap();
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        Buffer { ptr, layout }
+    }
+}
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+/// A fake scan state, which records the references that are fixed
+#[repr(C)]
+struct Recording {
+    // NOTE: Must be first, so the scan state points to the recording
+    ss: mps_ss_s,
+    visited: Vec<*mut c_void>
+}
+thread_local! {
+    /// The recording scan state of the scan in progress (if any)
+    static RECORDING: Cell<*mut Recording> = const { Cell::new(ptr::null_mut()) };
+}
+/// Restores the previous recording when dropped, even if the scan panics
+struct RestoreRecording(*mut Recording);
+impl Drop for RestoreRecording {
+    fn drop(&mut self) {
+        RECORDING.with(|current| current.set(self.0));
+    }
+}
+/// Call a scan function with a recording scan state,
+/// returning its result and the references that were fixed.
+pub(crate) unsafe fn record_scan(func: impl FnOnce(mps_ss_t) -> mps_res_t) -> (mps_res_t, Vec<*mut c_void>) {
+    let mut ss: mps_ss_s = std::mem::zeroed();
+    ss._zs = 0;
+    ss._w = !0; // Every reference is interesting
+    ss._ufs = 0;
+    let mut recording = Box::new(Recording { ss, visited: Vec::new() });
+    let raw = &mut *recording as *mut Recording;
+    let restore = RestoreRecording(RECORDING.with(|current| current.replace(raw)));
+    let res = func(raw as mps_ss_t);
+    drop(restore);
+    (res, recording.visited)
+}
+/// Record a fixed reference if the scan state is a recording,
+/// returning false if it's a real scan state.
+#[inline]
+pub(crate) unsafe fn record_fix(ss: mps_ss_t, reference: *mut *mut c_void) -> bool {
+    let recording = RECORDING.with(|current| current.get());
+    if !recording.is_null() && ss == recording as mps_ss_t {
+        (*recording).visited.push(*reference);
+        true
+    } else {
+        false
+    }
+}
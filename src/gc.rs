@@ -0,0 +1,267 @@
+//! Typed garbage collected references, built on top of an [AllocationPoint]
+//!
+//! A [Gc] can only be created by a successful commit on a [GcContext],
+//! and it borrows from the context.
+//! Since the context owns the thread registration (and its stack roots),
+//! a reference can never outlive the registration that keeps it alive.
+//! The context in turn borrows the pool it allocates from.
+//!
+//! The MPS can't see references stored outside of its pools and roots (like in a `Vec` or a `Box`),
+//! so every object a context allocates is *pinned*: it's recorded in an ambiguous root,
+//! which keeps it from being collected or moved until [GcContext::unpin_all].
+//! That takes the context mutably, so it can only be called once every [Gc] is gone,
+//! and a [Gc] is safe to store anywhere.
+//!
+//! The `'gc` lifetime of a [Gc] is invariant,
+//! so a reference can't be coerced to a different lifetime:
+//!
+//! ```compile_fail
+//! # use mps::gc::Gc;
+//! fn shorten<'a, 'b: 'a>(gc: Gc<'b, u64>) -> Gc<'a, u64> {
+//!     gc
+//! }
+//! ```
+use std::cell::{Cell, RefCell};
+use std::ffi::c_void;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::ptr::NonNull;
+use std::fmt::{self, Debug, Formatter};
+
+use mps_sys::mps_res_t;
+
+use crate::alloc::AllocationPoint;
+use crate::arena::{Arena, MpsThread, MpsRoot};
+use crate::format::{Fixable, ScanFixState};
+use crate::pools::AutomaticPool;
+use crate::MpsError;
+
+/// Owns everything a thread needs to safely allocate objects of type `T`
+///
+/// This includes the thread's registration, the root for its stack,
+/// its [AllocationPoint], and the roots that pin the objects it allocates.
+///
+/// The `'pool` lifetime borrows the pool the allocation point allocates from,
+/// so the context can't outlive it:
+///
+/// ```compile_fail
+/// # use std::ptr;
+/// # use mps::arena::VirtualMemoryArenaClass;
+/// # use mps::format::{ObjectFormat, RawFormatMethods, ScanState};
+/// # use mps::gc::GcContext;
+/// # use mps::pools::mark_sweep::AutoMarkSweep;
+/// # struct Format;
+/// # unsafe impl RawFormatMethods for Format {
+/// #     type Obj = u64;
+/// #     const ALIGNMENT: usize = 8;
+/// #     unsafe extern "C" fn scan(_: ScanState, _: *mut u64, _: *mut u64) -> mps_sys::mps_res_t { 0 }
+/// #     unsafe extern "C" fn skip(obj: *mut u64) -> *mut u64 { obj.add(1) }
+/// # }
+/// let arena = VirtualMemoryArenaClass::get().builder().build().unwrap();
+/// let context = {
+///     let format = ObjectFormat::non_moving_with::<Format>(&arena).unwrap();
+///     let pool = AutoMarkSweep::builder(&arena).build(format).unwrap();
+///     unsafe { GcContext::<u64>::register(&pool, ptr::null_mut()).unwrap() }
+/// };
+/// ```
+pub struct GcContext<'pool, T> {
+    // NOTE: Field order is drop order. The thread must be deregistered last.
+    ap: AllocationPoint<'pool>,
+    pins: RefCell<Vec<PinTable>>,
+    root: MpsRoot,
+    thread: MpsThread<'pool>,
+    marker: PhantomData<fn() -> T>
+}
+impl<'pool, T> GcContext<'pool, T> {
+    /// Register the current thread with the pool's arena,
+    /// registering its stack as an ambiguous root and creating an allocation point.
+    ///
+    /// The `cold_addr` is the top stack address to start scanning,
+    /// just like [MpsThread::register_roots].
+    ///
+    /// ## Safety
+    /// - Undefined behavior if `cold_addr` doesn't point to the the top of the stack.
+    /// - The pool's object format must be able to handle objects of type `T`,
+    ///   and the pool must allow ambiguous references.
+    pub unsafe fn register<'arena: 'pool, P: AutomaticPool<'arena>>(
+        pool: &'pool P, cold_addr: *mut c_void
+    ) -> Result<Self, MpsError> {
+        let thread = pool.arena().register_thread()?;
+        let root = thread.register_roots(cold_addr)?;
+        let ap = pool.create_allocation_point()?;
+        Ok(GcContext::from_parts(thread, root, ap))
+    }
+    /// Create a context from an existing thread registration,
+    /// root and allocation point.
+    ///
+    /// ## Safety
+    /// - The root must include the current thread's stack.
+    /// - The allocation point's object format must be able to handle objects of type `T`,
+    ///   and its pool must allow ambiguous references.
+    #[inline]
+    pub unsafe fn from_parts(thread: MpsThread<'pool>, root: MpsRoot, ap: AllocationPoint<'pool>) -> Self {
+        GcContext { ap, pins: RefCell::new(Vec::new()), root, thread, marker: PhantomData }
+    }
+    /// The arena this context allocates in
+    #[inline]
+    pub fn arena(&self) -> &'pool Arena {
+        self.thread.arena()
+    }
+    /// The underlying allocation point
+    #[inline]
+    pub fn allocation_point(&self) -> &AllocationPoint<'pool> {
+        &self.ap
+    }
+    /// The registration for the current thread
+    #[inline]
+    pub fn thread(&self) -> &MpsThread<'pool> {
+        &self.thread
+    }
+    /// The root for the current thread's stack
+    #[inline]
+    pub fn root(&self) -> &MpsRoot {
+        &self.root
+    }
+    /// Allocate a new object, using the closure to create its value.
+    ///
+    /// The closure may be called more than once,
+    /// if the allocation point's commit fails (see [AllocationPoint::commit]).
+    ///
+    /// The object is pinned until [GcContext::unpin_all].
+    #[inline]
+    pub fn alloc_with<F: FnMut() -> T>(&self, mut func: F) -> Result<Gc<'_, T>, MpsError> {
+        // NOTE: The format handles `T` (checked when the context was created)
+        let ptr = unsafe { self.ap.alloc_with(|ptr: *mut T| ptr.write(func()))? };
+        // Until it's pinned, the object is only kept alive by the stack
+        self.pin(ptr as *mut c_void)?;
+        Ok(unsafe { Gc::from_raw(NonNull::new_unchecked(ptr)) })
+    }
+    /// Allocate a new object, cloning the specified value
+    #[inline]
+    pub fn alloc(&self, value: T) -> Result<Gc<'_, T>, MpsError> where T: Clone {
+        self.alloc_with(|| value.clone())
+    }
+    /// The number of objects that are currently pinned
+    #[inline]
+    pub fn pinned(&self) -> usize {
+        self.pins.borrow().iter().map(|table| table.len.get()).sum()
+    }
+    /// Unpin every object this context has allocated,
+    /// so the MPS can collect (or move) them.
+    ///
+    /// Objects that are still referenced by other objects (or by the stack) stay alive.
+    ///
+    /// No [Gc] can be used afterwards, since they all borrow the context:
+    ///
+    /// ```compile_fail
+    /// # use mps::gc::GcContext;
+    /// fn use_after_unpin(context: &mut GcContext<u64>) {
+    ///     let gc = context.alloc(1).unwrap();
+    ///     context.unpin_all();
+    ///     assert_eq!(*gc, 1);
+    /// }
+    /// ```
+    #[inline]
+    pub fn unpin_all(&mut self) {
+        self.pins.get_mut().clear();
+    }
+    fn pin(&self, obj: *mut c_void) -> Result<(), MpsError> {
+        let mut pins = self.pins.borrow_mut();
+        match pins.last() {
+            Some(table) if table.len.get() < PIN_TABLE_LEN => {},
+            _ => pins.push(PinTable::new(self.arena())?)
+        }
+        let table = pins.last().unwrap();
+        table.refs[table.len.get()].set(obj);
+        table.len.set(table.len.get() + 1);
+        Ok(())
+    }
+}
+
+/// The number of references in each [PinTable]
+const PIN_TABLE_LEN: usize = 256;
+
+/// A fixed-size table of references to pinned objects,
+/// which is registered as an ambiguous root
+///
+/// Ambiguous references keep objects alive, and stop the MPS from moving them.
+struct PinTable {
+    // NOTE: Field order is drop order. The root must be destroyed before the table is freed.
+    _root: MpsRoot,
+    refs: Box<[Cell<*mut c_void>]>,
+    len: Cell<usize>
+}
+impl PinTable {
+    fn new(arena: &Arena) -> Result<PinTable, MpsError> {
+        let refs: Box<[Cell<*mut c_void>]> = (0..PIN_TABLE_LEN)
+            .map(|_| Cell::new(std::ptr::null_mut()))
+            .collect();
+        // NOTE: A `Cell` has the same layout as its value, and the boxed table never moves
+        let root = unsafe {
+            arena.register_ambiguous_table(refs.as_ptr() as *mut *mut c_void, PIN_TABLE_LEN)?
+        };
+        root.set_name("pinned objects");
+        Ok(PinTable { _root: root, refs, len: Cell::new(0) })
+    }
+}
+
+/// A garbage collected reference to an object of type `T`
+///
+/// The `'gc` lifetime is borrowed from the [GcContext] that allocated the object,
+/// and is invariant (see the [module docs](self)).
+/// The object is pinned by the context for as long as the reference can exist
+/// (see the [module docs](self)).
+pub struct Gc<'gc, T> {
+    ptr: NonNull<T>,
+    marker: PhantomData<(&'gc T, Invariant<'gc>)>
+}
+/// Makes a lifetime invariant
+type Invariant<'a> = fn(&'a ()) -> &'a ();
+impl<'gc, T> Gc<'gc, T> {
+    /// Create a reference from the specified raw pointer.
+    ///
+    /// ## Safety
+    /// The pointer must point to a committed object
+    /// that will be kept alive (and won't be moved) for the lifetime `'gc`.
+    #[inline]
+    pub unsafe fn from_raw(ptr: NonNull<T>) -> Self {
+        Gc { ptr, marker: PhantomData }
+    }
+    /// Get the underlying pointer to the object
+    #[inline]
+    pub fn as_raw(this: Self) -> NonNull<T> {
+        this.ptr
+    }
+    /// Check if both references point to the same object
+    #[inline]
+    pub fn ptr_eq(this: Self, other: Self) -> bool {
+        this.ptr == other.ptr
+    }
+}
+impl<'gc, T> Deref for Gc<'gc, T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.ptr.as_ptr() }
+    }
+}
+impl<T> Clone for Gc<'_, T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T> Copy for Gc<'_, T> {}
+impl<T: Debug> Debug for Gc<'_, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&**self, f)
+    }
+}
+/// References stored inside objects need to be fixed
+/// whenever the object is scanned.
+unsafe impl<T> Fixable for Gc<'_, T> {
+    #[inline]
+    unsafe fn fix(&mut self, state: &mut ScanFixState) -> Result<(), mps_res_t> {
+        self.ptr.fix(state)
+    }
+}
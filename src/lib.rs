@@ -23,6 +23,59 @@ mod err;
 pub mod arena;
 pub mod pools;
 pub mod format;
+#[cfg(feature = "format-check")]
+pub mod format_check;
 pub mod alloc;
+pub mod gc;
+pub mod segregated_cache;
+pub mod retry;
+pub mod cgroup;
+pub mod stats;
+pub mod registry;
+pub mod telemetry;
+pub mod snapshot;
+pub mod assertion;
+pub mod postmortem;
+#[cfg(feature = "stress")]
+pub mod stress;
+#[cfg(target_os = "linux")]
+pub mod signal;
+#[cfg(feature = "zerogc")]
+pub mod zerogc;
 
 pub use err::MpsError;
+
+/// A [variety](https://www.ravenbrook.com/project/mps/master/manual/html/topic/error.html#varieties) of the MPS
+///
+/// The variety is chosen at build time with the `variety-cool`, `variety-hot`
+/// and `variety-rash` features.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Variety {
+    /// Includes extra internal consistency checks, which are much slower
+    Cool,
+    /// The default variety, intended for production
+    Hot,
+    /// Has no checks at all, and doesn't support telemetry
+    Rash
+}
+impl Variety {
+    /// The name of this variety (like `"cool"`)
+    #[inline]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Variety::Cool => "cool",
+            Variety::Hot => "hot",
+            Variety::Rash => "rash"
+        }
+    }
+}
+/// The variety of the MPS that this crate was built with
+///
+/// Tests can use this to skip checks that only exist in the cool variety.
+pub const VARIETY: Variety = match mps_sys::MPS_VARIETY.as_bytes()[0] {
+    b'c' => Variety::Cool,
+    b'r' => Variety::Rash,
+    _ => Variety::Hot
+};
+#[doc(hidden)] // Used by mps-derive
+pub use mps_sys as __sys;
@@ -1,5 +1,7 @@
 //! Supported pools
-use mps_sys::{mps_pool_t, mps_ap_t};
+use mps_sys::{mps_pool_t, mps_ap_t, mps_addr_t, mps_rank_t, mps_kw_arg};
+use arrayvec::ArrayVec;
+use std::marker::PhantomData;
 
 use crate::arena::Arena;
 use crate::alloc::AllocationPoint;
@@ -7,6 +9,8 @@ use crate::MpsError;
 
 pub mod mark_sweep;
 pub mod automatic_mostly_copying;
+pub mod automatic_weak_linked;
+pub mod manual_first_fit;
 
 /// A pool of memory managed by the Memory Pool System
 ///
@@ -40,6 +44,17 @@ pub unsafe trait Pool<'arena> {
             mps_sys::mps_pool_free_size(self.as_raw())
         }
     }
+    /// The alignment of blocks allocated from this pool
+    /// (or the alignment of the pool's object format if it has one).
+    ///
+    /// All allocation sizes must be a multiple of this.
+    ///
+    /// By default this is the natural alignment of a pointer,
+    /// which is the default alignment used by the MPS.
+    #[inline]
+    fn alignment(&self) -> usize {
+        std::mem::align_of::<*mut u8>()
+    }
     /// Return if this pool automatically manages memory
     fn is_automatic(&self) -> bool;
     /// Return if this pool manually manages memory
@@ -47,18 +62,180 @@ pub unsafe trait Pool<'arena> {
     fn is_manual(&self) -> bool {
         !self.is_automatic()
     }
-    /// Create an allocation point
+    /// Begin to build an allocation point, with options specific to this pool class
+    #[inline]
+    fn allocation_point_builder(&self) -> AllocationPointBuilder<'_, 'arena, Self> where Self: Sized {
+        AllocationPointBuilder {
+            pool: self,
+            rank: None,
+            hash_arrays: None,
+            name: None,
+            marker: PhantomData
+        }
+    }
+    /// Create an allocation point, using the default options
     ///
     /// Corresponds to the C function [mps_ap_create_k](https://www.ravenbrook.com/project/mps/master/manual/html/topic/allocation.html#c.mps_ap_create_k)
     #[inline]
-    fn create_allocation_point(&self) -> Result<AllocationPoint, MpsError> {
+    fn create_allocation_point(&self) -> Result<AllocationPoint<'_>, MpsError> {
         unsafe {
             let mut res: mps_ap_t = std::ptr::null_mut();
             handle_mps_res!(::mps_sys::mps_ap_create_k(&mut res, self.as_raw(), mps_sys::mps_args_none.as_mut_ptr()))?;
-            Ok(AllocationPoint::from_raw(res))
+            Ok(AllocationPoint::from_raw_aligned(res, self.alignment()).registered(self.arena(), None))
         }
     }
 }
 
 /// A pool that supports automatic garbage collection
 pub unsafe trait AutomaticPool<'arena>: Pool<'arena> {}
+
+/// The [rank](https://www.ravenbrook.com/project/mps/master/manual/html/topic/root.html#ranks)
+/// of the references in objects allocated on an allocation point
+pub trait Rank: sealed::Sealed {
+    /// Get the raw value of the rank
+    fn raw() -> mps_rank_t;
+}
+/// References are exact (the default)
+pub struct ExactRank;
+/// References may be ambiguous
+pub struct AmbiguousRank;
+/// References are weak, and don't keep objects alive
+pub struct WeakRank;
+impl Rank for ExactRank {
+    #[inline]
+    fn raw() -> mps_rank_t {
+        unsafe { ::mps_sys::mps_rank_exact() }
+    }
+}
+impl Rank for AmbiguousRank {
+    #[inline]
+    fn raw() -> mps_rank_t {
+        unsafe { ::mps_sys::mps_rank_ambig() }
+    }
+}
+impl Rank for WeakRank {
+    #[inline]
+    fn raw() -> mps_rank_t {
+        unsafe { ::mps_sys::mps_rank_weak() }
+    }
+}
+mod sealed {
+    pub trait Sealed {}
+    impl Sealed for super::ExactRank {}
+    impl Sealed for super::AmbiguousRank {}
+    impl Sealed for super::WeakRank {}
+}
+/// A pool whose allocation points accept the specified [Rank]
+pub unsafe trait SupportsRank<'arena, R: Rank>: Pool<'arena> {
+    /// Check if this pool accepts the rank,
+    /// for pools where it depends on how the pool was configured.
+    #[inline]
+    fn allows_rank(&self) -> bool {
+        true
+    }
+}
+/// A pool whose allocation points can allocate hash arrays
+/// (see [AllocationPointBuilder::hash_arrays])
+pub unsafe trait SupportsHashArrays<'arena>: Pool<'arena> {}
+
+/// Builds an [AllocationPoint] with options specific to the pool class.
+///
+/// Options are only available if the pool supports them,
+/// so using an invalid option is a compile time error:
+///
+/// ```compile_fail
+/// # use mps::arena::VirtualMemoryArenaClass;
+/// # use mps::pools::Pool;
+/// # use mps::pools::manual_first_fit::ManualFirstFitPool;
+/// let arena = VirtualMemoryArenaClass::get().builder().build().unwrap();
+/// let pool = ManualFirstFitPool::builder(&arena).build().unwrap();
+/// let ap = pool.allocation_point_builder().hash_arrays(true).build().unwrap();
+/// ```
+pub struct AllocationPointBuilder<'p, 'arena, P: Pool<'arena>> {
+    pool: &'p P,
+    rank: Option<mps_rank_t>,
+    hash_arrays: Option<bool>,
+    name: Option<String>,
+    marker: PhantomData<&'arena Arena>
+}
+impl<'p, 'arena, P: Pool<'arena>> AllocationPointBuilder<'p, 'arena, P> {
+    /// Specify the rank of references in objects allocated on the allocation point
+    ///
+    /// Corresponds to `MPS_KEY_RANK`
+    ///
+    /// Panics if the pool wasn't configured to accept the rank
+    /// (see [SupportsRank::allows_rank]).
+    #[inline]
+    pub fn rank<R: Rank>(&mut self, _rank: R) -> &mut Self where P: SupportsRank<'arena, R> {
+        assert!(
+            <P as SupportsRank<'arena, R>>::allows_rank(self.pool),
+            "The pool doesn't accept the rank {}", std::any::type_name::<R>()
+        );
+        self.rank = Some(R::raw());
+        self
+    }
+    /// Specify whether objects allocated on the allocation point are hash arrays,
+    /// which are treated specially by the AWL pool's barriers.
+    ///
+    /// Corresponds to `MPS_KEY_AP_HASH_ARRAYS`
+    #[inline]
+    pub fn hash_arrays(&mut self, b: bool) -> &mut Self where P: SupportsHashArrays<'arena> {
+        self.hash_arrays = Some(b);
+        self
+    }
+    /// Give the allocation point a human-readable name,
+    /// which is used to label it in the telemetry stream.
+    #[inline]
+    pub fn name(&mut self, name: &str) -> &mut Self {
+        self.name = Some(name.into());
+        self
+    }
+    /// Create the allocation point
+    ///
+    /// Corresponds to the C function [mps_ap_create_k](https://www.ravenbrook.com/project/mps/master/manual/html/topic/allocation.html#c.mps_ap_create_k)
+    pub fn build(&self) -> Result<AllocationPoint<'p>, MpsError> {
+        unsafe {
+            let mut args = ArrayVec::<_, 3>::new();
+            if let Some(rank) = self.rank {
+                args.push(mps_kw_arg!(RANK => rank));
+            }
+            if let Some(hash_arrays) = self.hash_arrays {
+                args.push(mps_kw_arg!(AP_HASH_ARRAYS => hash_arrays));
+            }
+            args.push(::mps_sys::mps_args_end());
+            let mut res: mps_ap_t = std::ptr::null_mut();
+            handle_mps_res!(::mps_sys::mps_ap_create_k(&mut res, self.pool.as_raw(), args.as_mut_ptr()))?;
+            Ok(AllocationPoint::from_raw_aligned(res, self.pool.alignment()).registered(self.pool.arena(), self.name.as_deref()))
+        }
+    }
+}
+
+/// A pool whose memory is managed manually (malloc/free)
+pub unsafe trait ManualPool<'arena>: Pool<'arena> {
+    /// Allocate a block of memory of the specified size
+    ///
+    /// This is relatively slow, since it always calls into the MPS.
+    /// Consider using a [SegregatedCache](crate::segregated_cache::SegregatedCache)
+    /// for frequent small allocations.
+    ///
+    /// Corresponds to the C function [mps_alloc](https://www.ravenbrook.com/project/mps/master/manual/html/topic/allocation.html#c.mps_alloc)
+    ///
+    /// ## Safety
+    /// Undefined behavior if the size isn't aligned.
+    #[inline]
+    unsafe fn alloc(&self, size: usize) -> Result<mps_addr_t, MpsError> {
+        let mut res: mps_addr_t = std::ptr::null_mut();
+        handle_mps_res!(::mps_sys::mps_alloc(&mut res, self.as_raw(), size))?;
+        Ok(res)
+    }
+    /// Free a block of memory previously allocated from this pool
+    ///
+    /// Corresponds to the C function [mps_free](https://www.ravenbrook.com/project/mps/master/manual/html/topic/allocation.html#c.mps_free)
+    ///
+    /// ## Safety
+    /// The block must have been allocated from this pool, with the same size.
+    #[inline]
+    unsafe fn free(&self, addr: mps_addr_t, size: usize) {
+        ::mps_sys::mps_free(self.as_raw(), addr, size)
+    }
+}
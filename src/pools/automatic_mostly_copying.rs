@@ -3,20 +3,31 @@
 //! It is the most mature pool class in the MPS, and is the one primarily intended for production use.
 
 use crate::arena::Arena;
+use crate::registry::{Registration, HandleKind};
 use mps_sys::{mps_pool_t, mps_kw_arg, mps_pool_create_k, mps_pool_destroy};
 use std::mem::ManuallyDrop;
 use crate::format::ObjectFormat;
 use crate::pools::{AutomaticPool, Pool};
 use arrayvec::ArrayVec;
 use crate::MpsError;
+use std::ffi::c_void;
 
 /// A builder for [AMC pools](AutoMostlyCopyingPool)
 pub struct AutoMostlyCopyingBuilder<'a> {
     arena: &'a Arena,
     allow_interior: Option<bool>,
-    extend_by: Option<usize>
+    extend_by: Option<usize>,
+    name: Option<String>
 }
 impl<'a> AutoMostlyCopyingBuilder<'a> {
+    /// Give the pool a human-readable name.
+    ///
+    /// This is used to label the pool in the telemetry stream and in [Arena::stats].
+    #[inline]
+    pub fn name(&mut self, name: &str) -> &mut Self {
+        self.name = Some(name.into());
+        self
+    }
     /// Specify whether ambiguous interior pointers to blocks
     /// in the pool keep objects alive.
     ///
@@ -37,6 +48,24 @@ impl<'a> AutoMostlyCopyingBuilder<'a> {
         self
     }
     /// Finish building the pool, using the specified [object format](ObjectFormat)
+    ///
+    /// This pool moves objects, so the format must be [Moving](crate::format::Moving):
+    ///
+    /// ```compile_fail
+    /// # use mps::arena::VirtualMemoryArenaClass;
+    /// # use mps::format::{ObjectFormat, RawFormatMethods, ScanState};
+    /// # use mps::pools::automatic_mostly_copying::AutoMostlyCopyingPool;
+    /// # struct Format;
+    /// # unsafe impl RawFormatMethods for Format {
+    /// #     type Obj = u64;
+    /// #     const ALIGNMENT: usize = 8;
+    /// #     unsafe extern "C" fn scan(_: ScanState, _: *mut u64, _: *mut u64) -> mps_sys::mps_res_t { 0 }
+    /// #     unsafe extern "C" fn skip(obj: *mut u64) -> *mut u64 { obj.add(1) }
+    /// # }
+    /// let arena = VirtualMemoryArenaClass::get().builder().build().unwrap();
+    /// let format = ObjectFormat::non_moving_with::<Format>(&arena).unwrap();
+    /// let pool = AutoMostlyCopyingPool::builder(&arena).build(format).unwrap();
+    /// ```
     #[inline]
     pub fn build(&self, format: ObjectFormat<'a>) -> Result<AutoMostlyCopyingPool<'a>, MpsError> {
         unsafe {
@@ -51,9 +80,14 @@ impl<'a> AutoMostlyCopyingBuilder<'a> {
                 args.as_mut_ptr()
             ))?;
             assert!(!pool.is_null());
+            let registration = self.arena.register(HandleKind::Pool, Some("amc"), pool as *mut c_void);
+            if let Some(ref name) = self.name {
+                registration.set_name(name);
+            }
             Ok(AutoMostlyCopyingPool {
                 raw: pool, format,
-                arena: self.arena
+                arena: self.arena,
+                registration
             })
         }
     }
@@ -64,7 +98,9 @@ pub struct AutoMostlyCopyingPool<'a> {
     raw: mps_pool_t,
     // Must drop after pool
     format: ManuallyDrop<ObjectFormat<'a>>,
-    arena: &'a Arena
+    arena: &'a Arena,
+    // Unregistered before the pool is destroyed
+    registration: Registration
 }
 impl<'a> AutoMostlyCopyingPool<'a> {
     /// Begin to build a new automatic, mostly copying pool
@@ -76,7 +112,8 @@ impl<'a> AutoMostlyCopyingPool<'a> {
         AutoMostlyCopyingBuilder {
             arena,
             allow_interior: None,
-            extend_by: None
+            extend_by: None,
+            name: None
         }
     }
 }
@@ -90,6 +127,10 @@ unsafe impl<'a> Pool<'a> for AutoMostlyCopyingPool<'a> {
         self.arena
     }
     #[inline]
+    fn alignment(&self) -> usize {
+        self.format.alignment()
+    }
+    #[inline]
     fn is_automatic(&self) -> bool {
         true
     }
@@ -102,6 +143,7 @@ unsafe impl<'a> Send for AutoMostlyCopyingPool<'a> {}
 unsafe impl<'a> Sync for AutoMostlyCopyingPool<'a> {}
 impl<'a> Drop for AutoMostlyCopyingPool<'a> {
     fn drop(&mut self) {
+        self.registration.unregister();
         // NOTE: Drop pool *before* format
         unsafe {
             mps_pool_destroy(self.raw);
@@ -0,0 +1,140 @@
+//! Support for the [Automatic Weak Linked](https://www.ravenbrook.com/project/mps/master/manual/html/pool/awl.html) pool
+//!
+//! This pool is designed for weak references (and weak hash tables).
+//! Allocation points need to be created with [WeakRank] to allocate objects containing weak references,
+//! see [Pool::allocation_point_builder].
+
+use crate::arena::Arena;
+use crate::registry::{Registration, HandleKind};
+use mps_sys::{mps_pool_t, mps_addr_t, mps_kw_arg, mps_pool_create_k, mps_pool_destroy};
+use std::mem::ManuallyDrop;
+use crate::format::{ObjectFormat, FormatKind, NonMoving};
+use crate::pools::{
+    AutomaticPool, Pool, SupportsRank,
+    SupportsHashArrays, ExactRank, WeakRank
+};
+use arrayvec::ArrayVec;
+use crate::MpsError;
+use std::ffi::c_void;
+
+/// Finds the object that is dependent on the specified object, or null if there is none.
+///
+/// See [the docs](https://www.ravenbrook.com/project/mps/master/manual/html/pool/awl.html#c.mps_awl_find_dependent_t)
+/// for the restrictions on this function. They are the same as for format methods.
+pub type FindDependent = unsafe extern "C" fn(addr: mps_addr_t) -> mps_addr_t;
+
+/// A builder for [AWL pools](AutoWeakLinkedPool)
+pub struct AutoWeakLinkedBuilder<'a> {
+    arena: &'a Arena,
+    find_dependent: Option<FindDependent>,
+    name: Option<String>
+}
+impl<'a> AutoWeakLinkedBuilder<'a> {
+    /// Give the pool a human-readable name.
+    ///
+    /// This is used to label the pool in the telemetry stream and in [Arena::stats].
+    #[inline]
+    pub fn name(&mut self, name: &str) -> &mut Self {
+        self.name = Some(name.into());
+        self
+    }
+    /// Specify the function used to find the dependent object of an object
+    #[inline]
+    pub fn find_dependent(&mut self, func: FindDependent) -> &mut Self {
+        self.find_dependent = Some(func);
+        self
+    }
+    /// Finish building the pool, using the specified [object format](ObjectFormat)
+    ///
+    /// This pool never moves objects, so the format doesn't need to support moving.
+    pub fn build<K: FormatKind>(&self, format: ObjectFormat<'a, K>) -> Result<AutoWeakLinkedPool<'a>, MpsError> {
+        let format = format.into_non_moving();
+        unsafe {
+            let mut args = ArrayVec::<_, 3>::new();
+            args.push(mps_kw_arg!(FORMAT => format.as_raw()));
+            if let Some(func) = self.find_dependent {
+                // NOTE: Same type as a skip method
+                args.push(mps_kw_arg!(AWL_FIND_DEPENDENT => Some(func)));
+            }
+            args.push(::mps_sys::mps_args_end());
+            let mut pool = std::ptr::null_mut();
+            let format = ManuallyDrop::new(format);
+            handle_mps_res!(mps_pool_create_k(
+                &mut pool, self.arena.as_raw(),
+                ::mps_sys::mps_class_awl(),
+                args.as_mut_ptr()
+            ))?;
+            assert!(!pool.is_null());
+            let registration = self.arena.register(HandleKind::Pool, Some("awl"), pool as *mut c_void);
+            if let Some(ref name) = self.name {
+                registration.set_name(name);
+            }
+            Ok(AutoWeakLinkedPool {
+                raw: pool, format,
+                arena: self.arena,
+                registration
+            })
+        }
+    }
+}
+
+/// The [automatic, weak linked](https://www.ravenbrook.com/project/mps/master/manual/html/pool/awl.html) [Pool]
+pub struct AutoWeakLinkedPool<'a> {
+    raw: mps_pool_t,
+    // Must drop after pool
+    format: ManuallyDrop<ObjectFormat<'a, NonMoving>>,
+    arena: &'a Arena,
+    // Unregistered before the pool is destroyed
+    registration: Registration
+}
+impl<'a> AutoWeakLinkedPool<'a> {
+    /// Begin to build a new automatic weak linked pool
+    ///
+    /// See [the docs](https://www.ravenbrook.com/project/mps/master/manual/html/pool/awl.html#c.mps_class_awl)
+    /// for more details on the available options.
+    #[inline]
+    pub fn builder(arena: &'a Arena) -> AutoWeakLinkedBuilder<'a> {
+        AutoWeakLinkedBuilder {
+            arena,
+            find_dependent: None,
+            name: None
+        }
+    }
+}
+unsafe impl<'a> Pool<'a> for AutoWeakLinkedPool<'a> {
+    #[inline]
+    unsafe fn as_raw(&self) -> mps_pool_t {
+        self.raw
+    }
+    #[inline]
+    fn arena(&self) -> &'a Arena {
+        self.arena
+    }
+    #[inline]
+    fn alignment(&self) -> usize {
+        self.format.alignment()
+    }
+    #[inline]
+    fn is_automatic(&self) -> bool {
+        true
+    }
+}
+unsafe impl<'a> AutomaticPool<'a> for AutoWeakLinkedPool<'a> {}
+unsafe impl<'a> SupportsRank<'a, ExactRank> for AutoWeakLinkedPool<'a> {}
+unsafe impl<'a> SupportsRank<'a, WeakRank> for AutoWeakLinkedPool<'a> {}
+unsafe impl<'a> SupportsHashArrays<'a> for AutoWeakLinkedPool<'a> {}
+unsafe impl<'a> Send for AutoWeakLinkedPool<'a> {}
+/// This is thread safe
+///
+/// <https://www.ravenbrook.com/project/mps/master/manual/html/design/thread-safety.html>
+unsafe impl<'a> Sync for AutoWeakLinkedPool<'a> {}
+impl<'a> Drop for AutoWeakLinkedPool<'a> {
+    fn drop(&mut self) {
+        self.registration.unregister();
+        // NOTE: Drop pool *before* format
+        unsafe {
+            mps_pool_destroy(self.raw);
+            ManuallyDrop::drop(&mut self.format);
+        }
+    }
+}
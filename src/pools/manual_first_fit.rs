@@ -0,0 +1,157 @@
+//! Support for the [Manual Variable First Fit](https://www.ravenbrook.com/project/mps/master/manual/html/pool/mvff.html) pool
+//!
+//! This is a general-purpose manually managed pool, which can be used like `malloc`/`free`.
+
+use crate::arena::Arena;
+use crate::registry::{Registration, HandleKind};
+use mps_sys::{mps_pool_t, mps_kw_arg, mps_pool_create_k, mps_pool_destroy};
+use crate::pools::{ManualPool, Pool};
+use arrayvec::ArrayVec;
+use crate::MpsError;
+use std::ffi::c_void;
+
+/// A builder for [MVFF pools](ManualFirstFitPool)
+pub struct ManualFirstFitBuilder<'a> {
+    arena: &'a Arena,
+    align: Option<usize>,
+    extend_by: Option<usize>,
+    mean_size: Option<usize>,
+    first_fit: Option<bool>,
+    name: Option<String>
+}
+impl<'a> ManualFirstFitBuilder<'a> {
+    /// Give the pool a human-readable name.
+    ///
+    /// This is used to label the pool in the telemetry stream and in [Arena::stats].
+    #[inline]
+    pub fn name(&mut self, name: &str) -> &mut Self {
+        self.name = Some(name.into());
+        self
+    }
+    /// Specify the alignment of addresses allocated from the pool.
+    ///
+    /// This must be a power of two, and at least the natural alignment of a pointer.
+    #[inline]
+    pub fn align(&mut self, align: usize) -> &mut Self {
+        assert!(
+            align.is_power_of_two() && align >= std::mem::align_of::<*mut u8>(),
+            "Invalid alignment: {}", align
+        );
+        self.align = Some(align);
+        self
+    }
+    /// Specify the minimum size of the memory segments that the pool requests
+    /// from the underlying arena.
+    #[inline]
+    pub fn extend_by(&mut self, size: usize) -> &mut Self {
+        self.extend_by = Some(size);
+        self
+    }
+    /// Specify the predicted mean size of blocks that will be allocated from the pool.
+    #[inline]
+    pub fn mean_size(&mut self, size: usize) -> &mut Self {
+        self.mean_size = Some(size);
+        self
+    }
+    /// Specify whether to allocate from the first free block that fits (the default),
+    /// or from the last.
+    #[inline]
+    pub fn first_fit(&mut self, b: bool) -> &mut Self {
+        self.first_fit = Some(b);
+        self
+    }
+    /// Finish building the pool
+    pub fn build(&self) -> Result<ManualFirstFitPool<'a>, MpsError> {
+        unsafe {
+            let mut args = ArrayVec::<_, 5>::new();
+            if let Some(align) = self.align {
+                args.push(mps_kw_arg!(ALIGN => align));
+            }
+            if let Some(extend_by) = self.extend_by {
+                args.push(mps_kw_arg!(EXTEND_BY => extend_by));
+            }
+            if let Some(mean_size) = self.mean_size {
+                args.push(mps_kw_arg!(MEAN_SIZE => mean_size));
+            }
+            if let Some(first_fit) = self.first_fit {
+                args.push(mps_kw_arg!(MVFF_FIRST_FIT => first_fit));
+            }
+            args.push(::mps_sys::mps_args_end());
+            let mut pool = std::ptr::null_mut();
+            handle_mps_res!(mps_pool_create_k(
+                &mut pool, self.arena.as_raw(),
+                ::mps_sys::mps_class_mvff(),
+                args.as_mut_ptr()
+            ))?;
+            assert!(!pool.is_null());
+            let registration = self.arena.register(HandleKind::Pool, Some("mvff"), pool as *mut c_void);
+            if let Some(ref name) = self.name {
+                registration.set_name(name);
+            }
+            Ok(ManualFirstFitPool {
+                raw: pool,
+                align: self.align.unwrap_or_else(std::mem::align_of::<*mut u8>),
+                arena: self.arena,
+                registration
+            })
+        }
+    }
+}
+
+/// The [manual, variable first fit](https://www.ravenbrook.com/project/mps/master/manual/html/pool/mvff.html) [Pool]
+pub struct ManualFirstFitPool<'a> {
+    raw: mps_pool_t,
+    align: usize,
+    arena: &'a Arena,
+    // Unregistered before the pool is destroyed
+    registration: Registration
+}
+impl<'a> ManualFirstFitPool<'a> {
+    /// Begin to build a new manual variable first fit pool
+    ///
+    /// See [the docs](https://www.ravenbrook.com/project/mps/master/manual/html/pool/mvff.html#c.mps_class_mvff)
+    /// for more details on the available options.
+    #[inline]
+    pub fn builder(arena: &'a Arena) -> ManualFirstFitBuilder<'a> {
+        ManualFirstFitBuilder {
+            arena,
+            align: None,
+            extend_by: None,
+            mean_size: None,
+            first_fit: None,
+            name: None
+        }
+    }
+}
+unsafe impl<'a> Pool<'a> for ManualFirstFitPool<'a> {
+    #[inline]
+    unsafe fn as_raw(&self) -> mps_pool_t {
+        self.raw
+    }
+    #[inline]
+    fn arena(&self) -> &'a Arena {
+        self.arena
+    }
+    #[inline]
+    fn alignment(&self) -> usize {
+        self.align
+    }
+    #[inline]
+    fn is_automatic(&self) -> bool {
+        false
+    }
+}
+unsafe impl<'a> ManualPool<'a> for ManualFirstFitPool<'a> {}
+unsafe impl<'a> Send for ManualFirstFitPool<'a> {}
+/// This is thread safe
+///
+/// <https://www.ravenbrook.com/project/mps/master/manual/html/design/thread-safety.html>
+unsafe impl<'a> Sync for ManualFirstFitPool<'a> {}
+impl<'a> Drop for ManualFirstFitPool<'a> {
+    fn drop(&mut self) {
+        self.registration.unregister();
+        unsafe {
+            mps_pool_destroy(self.raw);
+        }
+    }
+}
@@ -1,12 +1,13 @@
 //! Support for the automatic mark/sweep pool
 use arrayvec::ArrayVec;
 use mps_sys::*;
-use crate::format::ObjectFormat;
+use crate::format::{ObjectFormat, FormatKind, NonMoving};
 use crate::arena::Arena;
+use crate::registry::{Registration, HandleKind};
 use std::mem::{ManuallyDrop, MaybeUninit};
 use crate::MpsError;
 
-use super::{Pool, AutomaticPool};
+use super::{Pool, AutomaticPool, SupportsRank, ExactRank, AmbiguousRank};
 use std::ffi::c_void;
 
 /// Debug options for a [AutoMarkSweep] collector
@@ -34,8 +35,17 @@ pub struct AutoMarkSweepBuilder<'a> {
     arena: &'a Arena,
     debug: Option<DebugOptions>,
     allow_ambiguous: Option<bool>,
+    name: Option<String>
 }
 impl<'a> AutoMarkSweepBuilder<'a> {
+    /// Give the pool a human-readable name.
+    ///
+    /// This is used to label the pool in the telemetry stream and in [Arena::stats].
+    #[inline]
+    pub fn name(&mut self, name: &str) -> &mut Self {
+        self.name = Some(name.into());
+        self
+    }
     /// Specify whether references to blocks in the pool
     /// may be ambiguous.
     ///
@@ -56,7 +66,10 @@ impl<'a> AutoMarkSweepBuilder<'a> {
     }
     /// Build the pool, using the specified
     /// object format to scan objects.
-    pub fn build(&mut self, format: ObjectFormat<'a>) -> Result<AutoMarkSweep<'a>, MpsError> {
+    ///
+    /// This pool never moves objects, so the format doesn't need to support moving.
+    pub fn build<K: FormatKind>(&mut self, format: ObjectFormat<'a, K>) -> Result<AutoMarkSweep<'a>, MpsError> {
+        let format = format.into_non_moving();
         unsafe {
             let raw_class = match self.debug {
                 Some(_) => mps_sys::mps_class_ams_debug(),
@@ -90,9 +103,15 @@ impl<'a> AutoMarkSweepBuilder<'a> {
                 args.as_mut_ptr()
             ))?;
             assert!(!pool.is_null());
+            let registration = self.arena.register(HandleKind::Pool, Some("ams"), pool as *mut c_void);
+            if let Some(ref name) = self.name {
+                registration.set_name(name);
+            }
             Ok(AutoMarkSweep {
                 raw: pool, format,
-                arena: self.arena
+                arena: self.arena,
+                allow_ambiguous: self.allow_ambiguous.unwrap_or(true),
+                registration
             })
         }
     }
@@ -110,8 +129,11 @@ impl<'a> AutoMarkSweepBuilder<'a> {
 pub struct AutoMarkSweep<'a> {
     raw: mps_pool_t,
     // Must drop after pool
-    format: ManuallyDrop<ObjectFormat<'a>>,
-    arena: &'a Arena
+    format: ManuallyDrop<ObjectFormat<'a, NonMoving>>,
+    arena: &'a Arena,
+    allow_ambiguous: bool,
+    // Unregistered before the pool is destroyed
+    registration: Registration
 }
 impl<'a> AutoMarkSweep<'a> {
     /// Begin to build a new automatic mark sweep pool
@@ -123,7 +145,8 @@ impl<'a> AutoMarkSweep<'a> {
         AutoMarkSweepBuilder {
             debug: None,
             arena,
-            allow_ambiguous: None
+            allow_ambiguous: None,
+            name: None
         }
     }
 }
@@ -137,11 +160,23 @@ unsafe impl<'a> Pool<'a> for AutoMarkSweep<'a> {
         self.arena
     }
     #[inline]
+    fn alignment(&self) -> usize {
+        self.format.alignment()
+    }
+    #[inline]
     fn is_automatic(&self) -> bool {
         true
     }
 }
 unsafe impl<'a> AutomaticPool<'a> for AutoMarkSweep<'a> {}
+unsafe impl<'a> SupportsRank<'a, ExactRank> for AutoMarkSweep<'a> {}
+/// Only if the pool [allows ambiguous references](AutoMarkSweepBuilder::allow_ambiguous)
+unsafe impl<'a> SupportsRank<'a, AmbiguousRank> for AutoMarkSweep<'a> {
+    #[inline]
+    fn allows_rank(&self) -> bool {
+        self.allow_ambiguous
+    }
+}
 unsafe impl<'a> Send for AutoMarkSweep<'a> {}
 /// This is thread safe
 ///
@@ -149,6 +184,7 @@ unsafe impl<'a> Send for AutoMarkSweep<'a> {}
 unsafe impl<'a> Sync for AutoMarkSweep<'a> {}
 impl<'a> Drop for AutoMarkSweep<'a> {
     fn drop(&mut self) {
+        self.registration.unregister();
         // NOTE: Drop pool *before* format
         unsafe {
             mps_pool_destroy(self.raw);
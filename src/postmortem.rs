@@ -0,0 +1,67 @@
+//! Inspecting an arena's heap after a crash
+//!
+//! The MPS protects memory to implement its barriers,
+//! so a core dump (or a debugger) may not be able to read parts of the heap.
+//! Putting the arena into the postmortem state (with [Arena::postmortem])
+//! removes the protection before the dump is written.
+//!
+//! [install_panic_hook] does this automatically when the process panics,
+//! and then aborts so the process dumps core.
+use std::panic;
+use std::ptr;
+use std::sync::{Mutex, Once};
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use mps_sys::{mps_arena_s, mps_arena_t};
+
+use crate::arena::Arena;
+
+/// The arena to put into the postmortem state when the process panics
+static ARENA: AtomicPtr<mps_arena_s> = AtomicPtr::new(ptr::null_mut());
+static INSTALL_HOOK: Once = Once::new();
+/// Held by the hook until the process aborts,
+/// so the arena can't be destroyed while (or after) it's put into the postmortem state
+static HOOK_LOCK: Mutex<()> = Mutex::new(());
+
+/// Install a panic hook that puts the specified arena into the postmortem state
+/// (see [Arena::postmortem]), calls the previous hook, and then aborts the process.
+///
+/// The process is aborted even if the panic would have been caught,
+/// because unwinding would drop the arena (and its pools) after it can no longer be used.
+/// The hook runs before `catch_unwind` sees the panic, so this applies to *every* panic,
+/// including ones that are always caught. In particular, a [CatchUnwind](crate::format::CatchUnwind)
+/// format using [OnPanic::Fail](crate::format::OnPanic::Fail) aborts instead of failing the scan.
+///
+/// Only a single arena is supported, and calling this again replaces it
+/// (without installing another hook).
+/// If the arena is dropped first, the hook just calls the previous one.
+pub fn install_panic_hook(arena: &Arena) {
+    ARENA.store(arena.as_raw(), Ordering::Release);
+    INSTALL_HOOK.call_once(|| {
+        let previous_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            // NOTE: The guard is never dropped if the arena is put into the postmortem state
+            let guard = HOOK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            let arena = ARENA.swap(ptr::null_mut(), Ordering::AcqRel);
+            if !arena.is_null() {
+                unsafe { mps_sys::mps_arena_postmortem(arena) }
+            }
+            if arena.is_null() {
+                drop(guard);
+            }
+            previous_hook(info);
+            if !arena.is_null() {
+                std::process::abort();
+            }
+        }));
+    });
+}
+
+/// Stop using an arena that is being destroyed
+///
+/// This blocks forever if the hook is putting the arena into the postmortem state,
+/// since the process is about to abort anyway.
+pub(crate) fn forget_arena(raw: mps_arena_t) {
+    let _guard = HOOK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let _ = ARENA.compare_exchange(raw, ptr::null_mut(), Ordering::AcqRel, Ordering::Acquire);
+}
@@ -0,0 +1,187 @@
+//! Tracking the live handles (pools, formats, roots, threads and allocation points)
+//! that belong to an [Arena](crate::arena::Arena)
+//!
+//! Every handle created by this crate registers itself with its arena,
+//! and unregisters itself when it's dropped.
+//! This is what allows [Arena::stats](crate::arena::Arena::stats)
+//! to find all the pools in an arena.
+//!
+//! In debug builds, dropping an arena while any of its handles are still alive panics
+//! (listing the handles), instead of failing an MPS assertion.
+use std::ffi::c_void;
+use std::fmt::{self, Display, Formatter};
+use std::sync::{Arc, Mutex};
+
+use mps_sys::{mps_fmt_skip_t, mps_fmt_class_t};
+#[cfg(feature = "stress")]
+use mps_sys::{mps_fmt_scan_t, mps_fmt_isfwd_t};
+
+/// The kind of a handle registered with an arena
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum HandleKind {
+    /// A [Pool](crate::pools::Pool)
+    Pool,
+    /// An [ObjectFormat](crate::format::ObjectFormat)
+    Format,
+    /// An [MpsRoot](crate::arena::MpsRoot)
+    Root,
+    /// An [MpsThread](crate::arena::MpsThread)
+    Thread,
+    /// An [AllocationPoint](crate::alloc::AllocationPoint)
+    AllocationPoint
+}
+impl Display for HandleKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            HandleKind::Pool => "pool",
+            HandleKind::Format => "format",
+            HandleKind::Root => "root",
+            HandleKind::Thread => "thread",
+            HandleKind::AllocationPoint => "allocation point"
+        })
+    }
+}
+
+/// Describes a live handle, as returned by [Arena::handles](crate::arena::Arena::handles)
+#[derive(Debug, Clone)]
+pub struct HandleInfo {
+    /// A number identifying the handle within its arena
+    ///
+    /// Ids are assigned in order of creation and are never reused.
+    pub id: usize,
+    /// The kind of handle
+    pub kind: HandleKind,
+    /// The short name of the pool class (like `"amc"`),
+    /// if this handle is a pool.
+    pub class: Option<&'static str>,
+    /// The name given to the handle by its builder (if any)
+    pub name: Option<String>
+}
+impl Display for HandleInfo {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} #{}", self.kind, self.id)?;
+        if let Some(ref name) = self.name {
+            write!(f, " {:?}", name)?;
+        }
+        if let Some(class) = self.class {
+            write!(f, " ({})", class)?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct Entry {
+    pub(crate) info: HandleInfo,
+    /// The raw MPS pointer of the handle
+    pub(crate) raw: *mut c_void,
+    /// The methods of the format, if this handle is a format
+    pub(crate) format_methods: Option<FormatMethods>
+}
+/// The format methods needed to walk (and verify) the heap
+#[derive(Copy, Clone)]
+pub(crate) struct FormatMethods {
+    /// Only needed to verify the heap (see the [stress](crate::stress) module)
+    #[cfg(feature = "stress")]
+    pub(crate) scan: mps_fmt_scan_t,
+    pub(crate) skip: mps_fmt_skip_t,
+    #[cfg(feature = "stress")]
+    pub(crate) is_forwarded: mps_fmt_isfwd_t,
+    pub(crate) class: mps_fmt_class_t
+}
+
+#[derive(Default)]
+struct RegistryState {
+    next_id: usize,
+    entries: Vec<Entry>
+}
+
+/// The registry of an arena's live handles
+///
+/// This is shared with each [Registration],
+/// so a handle doesn't need a reference to its arena to unregister itself.
+#[derive(Default, Clone)]
+pub(crate) struct Registry {
+    state: Arc<Mutex<RegistryState>>
+}
+impl Registry {
+    /// Record a newly created handle
+    pub(crate) fn register(&self, kind: HandleKind, class: Option<&'static str>, raw: *mut c_void) -> Registration {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.entries.push(Entry {
+            info: HandleInfo { id, kind, class, name: None },
+            raw, format_methods: None
+        });
+        Registration { state: Arc::clone(&self.state), id, registered: true }
+    }
+    /// Call the specified closure with all the live handles, in order of creation
+    pub(crate) fn with_entries<R>(&self, func: impl FnOnce(&[Entry]) -> R) -> R {
+        let state = self.state.lock().unwrap();
+        func(&state.entries)
+    }
+    /// Like [Registry::with_entries], but returns `None` instead of blocking
+    /// if the registry is already locked (or poisoned).
+    pub(crate) fn try_with_entries<R>(&self, func: impl FnOnce(&[Entry]) -> R) -> Option<R> {
+        let state = self.state.try_lock().ok()?;
+        Some(func(&state.entries))
+    }
+}
+
+/// Removes a handle from its arena's [Registry] when dropped
+///
+/// Handles must [unregister](Registration::unregister) themselves
+/// *before* destroying the underlying MPS object,
+/// since the arena may be walking its handles on another thread.
+pub(crate) struct Registration {
+    state: Arc<Mutex<RegistryState>>,
+    id: usize,
+    registered: bool
+}
+impl Registration {
+    /// Remove the handle from the registry,
+    /// waiting for anyone using its entry to finish.
+    ///
+    /// This does nothing if the handle is already unregistered.
+    pub(crate) fn unregister(&mut self) {
+        if !self.registered {
+            return
+        }
+        self.registered = false;
+        // NOTE: Don't double panic if the lock is poisoned
+        if let Ok(mut state) = self.state.lock() {
+            let id = self.id;
+            state.entries.retain(|entry| entry.info.id != id);
+        }
+    }
+    /// Record the methods of a format, so the heap can be walked
+    pub(crate) fn set_format_methods(&self, methods: FormatMethods) {
+        self.with_entry(|entry| {
+            debug_assert_eq!(entry.info.kind, HandleKind::Format);
+            entry.format_methods = Some(methods);
+        })
+    }
+    fn with_entry<R>(&self, func: impl FnOnce(&mut Entry) -> R) -> R {
+        let mut state = self.state.lock().unwrap();
+        let id = self.id;
+        let entry = state.entries.iter_mut()
+            .find(|entry| entry.info.id == id)
+            .expect("Missing registry entry");
+        func(entry)
+    }
+    /// Give the handle a human-readable name,
+    /// which is also used to label it in the telemetry stream.
+    pub(crate) fn set_name(&self, name: &str) {
+        self.with_entry(|entry| {
+            entry.info.name = Some(name.into());
+            unsafe { crate::telemetry::label(entry.raw, crate::telemetry::intern(name)) }
+        })
+    }
+}
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+/// The raw pointers are only dereferenced by the arena (while the lock is held)
+unsafe impl Send for RegistryState {}
@@ -0,0 +1,156 @@
+//! Recovering from allocation failures caused by running out of memory
+//!
+//! By default, when [AllocationPoint::reserve](crate::alloc::AllocationPoint::reserve)
+//! fails with [MpsError::CommitLimit] or [MpsError::Memory] the error is returned immediately.
+//! An opt-in [RetryPolicy] can instead try to free up memory (or raise the limit)
+//! and then retry the reservation.
+use std::marker::PhantomData;
+
+use mps_sys::mps_addr_t;
+
+use crate::arena::{Arena, ArenaControl};
+use crate::MpsError;
+
+/// Describes a failed reservation,
+/// given to the [out of memory handler](RetryPolicy::on_out_of_memory)
+#[derive(Debug)]
+pub struct OutOfMemory<'a> {
+    /// The error that caused the failure
+    pub error: &'a MpsError,
+    /// The size of the reservation that failed
+    pub size: usize,
+    /// The number of this attempt (starting at one)
+    pub attempt: u32,
+    /// The arena's current commit limit
+    pub commit_limit: usize,
+    /// The arena's currently committed memory
+    pub committed: usize
+}
+
+/// What to do when a reservation runs out of memory
+///
+/// Each attempt performs the enabled actions in order:
+/// 1. Run a [full collection](Arena::full_collection)
+/// 2. Raise the commit limit (up to a hard cap)
+/// 3. Call the out of memory handler
+///
+/// Then the reservation is retried.
+/// If all the attempts are exhausted, the last error is returned.
+///
+/// Set with [AllocationPoint::set_retry_policy](crate::alloc::AllocationPoint::set_retry_policy).
+/// The `'arena` lifetime borrows the arena the policy collects,
+/// so the policy can't outlive it:
+///
+/// ```compile_fail
+/// # use mps::arena::VirtualMemoryArenaClass;
+/// # use mps::retry::RetryPolicy;
+/// let policy = {
+///     let arena = VirtualMemoryArenaClass::get().builder().build().unwrap();
+///     RetryPolicy::new(&arena)
+/// };
+/// ```
+pub struct RetryPolicy<'arena> {
+    control: ArenaControl,
+    marker: PhantomData<&'arena Arena>,
+    collect: bool,
+    raise_limit: Option<(usize, usize)>,
+    max_attempts: u32,
+    handler: Option<OutOfMemoryHandler>
+}
+type OutOfMemoryHandler = Box<dyn FnMut(&OutOfMemory) -> bool>;
+impl<'arena> RetryPolicy<'arena> {
+    /// Create a policy for allocations in the specified arena.
+    ///
+    /// By default, this runs a full collection and retries once.
+    #[inline]
+    pub fn new(arena: &'arena Arena) -> RetryPolicy<'arena> {
+        RetryPolicy {
+            control: arena.control().clone(),
+            marker: PhantomData,
+            collect: true,
+            raise_limit: None,
+            max_attempts: 1,
+            handler: None
+        }
+    }
+    /// Specify whether to run a full collection before retrying.
+    ///
+    /// Since a full collection leaves the arena parked,
+    /// the arena is put back into its previous [state](Arena::state) afterwards.
+    #[inline]
+    pub fn collect(mut self, b: bool) -> Self {
+        self.collect = b;
+        self
+    }
+    /// Raise the commit limit by `increment` bytes on each attempt,
+    /// but never above `hard_cap`.
+    #[inline]
+    pub fn raise_limit(mut self, increment: usize, hard_cap: usize) -> Self {
+        assert!(increment > 0, "Increment must be positive");
+        self.raise_limit = Some((increment, hard_cap));
+        self
+    }
+    /// The maximum number of times to retry each reservation
+    #[inline]
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = attempts;
+        self
+    }
+    /// Call the specified handler before retrying.
+    ///
+    /// The handler can free up memory (for example by dropping caches),
+    /// and returns whether the reservation should be retried at all.
+    ///
+    /// The handler must not allocate on the allocation point that failed.
+    #[inline]
+    pub fn on_out_of_memory<F>(mut self, handler: F) -> Self
+        where F: FnMut(&OutOfMemory) -> bool + 'static {
+        self.handler = Some(Box::new(handler));
+        self
+    }
+    /// Whether a policy can do anything about the specified error
+    #[inline]
+    pub(crate) fn is_recoverable(error: &MpsError) -> bool {
+        matches!(*error, MpsError::CommitLimit | MpsError::Memory)
+    }
+    /// Attempt to recover from the specified error,
+    /// using the closure to retry the reservation.
+    pub(crate) fn retry<F>(&mut self, size: usize, cause: MpsError, mut reserve: F) -> Result<mps_addr_t, MpsError>
+        where F: FnMut() -> Result<mps_addr_t, MpsError> {
+        let arena = self.control.as_raw();
+        let mut error = cause;
+        for attempt in 1..=self.max_attempts {
+            unsafe {
+                if self.collect {
+                    let state = self.control.state();
+                    self.control.collect();
+                    self.control.restore(state);
+                }
+                if let Some((increment, hard_cap)) = self.raise_limit {
+                    let limit = ::mps_sys::mps_arena_commit_limit(arena);
+                    if limit < hard_cap {
+                        let new_limit = limit.saturating_add(increment).min(hard_cap);
+                        // NOTE: Failure just means we retry with the old limit
+                        let _ = ::mps_sys::mps_arena_commit_limit_set(arena, new_limit);
+                    }
+                }
+                if let Some(ref mut handler) = self.handler {
+                    let should_retry = handler(&OutOfMemory {
+                        error: &error, size, attempt,
+                        commit_limit: ::mps_sys::mps_arena_commit_limit(arena),
+                        committed: ::mps_sys::mps_arena_committed(arena)
+                    });
+                    if !should_retry {
+                        break
+                    }
+                }
+            }
+            match reserve() {
+                Ok(res) => return Ok(res),
+                Err(e) if Self::is_recoverable(&e) => error = e,
+                Err(e) => return Err(e)
+            }
+        }
+        Err(error)
+    }
+}
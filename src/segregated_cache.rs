@@ -0,0 +1,191 @@
+//! [Segregated allocation caches](https://www.ravenbrook.com/project/mps/master/manual/html/topic/cache.html),
+//! for fast allocation and freeing of small blocks in manual pools.
+//!
+//! A cache keeps a free list of recently freed blocks for each of its size classes.
+//! Just like an [AllocationPoint](crate::alloc::AllocationPoint),
+//! the common case is handled inline without calling into the MPS.
+use std::marker::PhantomData;
+use std::ptr;
+
+use mps_sys::{mps_sac_t, mps_addr_t, mps_sac_class_s, mps_sac_freelist_block_s};
+use arrayvec::ArrayVec;
+
+use crate::alloc::DEBUG_ALLOCATION_POINTS;
+use crate::pools::ManualPool;
+use crate::MpsError;
+
+/// The maximum number of size classes in a cache
+///
+/// Corresponds to the C macro `MPS_SAC_CLASS_LIMIT` (which bindgen can't translate)
+pub const MAX_SIZE_CLASSES: usize = 8;
+
+/// A size class for a [SegregatedCache]
+#[derive(Debug, Copy, Clone)]
+pub struct SizeClass {
+    /// The maximum size of blocks in this class.
+    ///
+    /// This must be aligned to the pool's alignment.
+    pub block_size: usize,
+    /// The maximum number of blocks that will be cached for this class
+    pub cached_count: usize,
+    /// A number that describes the frequency of requests (allocation and deallocation)
+    /// in this class relative to the other classes.
+    ///
+    /// Only the relative values matter.
+    pub frequency: u32
+}
+
+/// A segregated allocation cache for a [ManualPool]
+///
+/// Allocations are rounded up to the smallest size class that fits.
+/// Blocks that are larger than every size class are
+/// allocated directly from the pool.
+pub struct SegregatedCache<'pool> {
+    raw: mps_sac_t,
+    _pool: PhantomData<&'pool ()>
+}
+/// A cache is not thread safe.
+impl !Send for SegregatedCache<'_> {}
+impl !Sync for SegregatedCache<'_> {}
+impl<'pool> SegregatedCache<'pool> {
+    /// Create a cache for the specified pool, using the specified size classes.
+    ///
+    /// There must be between one and [MAX_SIZE_CLASSES] classes,
+    /// in order of increasing block size.
+    ///
+    /// Corresponds to C function [mps_sac_create](https://www.ravenbrook.com/project/mps/master/manual/html/topic/cache.html#c.mps_sac_create)
+    pub fn create<'arena, P: ManualPool<'arena>>(pool: &'pool P, classes: &[SizeClass]) -> Result<Self, MpsError> {
+        assert!(
+            !classes.is_empty() && classes.len() <= MAX_SIZE_CLASSES,
+            "Invalid number of size classes: {}", classes.len()
+        );
+        assert!(
+            classes.windows(2).all(|w| w[0].block_size < w[1].block_size),
+            "Size classes must be in increasing order"
+        );
+        let mut raw_classes: ArrayVec<mps_sac_class_s, MAX_SIZE_CLASSES> = classes.iter()
+            .map(|class| {
+                assert_eq!(
+                    class.block_size % pool.alignment(), 0,
+                    "Unaligned block size: {}", class.block_size
+                );
+                mps_sac_class_s {
+                    mps_block_size: class.block_size,
+                    mps_cached_count: class.cached_count,
+                    mps_frequency: class.frequency
+                }
+            })
+            .collect();
+        unsafe {
+            let mut raw: mps_sac_t = std::ptr::null_mut();
+            handle_mps_res!(::mps_sys::mps_sac_create(
+                &mut raw, pool.as_raw(),
+                raw_classes.len(), raw_classes.as_mut_ptr()
+            ))?;
+            Ok(SegregatedCache { raw, _pool: PhantomData })
+        }
+    }
+    /// Get the raw pointer to the underlying cache
+    #[inline]
+    pub fn as_raw(&self) -> mps_sac_t {
+        self.raw
+    }
+    /// Find the free list for the specified size
+    ///
+    /// Free lists alternate between classes above and below the "middle" size,
+    /// see `MPS_SAC_ALLOC_FAST` in `mps.h`.
+    ///
+    /// The MPS only allocates the free lists for the classes that were configured,
+    /// so they must be accessed through raw pointers instead of a reference to the whole array.
+    #[inline(always)]
+    unsafe fn freelist(&self, size: usize) -> *mut mps_sac_freelist_block_s {
+        let freelists = ptr::addr_of_mut!((*self.raw)._freelists) as *mut mps_sac_freelist_block_s;
+        let mut index;
+        if size > (*self.raw)._middle {
+            index = 0;
+            while size > (*freelists.add(index))._size {
+                index += 2;
+            }
+        } else {
+            index = 1;
+            while size <= (*freelists.add(index))._size {
+                index += 2;
+            }
+        }
+        freelists.add(index)
+    }
+    /// Allocate a block of the specified size from the cache
+    ///
+    /// Corresponds to C macro [MPS_SAC_ALLOC_FAST](https://www.ravenbrook.com/project/mps/master/manual/html/topic/cache.html#c.MPS_SAC_ALLOC_FAST)
+    ///
+    /// ## Safety
+    /// Undefined behavior if the size is not properly aligned.
+    #[cfg_attr(not(debug_assertions), inline(always))]
+    #[cfg_attr(debug_assertions, inline)]
+    pub unsafe fn alloc(&self, size: usize) -> Result<mps_addr_t, MpsError> {
+        if !DEBUG_ALLOCATION_POINTS {
+            let freelist = &mut *self.freelist(size);
+            if freelist._count != 0 {
+                let res = freelist._blocks;
+                freelist._blocks = *(res as *mut mps_addr_t);
+                freelist._count -= 1;
+                Ok(res)
+            } else {
+                self._fill(size)
+            }
+        } else {
+            let mut res: mps_addr_t = std::ptr::null_mut();
+            handle_mps_res!(::mps_sys::mps_sac_alloc(&mut res, self.raw, size, 0))?;
+            Ok(res)
+        }
+    }
+    /// Return a block to the cache
+    ///
+    /// Corresponds to C macro [MPS_SAC_FREE_FAST](https://www.ravenbrook.com/project/mps/master/manual/html/topic/cache.html#c.MPS_SAC_FREE_FAST)
+    ///
+    /// ## Safety
+    /// The block must have been allocated from this cache (or its pool),
+    /// and the size must match the allocated size.
+    #[cfg_attr(not(debug_assertions), inline(always))]
+    #[cfg_attr(debug_assertions, inline)]
+    pub unsafe fn free(&self, p: mps_addr_t, size: usize) {
+        if !DEBUG_ALLOCATION_POINTS {
+            let freelist = &mut *self.freelist(size);
+            if freelist._count < freelist._count_max {
+                *(p as *mut mps_addr_t) = freelist._blocks;
+                freelist._blocks = p;
+                freelist._count += 1;
+            } else {
+                self._empty(p, size)
+            }
+        } else {
+            ::mps_sys::mps_sac_free(self.raw, p, size)
+        }
+    }
+    /// Flush the cache, returning all cached blocks to the pool.
+    ///
+    /// Corresponds to C function [mps_sac_flush](https://www.ravenbrook.com/project/mps/master/manual/html/topic/cache.html#c.mps_sac_flush)
+    #[inline]
+    pub fn flush(&self) {
+        unsafe { ::mps_sys::mps_sac_flush(self.raw) }
+    }
+    /// Allocate a block when the free list for its class is empty
+    #[cold]
+    #[inline(never)]
+    unsafe fn _fill(&self, size: usize) -> Result<mps_addr_t, MpsError> {
+        let mut res: mps_addr_t = std::ptr::null_mut();
+        handle_mps_res!(::mps_sys::mps_sac_fill(&mut res, self.raw, size, 0))?;
+        Ok(res)
+    }
+    /// Free a block when the free list for its class is full
+    #[cold]
+    #[inline(never)]
+    unsafe fn _empty(&self, p: mps_addr_t, size: usize) {
+        ::mps_sys::mps_sac_empty(self.raw, p, size)
+    }
+}
+impl Drop for SegregatedCache<'_> {
+    fn drop(&mut self) {
+        unsafe { ::mps_sys::mps_sac_destroy(self.raw) }
+    }
+}
@@ -0,0 +1,278 @@
+//! Sharing `SIGSEGV` with other signal handlers
+//!
+//! On Linux, the MPS implements its memory barriers by protecting memory
+//! and handling the resulting `SIGSEGV`
+//! (see [the shield design](https://www.ravenbrook.com/project/mps/master/manual/html/design/shield.html#overview)).
+//! The MPS installs its protection handler when the first arena is created,
+//! and (as of this writing) never uninstalls it.
+//!
+//! Other libraries (like Wasmtime or a crash reporter) often install their own `SIGSEGV` handlers.
+//! Whichever handler is installed last sees every fault first,
+//! and must pass on the faults it doesn't recognize:
+//!
+//! 1. **MPS first, then the other handler:**
+//!    The other handler must forward unrecognized faults to the previously installed handler
+//!    (calling it directly, with the original `siginfo_t` and context).
+//!    Wasmtime does this already. Rust code can use [install_chained_handler].
+//! 2. **Other handler first, then the MPS:**
+//!    The MPS passes on faults outside its memory by re-raising the signal with `kill`,
+//!    so the other handler would receive it without the fault address or context.
+//!    To avoid this, when the first arena is created over an existing handler,
+//!    this crate installs a dispatcher on top of the MPS handler.
+//!    The dispatcher checks whether the faulting address belongs to one of the arenas
+//!    (with [mps_arena_has_addr](https://www.ravenbrook.com/project/mps/master/manual/html/topic/arena.html#c.mps_arena_has_addr))
+//!    and calls either the MPS handler or the original one directly.
+//!
+//! Use [protection_handler] to check the current state.
+use std::ffi::c_void;
+use std::io;
+use std::mem::{self, MaybeUninit};
+use std::os::raw::c_int;
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use libc::{sigaction, siginfo_t, SIGSEGV};
+use mps_sys::mps_arena_t;
+
+/// A Rust handler for `SIGSEGV`, installed with [install_chained_handler]
+///
+/// This is called with the signal information and the (`ucontext_t`) context,
+/// and returns whether it handled the fault.
+/// Unhandled faults are passed on to the next handler.
+///
+/// This runs inside a signal handler, so it must be async-signal-safe.
+/// In particular, it must not allocate or panic.
+pub type SignalHandler = unsafe fn(info: &siginfo_t, context: *mut c_void) -> bool;
+
+/// The state of the MPS protection handler, as returned by [protection_handler]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ProtectionHandler {
+    /// No arena has been created yet, so the MPS hasn't installed its handler
+    NotInstalled,
+    /// The MPS handler is the current `SIGSEGV` handler
+    Current,
+    /// The dispatcher installed by this crate is the current `SIGSEGV` handler,
+    /// and passes faults in MPS-managed memory on to the MPS handler.
+    Chained,
+    /// Another handler has been installed on top of the MPS handler
+    ///
+    /// That handler must pass on the faults it doesn't recognize,
+    /// or the MPS will stop working.
+    Overridden
+}
+impl ProtectionHandler {
+    /// Whether the MPS has installed its protection handler
+    #[inline]
+    pub fn is_installed(self) -> bool {
+        self != ProtectionHandler::NotInstalled
+    }
+}
+
+/// Determine the state of the MPS protection handler
+pub fn protection_handler() -> ProtectionHandler {
+    let mps = MPS_ACTION.load(Ordering::Acquire);
+    if mps.is_null() {
+        return ProtectionHandler::NotInstalled;
+    }
+    let mps = unsafe { &*mps };
+    let current = current_action();
+    if current.sa_sigaction == mps.sa_sigaction {
+        return ProtectionHandler::Current;
+    }
+    if current.sa_sigaction == dispatch as *const () as usize {
+        let chain = unsafe { &*CHAIN.load(Ordering::Acquire) };
+        if chain.mps.is_some() || chain.previous.sa_sigaction == mps.sa_sigaction {
+            return ProtectionHandler::Chained;
+        }
+    }
+    ProtectionHandler::Overridden
+}
+
+/// Install a `SIGSEGV` handler that passes unhandled faults on to the previous handler
+///
+/// This can be called either before or after the MPS installs its handler.
+/// Only a single chained handler is supported,
+/// and installing another one replaces it (keeping the same position in the chain).
+///
+/// ## Safety
+/// The handler sees every `SIGSEGV` first, including the MPS's own protection faults, so:
+///
+/// - It must be async-signal-safe (see [SignalHandler]).
+/// - It must only return `true` for faults it actually handled.
+///   Claiming one of the MPS's faults leaves the memory protected,
+///   so the faulting instruction faults again forever.
+///
+/// This must not be called from a signal handler,
+/// or while other code is changing the `SIGSEGV` handler (except through this module).
+pub unsafe fn install_chained_handler(handler: SignalHandler) -> io::Result<()> {
+    let _guard = INSTALL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let old = CHAIN.load(Ordering::Acquire);
+    let chain = if !old.is_null() {
+        Chain { handler: Some(handler), ..*old }
+    } else {
+        Chain { handler: Some(handler), mps: None, previous: current_action() }
+    };
+    CHAIN.store(Box::into_raw(Box::new(chain)), Ordering::Release);
+    // NOTE: The old chain is leaked, because a signal could still be using it
+    if old.is_null() {
+        install_dispatcher()?;
+    }
+    Ok(())
+}
+
+/// Call the specified function, which may create the first arena
+///
+/// If the MPS installs its handler over an existing one,
+/// this installs the dispatcher on top of it.
+pub(crate) fn watch_install<R>(create: impl FnOnce() -> R) -> R {
+    let _guard = INSTALL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let before = current_action();
+    let result = create();
+    let after = current_action();
+    if MPS_ACTION.load(Ordering::Acquire).is_null() && after.sa_sigaction != before.sa_sigaction {
+        MPS_ACTION.store(Box::into_raw(Box::new(after)), Ordering::Release);
+        if before.sa_sigaction != libc::SIG_DFL && before.sa_sigaction != libc::SIG_IGN {
+            let old = CHAIN.load(Ordering::Acquire);
+            let chain = if before.sa_sigaction == dispatch as *const () as usize && !old.is_null() {
+                // The MPS was installed over our own dispatcher
+                Chain { mps: Some(after), ..unsafe { *old } }
+            } else {
+                Chain { handler: None, mps: Some(after), previous: before }
+            };
+            CHAIN.store(Box::into_raw(Box::new(chain)), Ordering::Release);
+            install_dispatcher().expect("Failed to install the signal dispatcher");
+        }
+    }
+    result
+}
+
+/// The maximum number of live arenas the dispatcher can check
+const MAX_ARENAS: usize = 64;
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_ARENA: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+/// The live arenas, used to check whether a fault is in MPS-managed memory
+static ARENAS: [AtomicPtr<c_void>; MAX_ARENAS] = [NO_ARENA; MAX_ARENAS];
+/// The number of live arenas that didn't fit in [ARENAS]
+///
+/// While there are any, every protection fault is passed on to the MPS handler,
+/// which re-raises the faults it doesn't recognize (see case 2 in the module docs).
+static UNTRACKED_ARENAS: AtomicUsize = AtomicUsize::new(0);
+
+/// Record a newly created arena, so the dispatcher can recognize its faults
+pub(crate) fn add_arena(raw: mps_arena_t) {
+    let added = ARENAS.iter().any(|slot| {
+        slot.compare_exchange(ptr::null_mut(), raw as *mut c_void, Ordering::AcqRel, Ordering::Acquire).is_ok()
+    });
+    if !added {
+        UNTRACKED_ARENAS.fetch_add(1, Ordering::AcqRel);
+    }
+}
+/// Forget an arena that is being destroyed
+///
+/// This waits for any dispatcher that might still be checking the arena,
+/// so the arena can be destroyed as soon as this returns.
+pub(crate) fn remove_arena(raw: mps_arena_t) {
+    let removed = ARENAS.iter().any(|slot| {
+        slot.compare_exchange(raw as *mut c_void, ptr::null_mut(), Ordering::SeqCst, Ordering::SeqCst).is_ok()
+    });
+    if !removed {
+        UNTRACKED_ARENAS.fetch_sub(1, Ordering::AcqRel);
+        return;
+    }
+    // NOTE: A check that started after the slot was cleared can't see the arena
+    while ACTIVE_CHECKS.load(Ordering::SeqCst) > 0 {
+        std::thread::yield_now();
+    }
+}
+/// The number of dispatchers currently checking the arenas in [ARENAS]
+///
+/// Arenas are only destroyed once this drops to zero (see [remove_arena]).
+static ACTIVE_CHECKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Serializes changes to the `SIGSEGV` handler,
+/// so arenas created concurrently can't both record the MPS handler
+static INSTALL_LOCK: Mutex<()> = Mutex::new(());
+/// The handler that the MPS installed (if any)
+static MPS_ACTION: AtomicPtr<sigaction> = AtomicPtr::new(ptr::null_mut());
+/// The state of the dispatcher (if it's installed)
+static CHAIN: AtomicPtr<Chain> = AtomicPtr::new(ptr::null_mut());
+
+#[derive(Copy, Clone)]
+struct Chain {
+    /// The handler installed with [install_chained_handler]
+    handler: Option<SignalHandler>,
+    /// The MPS handler, if it was installed underneath the dispatcher
+    mps: Option<sigaction>,
+    /// The handler that was installed before the dispatcher (or the MPS)
+    previous: sigaction
+}
+
+fn current_action() -> sigaction {
+    unsafe {
+        let mut action = MaybeUninit::<sigaction>::zeroed();
+        let res = libc::sigaction(SIGSEGV, ptr::null(), action.as_mut_ptr());
+        assert_eq!(res, 0, "Failed to query the SIGSEGV handler");
+        action.assume_init()
+    }
+}
+fn install_dispatcher() -> io::Result<()> {
+    unsafe {
+        let mut action: sigaction = mem::zeroed();
+        action.sa_sigaction = dispatch as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(SIGSEGV, &action, ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+unsafe extern "C" fn dispatch(signal: c_int, info: *mut siginfo_t, context: *mut c_void) {
+    let chain = &*CHAIN.load(Ordering::Acquire);
+    if let Some(handler) = chain.handler {
+        if handler(&*info, context) {
+            return;
+        }
+    }
+    if let Some(ref mps) = chain.mps {
+        if is_mps_fault(&*info) {
+            forward(mps, signal, info, context);
+            return;
+        }
+    }
+    forward(&chain.previous, signal, info, context);
+}
+/// The `si_code` of a fault caused by memory protection
+///
+/// Not exported by the libc crate (see `<asm-generic/siginfo.h>`)
+const SEGV_ACCERR: c_int = 2;
+/// Check whether a fault was in memory managed by one of the arenas
+unsafe fn is_mps_fault(info: &siginfo_t) -> bool {
+    if info.si_code != SEGV_ACCERR {
+        return false;
+    }
+    if UNTRACKED_ARENAS.load(Ordering::Acquire) > 0 {
+        return true;
+    }
+    let addr = info.si_addr();
+    ACTIVE_CHECKS.fetch_add(1, Ordering::SeqCst);
+    let found = ARENAS.iter()
+        .map(|slot| slot.load(Ordering::SeqCst))
+        .any(|arena| !arena.is_null() && mps_sys::mps_arena_has_addr(arena as mps_arena_t, addr) != 0);
+    ACTIVE_CHECKS.fetch_sub(1, Ordering::SeqCst);
+    found
+}
+/// Pass a signal on to the specified handler
+unsafe fn forward(action: &sigaction, signal: c_int, info: *mut siginfo_t, context: *mut c_void) {
+    if action.sa_sigaction == libc::SIG_DFL || action.sa_sigaction == libc::SIG_IGN {
+        // Restore the default action, so the fault kills the process when it happens again
+        libc::signal(signal, libc::SIG_DFL);
+    } else if action.sa_flags & libc::SA_SIGINFO != 0 {
+        let handler: unsafe extern "C" fn(c_int, *mut siginfo_t, *mut c_void) = mem::transmute(action.sa_sigaction);
+        handler(signal, info, context);
+    } else {
+        let handler: unsafe extern "C" fn(c_int) = mem::transmute(action.sa_sigaction);
+        handler(signal);
+    }
+}
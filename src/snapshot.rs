@@ -0,0 +1,373 @@
+//! Heap snapshots, summarizing the objects in an arena by class
+//!
+//! A snapshot is captured by walking every object in the arena's formatted pools,
+//! using the [ClassFormatMethods](crate::format::ClassFormatMethods) of their formats.
+//! Snapshots can be saved in a simple line-based text format
+//! and compared with [HeapSnapshot::diff] (or the `mps-inspect` tool).
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use mps_sys::{mps_addr_t, mps_fmt_t, mps_pool_t};
+use thiserror::Error;
+
+use crate::arena::Arena;
+use crate::registry::{FormatMethods, HandleKind};
+use crate::stats::{ArenaStats, PoolStats};
+
+/// The first line of a snapshot in the text format
+const HEADER: &str = "# mps heap snapshot v1";
+/// The pool classes that can appear in a snapshot
+const POOL_CLASSES: &[&str] = &["ams", "amc", "awl", "mvff"];
+
+/// The objects of a single class in a [HeapSnapshot]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClassStats {
+    /// The name of the class
+    pub name: String,
+    /// The number of objects
+    pub count: u64,
+    /// The total size of the objects (in bytes)
+    pub size: u64
+}
+
+/// A summary of the objects in an arena, along with its [ArenaStats]
+#[derive(Debug, Clone)]
+pub struct HeapSnapshot {
+    /// The statistics of the arena when the snapshot was taken
+    pub stats: ArenaStats,
+    /// The classes of objects, in decreasing order of total size
+    pub classes: Vec<ClassStats>
+}
+impl HeapSnapshot {
+    /// Capture a snapshot of the specified arena
+    ///
+    /// Objects are grouped by the pointer returned from their format's class method,
+    /// and `class_name` is used to give each class a name.
+    /// Objects whose format doesn't have a class method (or whose class is null)
+    /// are counted as `"<unknown>"`.
+    ///
+    /// The arena is parked during the walk,
+    /// and is put back into its previous [state](crate::arena::ArenaState) afterwards.
+    ///
+    /// Corresponds to C function [mps_arena_formatted_objects_walk](https://www.ravenbrook.com/project/mps/master/manual/html/topic/deprecated.html#c.mps_arena_formatted_objects_walk)
+    pub fn capture<F>(arena: &Arena, mut class_name: F) -> HeapSnapshot
+        where F: FnMut(*mut c_void) -> String {
+        let previous_state = arena.state();
+        // NOTE: Hold the registry lock, so no format is destroyed during the walk
+        let walk = arena.registry().with_entries(|entries| {
+            let mut walk = Walk {
+                formats: entries.iter()
+                    .filter(|entry| entry.info.kind == HandleKind::Format)
+                    .filter_map(|entry| Some((entry.raw as mps_fmt_t, entry.format_methods?)))
+                    .collect(),
+                classes: HashMap::new()
+            };
+            arena.park();
+            unsafe {
+                ::mps_sys::mps_arena_formatted_objects_walk(
+                    arena.as_raw(), Some(step),
+                    &mut walk as *mut Walk as *mut c_void, 0
+                );
+            }
+            arena.control().restore(previous_state);
+            walk
+        });
+        let mut classes: Vec<ClassStats> = walk.classes.into_iter()
+            .map(|(class, (count, size))| ClassStats {
+                name: if class.is_null() { "<unknown>".into() } else { class_name(class) },
+                count, size
+            })
+            .collect();
+        sort_classes(&mut classes);
+        HeapSnapshot { stats: arena.stats(), classes }
+    }
+    /// Compare this snapshot with a later one
+    ///
+    /// Classes are matched by name, and sorted by the (absolute) change in size.
+    pub fn diff(&self, later: &HeapSnapshot) -> Vec<ClassDiff> {
+        let mut diffs: HashMap<&str, ClassDiff> = HashMap::new();
+        for (class, before) in self.classes.iter().map(|class| (class, true))
+            .chain(later.classes.iter().map(|class| (class, false))) {
+            let diff = diffs.entry(class.name.as_str()).or_insert_with(|| ClassDiff {
+                name: class.name.clone(),
+                count_change: 0,
+                size_change: 0
+            });
+            let (count, size) = (class.count as i64, class.size as i64);
+            if before {
+                diff.count_change -= count;
+                diff.size_change -= size;
+            } else {
+                diff.count_change += count;
+                diff.size_change += size;
+            }
+        }
+        let mut diffs: Vec<ClassDiff> = diffs.into_values()
+            .filter(|diff| diff.count_change != 0 || diff.size_change != 0)
+            .collect();
+        diffs.sort_by(|a, b| b.size_change.abs().cmp(&a.size_change.abs())
+            .then_with(|| a.name.cmp(&b.name)));
+        diffs
+    }
+}
+/// The change in a class between two snapshots, from [HeapSnapshot::diff]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClassDiff {
+    /// The name of the class
+    pub name: String,
+    /// The change in the number of objects
+    pub count_change: i64,
+    /// The change in the total size of the objects (in bytes)
+    pub size_change: i64
+}
+
+/// Writes the snapshot in its text format, which can be read back with [str::parse]
+impl Display for HeapSnapshot {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let stats = &self.stats;
+        writeln!(f, "{}", HEADER)?;
+        writeln!(
+            f, "arena {} {} {} {}",
+            stats.committed, stats.spare_committed,
+            stats.commit_limit, stats.moved_collections
+        )?;
+        for pool in &stats.pools {
+            write!(f, "pool {} {} {} {}", pool.id, pool.class, pool.total_size, pool.free_size)?;
+            if let Some(ref name) = pool.name {
+                write!(f, " {}", single_line(name))?;
+            }
+            writeln!(f)?;
+        }
+        for class in &self.classes {
+            writeln!(f, "class {} {} {}", class.count, class.size, single_line(&class.name))?;
+        }
+        Ok(())
+    }
+}
+impl FromStr for HeapSnapshot {
+    type Err = ParseSnapshotError;
+    fn from_str(text: &str) -> Result<Self, ParseSnapshotError> {
+        let mut lines = text.lines().enumerate()
+            .map(|(index, line)| (index + 1, line))
+            .filter(|&(_, line)| !line.trim().is_empty());
+        match lines.next() {
+            Some((_, line)) if line.trim() == HEADER => {},
+            _ => return Err(ParseSnapshotError::MissingHeader)
+        }
+        let mut arena = None;
+        let mut pools = Vec::new();
+        let mut classes = Vec::new();
+        for (line_number, line) in lines {
+            let invalid = || ParseSnapshotError::InvalidLine { line_number };
+            let mut words = line.splitn(2, ' ');
+            let kind = words.next().unwrap();
+            let rest = words.next().unwrap_or("");
+            match kind {
+                "arena" => {
+                    let fields = parse_numbers::<usize>(rest, 4).ok_or_else(invalid)?;
+                    arena = Some((fields[0], fields[1], fields[2], fields[3]));
+                },
+                "pool" => {
+                    let fields: Vec<&str> = rest.splitn(5, ' ').collect();
+                    if fields.len() < 4 {
+                        return Err(invalid());
+                    }
+                    let class = POOL_CLASSES.iter().copied()
+                        .find(|&known| known == fields[1])
+                        .unwrap_or("unknown");
+                    pools.push(PoolStats {
+                        id: fields[0].parse().map_err(|_| invalid())?,
+                        class,
+                        name: fields.get(4).map(|&name| name.into()),
+                        total_size: fields[2].parse().map_err(|_| invalid())?,
+                        free_size: fields[3].parse().map_err(|_| invalid())?
+                    });
+                },
+                "class" => {
+                    let fields: Vec<&str> = rest.splitn(3, ' ').collect();
+                    if fields.len() < 3 {
+                        return Err(invalid());
+                    }
+                    classes.push(ClassStats {
+                        count: fields[0].parse().map_err(|_| invalid())?,
+                        size: fields[1].parse().map_err(|_| invalid())?,
+                        name: fields[2].into()
+                    });
+                },
+                _ if kind.starts_with('#') => {},
+                _ => return Err(invalid())
+            }
+        }
+        let (committed, spare_committed, commit_limit, moved_collections) = arena
+            .ok_or(ParseSnapshotError::MissingArena)?;
+        sort_classes(&mut classes);
+        Ok(HeapSnapshot {
+            stats: ArenaStats {
+                committed, spare_committed,
+                commit_limit, moved_collections,
+                pools
+            },
+            classes
+        })
+    }
+}
+
+/// An error parsing a [HeapSnapshot] from its text format
+#[derive(Error, Debug)]
+pub enum ParseSnapshotError {
+    /// The first line isn't the snapshot header
+    #[error("Missing snapshot header")]
+    MissingHeader,
+    /// There's no line with the arena's statistics
+    #[error("Missing arena statistics")]
+    MissingArena,
+    /// A line couldn't be parsed
+    #[error("Invalid snapshot on line {line_number}")]
+    InvalidLine {
+        /// The (one-based) number of the invalid line
+        line_number: usize
+    }
+}
+
+/// The state of a heap walk, passed through the closure pointer
+struct Walk {
+    formats: HashMap<mps_fmt_t, FormatMethods>,
+    /// The count and total size of the objects in each class
+    classes: HashMap<*mut c_void, (u64, u64)>
+}
+unsafe extern "C" fn step(addr: mps_addr_t, fmt: mps_fmt_t, _pool: mps_pool_t, p: *mut c_void, _s: usize) {
+    let walk = &mut *(p as *mut Walk);
+    let methods = match walk.formats.get(&fmt) {
+        Some(methods) => *methods,
+        None => return
+    };
+    let size = match methods.skip {
+        Some(skip) => skip(addr) as usize - addr as usize,
+        None => return
+    };
+    let class = match methods.class {
+        Some(class) => class(addr),
+        None => std::ptr::null_mut()
+    };
+    let entry = walk.classes.entry(class).or_insert((0, 0));
+    entry.0 += 1;
+    entry.1 += size as u64;
+}
+
+fn sort_classes(classes: &mut [ClassStats]) {
+    classes.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+}
+fn single_line(s: &str) -> String {
+    s.replace(['\n', '\r'], " ")
+}
+fn parse_numbers<T: FromStr>(s: &str, expected: usize) -> Option<Vec<T>> {
+    let numbers = s.split(' ')
+        .map(|word| word.parse().ok())
+        .collect::<Option<Vec<T>>>()?;
+    if numbers.len() == expected { Some(numbers) } else { None }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn snapshot(classes: &[(&str, u64, u64)]) -> HeapSnapshot {
+        let mut classes: Vec<ClassStats> = classes.iter()
+            .map(|&(name, count, size)| ClassStats { name: name.into(), count, size })
+            .collect();
+        sort_classes(&mut classes);
+        HeapSnapshot {
+            stats: ArenaStats {
+                committed: 4096, spare_committed: 1024,
+                commit_limit: 1 << 20, moved_collections: 3,
+                pools: vec![
+                    PoolStats { id: 0, class: "amc", name: Some("objects".into()), total_size: 2048, free_size: 512 },
+                    PoolStats { id: 2, class: "mvff", name: None, total_size: 1024, free_size: 0 }
+                ]
+            },
+            classes
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let original = snapshot(&[("Cons", 10, 320), ("String", 2, 64)]);
+        let text = original.to_string();
+        let parsed: HeapSnapshot = text.parse().unwrap();
+        assert_eq!(parsed.to_string(), text);
+        assert_eq!(parsed.classes, original.classes);
+        assert_eq!(parsed.stats.committed, 4096);
+        assert_eq!(parsed.stats.moved_collections, 3);
+        assert_eq!(parsed.stats.pools[0].name.as_deref(), Some("objects"));
+        assert_eq!(parsed.stats.pools[1].name, None);
+    }
+
+    #[test]
+    fn names_with_spaces() {
+        let mut original = snapshot(&[("Vec<(u8, u8)>", 1, 48)]);
+        original.stats.pools[0].name = Some("young objects".into());
+        let parsed: HeapSnapshot = original.to_string().parse().unwrap();
+        assert_eq!(parsed.classes[0].name, "Vec<(u8, u8)>");
+        assert_eq!(parsed.stats.pools[0].name.as_deref(), Some("young objects"));
+    }
+
+    #[test]
+    fn multi_line_names() {
+        let original = snapshot(&[("first\nsecond", 1, 16)]);
+        let parsed: HeapSnapshot = original.to_string().parse().unwrap();
+        assert_eq!(parsed.classes[0].name, "first second");
+    }
+
+    #[test]
+    fn unknown_pool_class() {
+        let text = format!("{}\narena 1 2 3 4\npool 7 lo 100 50 custom\n", HEADER);
+        let parsed: HeapSnapshot = text.parse().unwrap();
+        assert_eq!(parsed.stats.pools[0].id, 7);
+        assert_eq!(parsed.stats.pools[0].class, "unknown");
+        assert_eq!(parsed.stats.pools[0].name.as_deref(), Some("custom"));
+    }
+
+    #[test]
+    fn invalid_snapshots() {
+        assert!(matches!("".parse::<HeapSnapshot>(), Err(ParseSnapshotError::MissingHeader)));
+        assert!(matches!("arena 1 2 3 4".parse::<HeapSnapshot>(), Err(ParseSnapshotError::MissingHeader)));
+        assert!(matches!(HEADER.parse::<HeapSnapshot>(), Err(ParseSnapshotError::MissingArena)));
+        let text = format!("{}\n# comment\narena 1 2 3 4\nclass 1 x\n", HEADER);
+        assert!(matches!(
+            text.parse::<HeapSnapshot>(),
+            Err(ParseSnapshotError::InvalidLine { line_number: 4 })
+        ));
+        let text = format!("{}\narena 1 2 3\n", HEADER);
+        assert!(matches!(
+            text.parse::<HeapSnapshot>(),
+            Err(ParseSnapshotError::InvalidLine { line_number: 2 })
+        ));
+    }
+
+    #[test]
+    fn parsed_classes_are_sorted() {
+        let text = format!("{}\narena 1 2 3 4\nclass 1 16 small\nclass 2 64 b\nclass 4 64 a\n", HEADER);
+        let parsed: HeapSnapshot = text.parse().unwrap();
+        let names: Vec<&str> = parsed.classes.iter().map(|class| class.name.as_str()).collect();
+        assert_eq!(names, ["a", "b", "small"]);
+    }
+
+    #[test]
+    fn diff_ordering() {
+        let before = snapshot(&[("Cons", 10, 320), ("String", 4, 128), ("Same", 1, 8), ("Gone", 1, 16)]);
+        let after = snapshot(&[("Cons", 12, 384), ("String", 1, 32), ("Same", 1, 8), ("New", 2, 64)]);
+        let diffs = before.diff(&after);
+        let summary: Vec<(&str, i64, i64)> = diffs.iter()
+            .map(|diff| (diff.name.as_str(), diff.count_change, diff.size_change))
+            .collect();
+        // Sorted by the absolute change in size, then by name
+        assert_eq!(summary, [
+            ("String", -3, -96),
+            ("Cons", 2, 64),
+            ("New", 2, 64),
+            ("Gone", -1, -16)
+        ]);
+    }
+}
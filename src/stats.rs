@@ -0,0 +1,200 @@
+//! Snapshots of arena and pool statistics
+//!
+//! Use [Arena::stats](crate::arena::Arena::stats) to take a snapshot,
+//! and [ArenaStats::prometheus] to format it for a `/metrics` endpoint.
+use std::fmt::{self, Display, Formatter, Write};
+
+use mps_sys::{mps_arena_t, mps_pool_t};
+
+use crate::registry::{Entry, HandleKind};
+
+/// A snapshot of the statistics of an [Arena](crate::arena::Arena)
+/// and all of its pools
+#[derive(Debug, Clone)]
+pub struct ArenaStats {
+    /// The total committed memory (see [Arena::committed](crate::arena::Arena::committed))
+    pub committed: usize,
+    /// The committed memory that isn't in use
+    /// (see [Arena::spare_committed](crate::arena::Arena::spare_committed))
+    pub spare_committed: usize,
+    /// The commit limit (see [Arena::commit_limit](crate::arena::Arena::commit_limit))
+    pub commit_limit: usize,
+    /// The number of collections that might have moved objects
+    /// (see [Arena::moved_collections](crate::arena::Arena::moved_collections))
+    pub moved_collections: usize,
+    /// The statistics of each pool in the arena, in order of creation
+    pub pools: Vec<PoolStats>
+}
+impl ArenaStats {
+    /// Read the statistics of a raw arena, along with the pools in the specified registry entries
+    pub(crate) unsafe fn collect(raw: mps_arena_t, entries: &[Entry]) -> ArenaStats {
+        let pools = entries.iter()
+            .filter(|entry| entry.info.kind == HandleKind::Pool)
+            .map(|entry| PoolStats {
+                id: entry.info.id,
+                class: entry.info.class.unwrap_or("unknown"),
+                name: entry.info.name.clone(),
+                total_size: mps_sys::mps_pool_total_size(entry.raw as mps_pool_t),
+                free_size: mps_sys::mps_pool_free_size(entry.raw as mps_pool_t)
+            })
+            .collect();
+        ArenaStats {
+            committed: mps_sys::mps_arena_committed(raw),
+            spare_committed: mps_sys::mps_arena_spare_committed(raw),
+            commit_limit: mps_sys::mps_arena_commit_limit(raw),
+            moved_collections: mps_sys::mps_collections(raw),
+            pools
+        }
+    }
+    /// Format these statistics in the
+    /// [Prometheus text exposition format](https://prometheus.io/docs/instrumenting/exposition_formats/#text-based-format)
+    ///
+    /// Pools are distinguished by their `pool` (id) and `class` labels,
+    /// along with a `name` label if they were given a name.
+    #[inline]
+    pub fn prometheus(&self) -> PrometheusFormat<'_> {
+        PrometheusFormat { stats: self }
+    }
+}
+
+/// A snapshot of the statistics of a single [Pool](crate::pools::Pool)
+#[derive(Debug, Clone)]
+pub struct PoolStats {
+    /// A number identifying the pool within its arena
+    ///
+    /// Ids are assigned in order of creation and are never reused.
+    pub id: usize,
+    /// The short name of the pool class (like `"amc"` or `"mvff"`)
+    pub class: &'static str,
+    /// The name given to the pool by its builder (if any)
+    pub name: Option<String>,
+    /// The total memory managed by the pool (see [Pool::total_size](crate::pools::Pool::total_size))
+    pub total_size: usize,
+    /// The memory managed by the pool that isn't in use
+    /// (see [Pool::free_size](crate::pools::Pool::free_size))
+    pub free_size: usize
+}
+
+/// Displays [ArenaStats] in the Prometheus text exposition format
+///
+/// Created by [ArenaStats::prometheus]
+pub struct PrometheusFormat<'a> {
+    stats: &'a ArenaStats
+}
+impl PrometheusFormat<'_> {
+    fn header(f: &mut Formatter, name: &str, kind: &str, help: &str) -> fmt::Result {
+        writeln!(f, "# HELP {} {}", name, help)?;
+        writeln!(f, "# TYPE {} {}", name, kind)
+    }
+    fn pool_metric(&self, f: &mut Formatter, name: &str, help: &str, value: fn(&PoolStats) -> usize) -> fmt::Result {
+        Self::header(f, name, "gauge", help)?;
+        for pool in &self.stats.pools {
+            write!(f, "{}{{pool=\"{}\",class=\"{}\"", name, pool.id, pool.class)?;
+            if let Some(ref pool_name) = pool.name {
+                f.write_str(",name=\"")?;
+                write_label_value(f, pool_name)?;
+                f.write_str("\"")?;
+            }
+            writeln!(f, "}} {}", value(pool))?;
+        }
+        Ok(())
+    }
+}
+impl Display for PrometheusFormat<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let stats = self.stats;
+        let arena_metrics = [
+            ("mps_arena_committed_bytes", "gauge", "Total memory committed by the arena", stats.committed),
+            ("mps_arena_spare_committed_bytes", "gauge", "Committed memory that is not in use", stats.spare_committed),
+            ("mps_arena_commit_limit_bytes", "gauge", "Maximum memory the arena may commit", stats.commit_limit),
+            ("mps_arena_moved_collections_total", "counter", "Collections in which objects might have moved", stats.moved_collections),
+        ];
+        for &(name, kind, help, value) in &arena_metrics {
+            Self::header(f, name, kind, help)?;
+            writeln!(f, "{} {}", name, value)?;
+        }
+        self.pool_metric(f, "mps_pool_total_size_bytes", "Total memory managed by the pool", |pool| pool.total_size)?;
+        self.pool_metric(f, "mps_pool_free_size_bytes", "Memory managed by the pool that is not in use", |pool| pool.free_size)?;
+        Ok(())
+    }
+}
+
+/// Write a label value, escaping backslashes, quotes and newlines
+fn write_label_value(f: &mut Formatter, value: &str) -> fmt::Result {
+    for c in value.chars() {
+        match c {
+            '\\' => f.write_str("\\\\")?,
+            '"' => f.write_str("\\\"")?,
+            '\n' => f.write_str("\\n")?,
+            c => f.write_char(c)?
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stats(pools: Vec<PoolStats>) -> ArenaStats {
+        ArenaStats {
+            committed: 4096,
+            spare_committed: 1024,
+            commit_limit: 1 << 20,
+            moved_collections: 3,
+            pools
+        }
+    }
+
+    #[test]
+    fn arena_metrics() {
+        assert_eq!(stats(Vec::new()).prometheus().to_string(), "\
+# HELP mps_arena_committed_bytes Total memory committed by the arena
+# TYPE mps_arena_committed_bytes gauge
+mps_arena_committed_bytes 4096
+# HELP mps_arena_spare_committed_bytes Committed memory that is not in use
+# TYPE mps_arena_spare_committed_bytes gauge
+mps_arena_spare_committed_bytes 1024
+# HELP mps_arena_commit_limit_bytes Maximum memory the arena may commit
+# TYPE mps_arena_commit_limit_bytes gauge
+mps_arena_commit_limit_bytes 1048576
+# HELP mps_arena_moved_collections_total Collections in which objects might have moved
+# TYPE mps_arena_moved_collections_total counter
+mps_arena_moved_collections_total 3
+# HELP mps_pool_total_size_bytes Total memory managed by the pool
+# TYPE mps_pool_total_size_bytes gauge
+# HELP mps_pool_free_size_bytes Memory managed by the pool that is not in use
+# TYPE mps_pool_free_size_bytes gauge
+");
+    }
+
+    #[test]
+    fn pool_metrics() {
+        let output = stats(vec![
+            PoolStats { id: 0, class: "amc", name: None, total_size: 8192, free_size: 512 },
+            PoolStats { id: 2, class: "mvff", name: Some("strings".into()), total_size: 65536, free_size: 0 }
+        ]).prometheus().to_string();
+        let lines: Vec<&str> = output.lines().filter(|line| line.starts_with("mps_pool_")).collect();
+        assert_eq!(lines, [
+            "mps_pool_total_size_bytes{pool=\"0\",class=\"amc\"} 8192",
+            "mps_pool_total_size_bytes{pool=\"2\",class=\"mvff\",name=\"strings\"} 65536",
+            "mps_pool_free_size_bytes{pool=\"0\",class=\"amc\"} 512",
+            "mps_pool_free_size_bytes{pool=\"2\",class=\"mvff\",name=\"strings\"} 0",
+        ]);
+    }
+
+    #[test]
+    fn label_escaping() {
+        let output = stats(vec![
+            PoolStats {
+                id: 1, class: "ams", name: Some("a \"quoted\"\\path\nwith newline".into()),
+                total_size: 0, free_size: 0
+            }
+        ]).prometheus().to_string();
+        assert!(output.contains(
+            "mps_pool_total_size_bytes{pool=\"1\",class=\"ams\",name=\"a \\\"quoted\\\"\\\\path\\nwith newline\"} 0\n"
+        ), "{}", output);
+        // Every sample is on its own line
+        assert_eq!(output.lines().filter(|line| line.starts_with("mps_pool_")).count(), 2);
+    }
+}
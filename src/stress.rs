@@ -0,0 +1,218 @@
+//! A test mode that collects (and verifies the heap) while allocating
+//!
+//! Missing roots usually only cause crashes when a collection happens at just the wrong time.
+//! Giving an [AllocationPoint](crate::alloc::AllocationPoint) a [StressMode]
+//! forces a full collection every few allocations, so these bugs show up quickly and reproducibly.
+//!
+//! The stress mode can also verify the heap after every commit,
+//! by walking every object (in pools with a format) and scanning it.
+//! Verification fails if any reference into one of those pools doesn't point to a live object,
+//! which means the object it pointed to was collected (a missing root)
+//! or moved without the reference being fixed.
+//! This works for both AMS and AMC pools.
+//!
+//! Scanning uses the same recording scan state as the [format_check](crate::format_check) module,
+//! so this requires the `stress` feature (which implies `format-check`).
+//! Needless to say, this is extremely slow.
+use std::collections::{HashMap, HashSet};
+use std::ffi::c_void;
+use std::marker::PhantomData;
+
+use mps_sys::{mps_addr_t, mps_arena_t, mps_fmt_t, mps_pool_t, mps_res_t};
+use thiserror::Error;
+
+use crate::arena::{Arena, ArenaControl};
+use crate::registry::{FormatMethods, HandleKind, Registry};
+
+/// Forces collections (and optionally verifies the heap) while allocating
+///
+/// Set with [AllocationPoint::set_stress_mode](crate::alloc::AllocationPoint::set_stress_mode).
+/// The `'arena` lifetime borrows the arena the mode collects,
+/// so the mode can't outlive it:
+///
+/// ```compile_fail
+/// # use mps::arena::VirtualMemoryArenaClass;
+/// # use mps::stress::StressMode;
+/// let mode = {
+///     let arena = VirtualMemoryArenaClass::get().builder().build().unwrap();
+///     StressMode::new(&arena, 1)
+/// };
+/// ```
+pub struct StressMode<'arena> {
+    control: ArenaControl,
+    registry: Registry,
+    marker: PhantomData<&'arena Arena>,
+    interval: usize,
+    verify: bool,
+    allocations: usize
+}
+impl<'arena> StressMode<'arena> {
+    /// Force a full collection of the specified arena
+    /// after every `interval` allocations
+    ///
+    /// An interval of zero never collects (which is only useful with [StressMode::verify]).
+    /// The arena is put back into its previous [state](Arena::state) after each collection.
+    #[inline]
+    pub fn new(arena: &'arena Arena, interval: usize) -> StressMode<'arena> {
+        StressMode {
+            control: arena.control().clone(),
+            registry: arena.registry().clone(),
+            marker: PhantomData,
+            interval,
+            verify: false,
+            allocations: 0
+        }
+    }
+    /// Verify the heap after every commit,
+    /// panicking if there are any [HeapError]s
+    #[inline]
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+    /// The number of allocations committed in this mode so far
+    #[inline]
+    pub fn allocations(&self) -> usize {
+        self.allocations
+    }
+    /// Called after every successful commit
+    pub(crate) fn after_commit(&mut self) {
+        self.allocations += 1;
+        if self.interval != 0 && self.allocations.is_multiple_of(self.interval) {
+            let state = self.control.state();
+            self.control.collect();
+            self.control.restore(state);
+        }
+        if self.verify {
+            let errors = unsafe { verify(&self.control, &self.registry) };
+            if !errors.is_empty() {
+                let errors = errors.iter().map(|error| format!("  {}", error))
+                    .collect::<Vec<_>>().join("\n");
+                panic!("Heap verification failed after {} allocations:\n{}", self.allocations, errors);
+            }
+        }
+    }
+}
+
+/// Verify the heap of the specified arena, returning any errors that were found
+///
+/// See the [module docs](self) for what is checked.
+/// The arena is parked during the check, and put back into its previous [state](Arena::state) afterwards.
+pub fn verify_heap(arena: &Arena) -> Vec<HeapError> {
+    unsafe { verify(arena.control(), arena.registry()) }
+}
+
+/// A problem found by verifying the heap
+#[derive(Error, Debug)]
+pub enum HeapError {
+    /// A reference points into a pool, but not to a live object
+    #[error("object {object:?} refers to {reference:?}, which isn't a live object")]
+    Dangling {
+        /// The object holding the reference
+        object: *mut c_void,
+        /// The reference to the dead object
+        reference: *mut c_void
+    },
+    /// A reference points to an object that has been moved
+    #[error("object {object:?} refers to {reference:?}, which has been forwarded to {new:?}")]
+    Forwarded {
+        /// The object holding the reference
+        object: *mut c_void,
+        /// The reference to the old location
+        reference: *mut c_void,
+        /// The new location of the object
+        new: *mut c_void
+    },
+    /// Scanning an object failed
+    #[error("scan failed for object {object:?} with code {code}")]
+    ScanFailed {
+        /// The object that was scanned
+        object: *mut c_void,
+        /// The error code returned by the scan method
+        code: mps_res_t
+    }
+}
+
+/// The state of a heap walk, passed through the closure pointer
+struct Walk {
+    formats: HashMap<mps_fmt_t, FormatMethods>,
+    /// The pools that contain walked objects
+    pools: HashSet<mps_pool_t>,
+    objects: HashSet<mps_addr_t>,
+    /// Each object along with the references it holds
+    references: Vec<(mps_addr_t, Vec<*mut c_void>)>,
+    errors: Vec<HeapError>
+}
+unsafe fn verify(control: &ArenaControl, registry: &Registry) -> Vec<HeapError> {
+    let arena = control.as_raw();
+    let previous_state = control.state();
+    // NOTE: Hold the registry lock, so no format (or pool) is destroyed during the check
+    registry.with_entries(|entries| {
+        let mut walk = Walk {
+            formats: entries.iter()
+                .filter(|entry| entry.info.kind == HandleKind::Format)
+                .filter_map(|entry| Some((entry.raw as mps_fmt_t, entry.format_methods?)))
+                .collect(),
+            pools: HashSet::new(),
+            objects: HashSet::new(),
+            references: Vec::new(),
+            errors: Vec::new()
+        };
+        control.park();
+        mps_sys::mps_arena_formatted_objects_walk(
+            arena, Some(step),
+            &mut walk as *mut Walk as *mut c_void, 0
+        );
+        for &(object, ref references) in &walk.references {
+            for &reference in references {
+                if let Some(error) = check_reference(arena, &walk, object, reference) {
+                    walk.errors.push(error);
+                }
+            }
+        }
+        control.restore(previous_state);
+        walk.errors
+    })
+}
+unsafe extern "C" fn step(addr: mps_addr_t, fmt: mps_fmt_t, pool: mps_pool_t, p: *mut c_void, _s: usize) {
+    let walk = &mut *(p as *mut Walk);
+    let methods = match walk.formats.get(&fmt) {
+        Some(methods) => *methods,
+        None => return
+    };
+    let (scan, skip) = match (methods.scan, methods.skip) {
+        (Some(scan), Some(skip)) => (scan, skip),
+        _ => return
+    };
+    walk.pools.insert(pool);
+    walk.objects.insert(addr);
+    let limit = skip(addr);
+    let (res, references) = crate::format_check::record_scan(|ss| scan(ss, addr, limit));
+    if res != mps_sys::MPS_RES_OK as mps_res_t {
+        walk.errors.push(HeapError::ScanFailed { object: addr, code: res });
+    }
+    walk.references.push((addr, references));
+}
+/// Check that a reference (held by the specified object) points to a live object
+unsafe fn check_reference(arena: mps_arena_t, walk: &Walk, object: mps_addr_t, reference: *mut c_void) -> Option<HeapError> {
+    if reference.is_null() || walk.objects.contains(&reference) {
+        return None;
+    }
+    let mut pool: mps_pool_t = std::ptr::null_mut();
+    if mps_sys::mps_arena_has_addr(arena, reference) == 0
+        || mps_sys::mps_addr_pool(&mut pool, arena, reference) == 0
+        || !walk.pools.contains(&pool) {
+        // Not in a pool that we walked
+        return None;
+    }
+    let mut fmt: mps_fmt_t = std::ptr::null_mut();
+    if mps_sys::mps_addr_fmt(&mut fmt, arena, reference) != 0 {
+        if let Some(is_forwarded) = walk.formats.get(&fmt).and_then(|methods| methods.is_forwarded) {
+            let new = is_forwarded(reference);
+            if !new.is_null() {
+                return Some(HeapError::Forwarded { object, reference, new });
+            }
+        }
+    }
+    Some(HeapError::Dangling { object, reference })
+}
@@ -0,0 +1,639 @@
+//! Interface to the MPS [telemetry](https://www.ravenbrook.com/project/mps/master/manual/html/topic/telemetry.html) system
+//!
+//! The MPS can write a binary stream of events to a file
+//! (named by the `MPS_TELEMETRY_FILENAME` environment variable, `mpsio.log` by default).
+//! The categories of events that are written can be controlled by the `MPS_TELEMETRY_CONTROL`
+//! environment variable, or at runtime by [Arena::enable_telemetry](crate::arena::Arena::enable_telemetry).
+//! The stream can then be read with an [EventReader].
+//!
+//! Labels attach human-readable names to addresses (like pools and formats)
+//! in the telemetry stream.
+//! Handles created by this crate are labelled automatically
+//! when they're given a name by their builders.
+use std::collections::HashMap;
+use std::ffi::{CString, CStr};
+use std::fs::File;
+use std::io::{self, Read, BufReader};
+use std::ops::BitOr;
+use std::os::raw::c_char;
+use std::path::Path;
+
+use mps_sys::{
+    mps_label_t, mps_addr_t, mps_word_t, mps_sys_event_layout_s, mps_sys_event_s,
+    mps_sys_events, mps_sys_event_count, mps_sys_event_layout
+};
+
+/// A string that has been interned in the telemetry stream
+///
+/// Created by [intern]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Label(mps_label_t);
+impl Label {
+    /// The raw value of the label
+    #[inline]
+    pub fn as_raw(self) -> mps_label_t {
+        self.0
+    }
+}
+
+/// Register the specified string in the telemetry stream, returning a label for it.
+///
+/// Interior nul bytes are replaced with spaces.
+///
+/// Corresponds to C function [mps_telemetry_intern](https://www.ravenbrook.com/project/mps/master/manual/html/topic/telemetry.html#c.mps_telemetry_intern)
+pub fn intern(name: &str) -> Label {
+    let name = CString::new(name.replace('\0', " ")).unwrap();
+    // NOTE: The MPS copies the string into the event stream
+    Label(unsafe { ::mps_sys::mps_telemetry_intern(name.as_ptr()) })
+}
+
+/// Associate the label with the specified address in the telemetry stream
+///
+/// Corresponds to C function [mps_telemetry_label](https://www.ravenbrook.com/project/mps/master/manual/html/topic/telemetry.html#c.mps_telemetry_label)
+///
+/// ## Safety
+/// The address must be a valid MPS handle or client address.
+#[inline]
+pub unsafe fn label(addr: mps_addr_t, label: Label) {
+    ::mps_sys::mps_telemetry_label(addr, label.0)
+}
+
+/// Categories of telemetry events, which can be turned on and off
+/// with [Arena::enable_telemetry](crate::arena::Arena::enable_telemetry)
+///
+/// Corresponds to the event categories in the
+/// [telemetry docs](https://www.ravenbrook.com/project/mps/master/manual/html/topic/telemetry.html#event-categories)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct TelemetryCategories(mps_word_t);
+impl TelemetryCategories {
+    /// No events
+    pub const NONE: Self = TelemetryCategories(0);
+    /// Per space or arena
+    pub const ARENA: Self = TelemetryCategories(1 << 0);
+    /// Per pool
+    pub const POOL: Self = TelemetryCategories(1 << 1);
+    /// Per trace or scan (collections)
+    pub const TRACE: Self = TelemetryCategories(1 << 2);
+    /// Per segment
+    pub const SEGMENT: Self = TelemetryCategories(1 << 3);
+    /// Per reference or fix
+    pub const REFERENCE: Self = TelemetryCategories(1 << 4);
+    /// Per allocation, block, or object
+    pub const OBJECT: Self = TelemetryCategories(1 << 5);
+    /// User-invoked events (like labels)
+    pub const USER: Self = TelemetryCategories(1 << 6);
+    /// All of the categories
+    pub const ALL: Self = TelemetryCategories((1 << 7) - 1);
+    /// Create a set of categories from the raw bits
+    #[inline]
+    pub const fn from_bits(bits: mps_word_t) -> Self {
+        TelemetryCategories(bits)
+    }
+    /// The raw bits of this set of categories
+    #[inline]
+    pub const fn bits(self) -> mps_word_t {
+        self.0
+    }
+    /// Whether this includes all of the specified categories
+    #[inline]
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+impl BitOr for TelemetryCategories {
+    type Output = Self;
+    #[inline]
+    fn bitor(self, rhs: Self) -> Self {
+        TelemetryCategories(self.0 | rhs.0)
+    }
+}
+
+/// The value of an event parameter
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// A pointer to an MPS data structure (like a pool or trace)
+    Pointer(u64),
+    /// An address in the client's memory
+    Address(u64),
+    /// An unsigned word (usually a size or count)
+    Word(u64),
+    /// An unsigned integer
+    Unsigned(u64),
+    /// A floating point number
+    Double(f64),
+    /// A boolean
+    Bool(bool),
+    /// A string
+    String(String)
+}
+impl Value {
+    /// Get this value as an integer, if it is one
+    #[inline]
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::Pointer(val) | Value::Address(val) |
+            Value::Word(val) | Value::Unsigned(val) => Some(val),
+            Value::Bool(b) => Some(b as u64),
+            Value::Double(_) | Value::String(_) => None
+        }
+    }
+    /// Get this value as a floating point number, if it is one
+    #[inline]
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Double(d) => Some(d),
+            _ => None
+        }
+    }
+    /// Get this value as a string, if it is one
+    #[inline]
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref s) => Some(s),
+            _ => None
+        }
+    }
+}
+
+/// An event read from the telemetry stream
+#[derive(Debug, Clone)]
+pub struct Event {
+    /// The event code, which identifies the type of event
+    pub code: u16,
+    /// The name of the event (like `"TraceStart"`),
+    /// or `"Unknown"` if the code isn't known to this version of the MPS
+    pub name: &'static str,
+    /// The time the event occurred, in processor clock ticks
+    pub clock: u64,
+    /// The parameters of the event (in order), with their names
+    pub params: Vec<(&'static str, Value)>
+}
+impl Event {
+    /// Get the parameter with the specified name
+    #[inline]
+    pub fn param(&self, name: &str) -> Option<&Value> {
+        self.params.iter()
+            .find(|&&(param_name, _)| param_name == name)
+            .map(|(_, value)| value)
+    }
+    /// Look up a parameter by name, falling back to its position
+    fn lookup(&self, name: &str, index: usize) -> Option<&Value> {
+        self.param(name).or_else(|| self.params.get(index).map(|(_, value)| value))
+    }
+    fn lookup_u64(&self, name: &str, index: usize) -> Option<u64> {
+        self.lookup(name, index).and_then(Value::as_u64)
+    }
+    /// Interpret this event as one of the commonly used kinds of event
+    ///
+    /// Returns [EventKind::Other] if the event isn't one of them
+    /// (or doesn't have the expected parameters).
+    pub fn kind(&self) -> EventKind {
+        self.try_kind().unwrap_or(EventKind::Other)
+    }
+    fn try_kind(&self) -> Option<EventKind> {
+        Some(match self.name {
+            "EventInit" => EventKind::Init {
+                version: (
+                    self.lookup_u64("major", 0)?,
+                    self.lookup_u64("median", 1)?,
+                    self.lookup_u64("minor", 2)?
+                ),
+                clocks_per_second: self.lookup_u64("clocksPerSec", 6)?
+            },
+            "Intern" => EventKind::Intern {
+                id: self.lookup_u64("stringId", 0)?,
+                string: self.lookup("string", 1)?.as_str()?.into()
+            },
+            "Label" => EventKind::Label {
+                address: self.lookup_u64("address", 0)?,
+                id: self.lookup_u64("stringId", 1)?
+            },
+            "PoolInit" => EventKind::PoolCreate {
+                pool: self.lookup_u64("pool", 0)?,
+                arena: self.lookup_u64("arena", 1)?,
+                class: self.lookup_u64("poolClass", 2)?
+            },
+            "PoolFinish" => EventKind::PoolDestroy {
+                pool: self.lookup_u64("pool", 0)?
+            },
+            "PoolAlloc" => EventKind::Alloc {
+                pool: self.lookup_u64("pool", 0)?,
+                address: self.lookup_u64("pReturn", 1)?,
+                size: self.lookup_u64("size", 2)?
+            },
+            "PoolFree" => EventKind::Free {
+                pool: self.lookup_u64("pool", 0)?,
+                address: self.lookup_u64("old", 1)?,
+                size: self.lookup_u64("size", 2)?
+            },
+            "TraceStart" => EventKind::CollectionStart {
+                trace: self.lookup_u64("trace", 0)?
+            },
+            "TraceDestroy" => EventKind::CollectionEnd {
+                trace: self.lookup_u64("trace", 0)?
+            },
+            "TraceEndGen" => EventKind::GenerationEnd {
+                trace: self.lookup_u64("trace", 0)?,
+                generation: self.lookup_u64("gen", 1)?,
+                condemned: self.lookup_u64("condemned", 2)?,
+                forwarded: self.lookup_u64("forwarded", 3)?,
+                preserved: self.lookup_u64("preservedInPlace", 4)?
+            },
+            "ArenaPollBegin" => EventKind::PollBegin {
+                arena: self.lookup_u64("arena", 0)?
+            },
+            "ArenaPollEnd" => EventKind::PollEnd {
+                arena: self.lookup_u64("arena", 0)?
+            },
+            _ => return None
+        })
+    }
+}
+
+/// The commonly used kinds of [Event], with their parameters
+///
+/// Pointers and addresses are given as integers,
+/// since they refer to the memory of the process that wrote the stream.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventKind {
+    /// The start of the telemetry stream
+    Init {
+        /// The version of the event stream (major, median, minor)
+        version: (u64, u64, u64),
+        /// The number of clock ticks per second
+        clocks_per_second: u64
+    },
+    /// A string was [interned](intern)
+    Intern {
+        /// The id of the string
+        id: u64,
+        /// The interned string
+        string: String
+    },
+    /// An address was [labelled](label)
+    Label {
+        /// The address that was labelled
+        address: u64,
+        /// The id of the interned string
+        id: u64
+    },
+    /// A pool was created
+    PoolCreate {
+        /// The new pool
+        pool: u64,
+        /// The arena the pool belongs to
+        arena: u64,
+        /// The pool's class
+        class: u64
+    },
+    /// A pool was destroyed
+    PoolDestroy {
+        /// The destroyed pool
+        pool: u64
+    },
+    /// A block was allocated (directly from a pool)
+    Alloc {
+        /// The pool the block was allocated from
+        pool: u64,
+        /// The address of the block
+        address: u64,
+        /// The size of the block
+        size: u64
+    },
+    /// A block was freed
+    Free {
+        /// The pool the block belonged to
+        pool: u64,
+        /// The address of the block
+        address: u64,
+        /// The size of the block
+        size: u64
+    },
+    /// A collection (trace) started
+    CollectionStart {
+        /// The trace doing the collection
+        trace: u64
+    },
+    /// A collection (trace) finished
+    CollectionEnd {
+        /// The trace that did the collection
+        trace: u64
+    },
+    /// A collection finished condemning a generation
+    GenerationEnd {
+        /// The trace that did the collection
+        trace: u64,
+        /// The generation
+        generation: u64,
+        /// The size of the condemned objects
+        condemned: u64,
+        /// The size of the objects that survived by being copied
+        forwarded: u64,
+        /// The size of the objects that survived in place
+        preserved: u64
+    },
+    /// The arena started doing collection work
+    PollBegin {
+        /// The arena
+        arena: u64
+    },
+    /// The arena finished doing collection work
+    PollEnd {
+        /// The arena
+        arena: u64
+    },
+    /// Any other event
+    Other
+}
+
+struct ParamDefinition {
+    name: &'static str,
+    sort: u8,
+    offset: usize
+}
+struct EventDefinition {
+    name: &'static str,
+    params: Vec<ParamDefinition>
+}
+
+/// Reads [Event]s from a telemetry stream
+///
+/// The stream must have been written by a process using
+/// the same version of the MPS, on the same kind of machine.
+/// It doesn't need an arena, so it can be used offline on a captured log.
+pub struct EventReader<R: Read> {
+    reader: R,
+    layout: mps_sys_event_layout_s,
+    definitions: HashMap<u16, EventDefinition>,
+    buffer: Vec<u8>
+}
+impl EventReader<BufReader<File>> {
+    /// Open the telemetry log at the specified path
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(EventReader::new(BufReader::new(File::open(path)?)))
+    }
+}
+impl<R: Read> EventReader<R> {
+    /// Read events from the specified stream
+    pub fn new(reader: R) -> Self {
+        unsafe {
+            let events = std::slice::from_raw_parts(
+                mps_sys_events.as_ptr(),
+                mps_sys_event_count
+            );
+            EventReader::with_tables(reader, events, mps_sys_event_layout)
+        }
+    }
+    /// Read events using the specified event table and header layout
+    ///
+    /// ## Safety
+    /// The table must be laid out like the one in `mps-sys/src/events.c`,
+    /// with static names and a null-terminated list of parameters for each event.
+    unsafe fn with_tables(reader: R, events: &[mps_sys_event_s], layout: mps_sys_event_layout_s) -> Self {
+        let mut definitions = HashMap::new();
+        for event in events {
+            let mut params = Vec::new();
+            let mut param = event.params;
+            while !(*param).name.is_null() {
+                params.push(ParamDefinition {
+                    name: static_str((*param).name),
+                    sort: *(*param).sort as u8,
+                    offset: (*param).offset
+                });
+                param = param.add(1);
+            }
+            definitions.insert(event.code as u16, EventDefinition {
+                name: static_str(event.name),
+                params
+            });
+        }
+        EventReader {
+            reader, layout,
+            definitions, buffer: Vec::new()
+        }
+    }
+    /// Read the next event, returning `None` at the end of the stream
+    pub fn read_event(&mut self) -> io::Result<Option<Event>> {
+        let header_size = self.layout.header_size;
+        if header_size == 0 {
+            return Err(io::Error::other(
+                "The event definitions aren't available (mps-sys was built without the internal MPS headers)"
+            ));
+        }
+        self.buffer.resize(header_size, 0);
+        // NOTE: The stream can only end between events
+        let mut filled = 0;
+        while filled < header_size {
+            match self.reader.read(&mut self.buffer[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e)
+            }
+        }
+        let layout = &self.layout;
+        let code = read_uint(&self.buffer, layout.code_offset, layout.code_size)? as u16;
+        let size = read_uint(&self.buffer, layout.size_offset, layout.size_size)? as usize;
+        let clock = read_uint(&self.buffer, layout.clock_offset, layout.clock_size)?;
+        if size < header_size {
+            return Err(invalid_data(format!("Invalid size {} for event {:#x}", size, code)));
+        }
+        self.buffer.resize(size, 0);
+        self.reader.read_exact(&mut self.buffer[header_size..])?;
+        let definition = match self.definitions.get(&code) {
+            Some(definition) => definition,
+            None => return Ok(Some(Event { code, name: "Unknown", clock, params: Vec::new() }))
+        };
+        let mut params = Vec::with_capacity(definition.params.len());
+        for param in &definition.params {
+            let data = &self.buffer;
+            let value = match param.sort {
+                b'P' => Value::Pointer(read_uint(data, param.offset, layout.word_size)?),
+                b'A' => Value::Address(read_uint(data, param.offset, layout.word_size)?),
+                b'W' => Value::Word(read_uint(data, param.offset, layout.word_size)?),
+                b'U' => Value::Unsigned(read_uint(data, param.offset, layout.unsigned_size)?),
+                b'B' => Value::Bool(read_uint(data, param.offset, layout.bool_size)? != 0),
+                b'D' => {
+                    if layout.double_size != 8 {
+                        return Err(invalid_data(format!("Unsupported double size {}", layout.double_size)));
+                    }
+                    Value::Double(f64::from_bits(read_uint(data, param.offset, 8)?))
+                },
+                b'S' => {
+                    let bytes = data.get(param.offset..)
+                        .ok_or_else(|| invalid_data(format!("Truncated {} event", definition.name)))?;
+                    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+                    Value::String(String::from_utf8_lossy(&bytes[..len]).into_owned())
+                },
+                sort => return Err(invalid_data(format!(
+                    "Unknown parameter sort {:?} for {} event",
+                    sort as char, definition.name
+                )))
+            };
+            params.push((param.name, value));
+        }
+        Ok(Some(Event { code, name: definition.name, clock, params }))
+    }
+}
+impl<R: Read> Iterator for EventReader<R> {
+    type Item = io::Result<Event>;
+    #[inline]
+    fn next(&mut self) -> Option<io::Result<Event>> {
+        self.read_event().transpose()
+    }
+}
+
+/// Read all the events in the telemetry log at the specified path
+pub fn read_events<P: AsRef<Path>>(path: P) -> io::Result<Vec<Event>> {
+    EventReader::open(path)?.collect()
+}
+
+unsafe fn static_str(s: *const c_char) -> &'static str {
+    CStr::from_ptr(s).to_str().expect("Invalid event name")
+}
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+/// Read a native-endian unsigned integer of the specified size
+fn read_uint(data: &[u8], offset: usize, size: usize) -> io::Result<u64> {
+    let bytes = data.get(offset..offset + size)
+        .ok_or_else(|| invalid_data(format!("Truncated event field at {}", offset)))?;
+    Ok(match size {
+        1 => bytes[0] as u64,
+        2 => u16::from_ne_bytes([bytes[0], bytes[1]]) as u64,
+        4 => u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as u64,
+        8 => {
+            let mut buf = [0; 8];
+            buf.copy_from_slice(bytes);
+            u64::from_ne_bytes(buf)
+        },
+        _ => return Err(invalid_data(format!("Unsupported field size {}", size)))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use mps_sys::mps_sys_event_param_s;
+
+    use super::*;
+
+    /// The header of an `EventAnyStruct` on a 64-bit machine
+    const LAYOUT: mps_sys_event_layout_s = mps_sys_event_layout_s {
+        header_size: 16,
+        code_offset: 0, code_size: 2,
+        size_offset: 2, size_size: 2,
+        clock_offset: 8, clock_size: 8,
+        word_size: 8, unsigned_size: 4, double_size: 8, bool_size: 1
+    };
+    const POOL_INIT: u16 = 0x0044;
+    const MEASURE: u16 = 0x0100;
+
+    fn c_str(s: &'static [u8]) -> *const c_char {
+        assert_eq!(s.last(), Some(&0));
+        s.as_ptr() as *const c_char
+    }
+
+    /// Read the stream with a table holding the `PoolInit` event,
+    /// and a made up event with a double
+    fn read_with_layout(data: &[u8], layout: mps_sys_event_layout_s) -> io::Result<Vec<Event>> {
+        let pool_params = [
+            mps_sys_event_param_s { name: c_str(b"pool\0"), sort: c_str(b"P\0"), offset: 16 },
+            mps_sys_event_param_s { name: c_str(b"arena\0"), sort: c_str(b"P\0"), offset: 24 },
+            mps_sys_event_param_s { name: c_str(b"poolClass\0"), sort: c_str(b"P\0"), offset: 32 },
+            mps_sys_event_param_s { name: std::ptr::null(), sort: std::ptr::null(), offset: 0 }
+        ];
+        let measure_params = [
+            mps_sys_event_param_s { name: c_str(b"value\0"), sort: c_str(b"D\0"), offset: 16 },
+            mps_sys_event_param_s { name: std::ptr::null(), sort: std::ptr::null(), offset: 0 }
+        ];
+        let events = [
+            mps_sys_event_s {
+                code: POOL_INIT as _, name: c_str(b"PoolInit\0"),
+                size: 40, params: pool_params.as_ptr()
+            },
+            mps_sys_event_s {
+                code: MEASURE as _, name: c_str(b"Measure\0"),
+                size: 24, params: measure_params.as_ptr()
+            }
+        ];
+        unsafe { EventReader::with_tables(data, &events, layout) }.collect()
+    }
+    fn read(data: &[u8]) -> io::Result<Vec<Event>> {
+        read_with_layout(data, LAYOUT)
+    }
+
+    fn event(code: u16, clock: u64, params: &[u64]) -> Vec<u8> {
+        let size = 16 + params.len() * 8;
+        let mut data = Vec::with_capacity(size);
+        data.extend_from_slice(&code.to_ne_bytes());
+        data.extend_from_slice(&(size as u16).to_ne_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&clock.to_ne_bytes());
+        for param in params {
+            data.extend_from_slice(&param.to_ne_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn known_event() {
+        let events = read(&event(POOL_INIT, 1234, &[0x1000, 0x2000, 0x3000])).unwrap();
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!((event.code, event.name, event.clock), (POOL_INIT, "PoolInit", 1234));
+        assert_eq!(event.param("arena"), Some(&Value::Pointer(0x2000)));
+        assert_eq!(event.kind(), EventKind::PoolCreate { pool: 0x1000, arena: 0x2000, class: 0x3000 });
+    }
+
+    #[test]
+    fn unknown_event() {
+        let mut data = event(0x7fff, 1, &[42]);
+        data.extend(event(POOL_INIT, 2, &[1, 2, 3]));
+        let events = read(&data).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[0].code, events[0].name), (0x7fff, "Unknown"));
+        assert!(events[0].params.is_empty());
+        assert_eq!(events[0].kind(), EventKind::Other);
+        // The unknown event is skipped using its size
+        assert_eq!(events[1].kind(), EventKind::PoolCreate { pool: 1, arena: 2, class: 3 });
+    }
+
+    #[test]
+    fn empty_stream() {
+        assert!(read(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated_stream() {
+        let data = event(POOL_INIT, 1, &[1, 2, 3]);
+        // In the middle of the header
+        assert_eq!(read(&data[..10]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        // In the middle of the parameters
+        assert_eq!(read(&data[..30]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn invalid_size() {
+        let mut data = event(POOL_INIT, 1, &[1, 2, 3]);
+        data[2..4].copy_from_slice(&8u16.to_ne_bytes());
+        assert_eq!(read(&data).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn double() {
+        let data = event(MEASURE, 1, &[1.5f64.to_bits()]);
+        let events = read(&data).unwrap();
+        assert_eq!(events[0].param("value"), Some(&Value::Double(1.5)));
+        assert_eq!(events[0].kind(), EventKind::Other);
+        let layout = mps_sys_event_layout_s { double_size: 4, ..LAYOUT };
+        assert_eq!(read_with_layout(&data, layout).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn missing_definitions() {
+        let layout = mps_sys_event_layout_s { header_size: 0, ..LAYOUT };
+        let data = event(POOL_INIT, 1, &[1, 2, 3]);
+        assert!(read_with_layout(&data, layout).is_err());
+    }
+}
@@ -0,0 +1,451 @@
+//! An implementation of [zerogc](https://github.com/DuckLogic/zerogc)'s collector API,
+//! backed by the MPS.
+//!
+//! Objects of any type implementing [Trace] are allocated in a single [AMC pool](AutoMostlyCopyingPool),
+//! using a type-erased [TraceFormat] with an in-band header.
+//!
+//! The MPS decides for itself when to collect, so a zerogc safepoint can't force one.
+//! Instead, safepoints are mapped to the MPS according to the [SafepointMode].
+use std::alloc::{handle_alloc_error, Layout};
+use std::any::TypeId;
+use std::ffi::c_void;
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop};
+use std::ptr::{self, NonNull};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use mps_sys::mps_res_t;
+use ::zerogc::{
+    CollectorId, Gc, GcContext, GcSafe, GcSimpleAlloc,
+    GcSystem, GcVisitor, NullTrace, Trace, TraceImmutable
+};
+
+use crate::arena::{Arena, VirtualMemoryArenaBuilder};
+use crate::format::{
+    ObjectFormat, RawFormatMethods, MovingFormatMethods,
+    PaddingFormatMethods, ScanState, ScanFixState
+};
+use crate::gc::GcContext as MpsGcContext;
+use crate::pools::automatic_mostly_copying::AutoMostlyCopyingPool;
+use crate::retry::RetryPolicy;
+use crate::MpsError;
+
+/// How zerogc safepoints interact with the MPS
+///
+/// An arena in [debug mode](VirtualMemoryArenaBuilder::debug_mode) can't be stepped,
+/// so in either mode every safepoint does a [full collection](Arena::full_collection) instead.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SafepointMode {
+    /// Let the MPS collect whenever it wants,
+    /// giving it some "idle time" to do collection work at each safepoint.
+    ///
+    /// See [Arena::step] for the meaning of the parameters.
+    Step {
+        /// The maximum time (in seconds) to spend at each safepoint
+        interval: f64,
+        /// The number of further safepoints expected before the next idle time
+        multiplier: f64
+    },
+    /// Keep the arena parked, so that collections only happen at safepoints.
+    ///
+    /// At every safepoint the arena is [stepped](Arena::step) without being released.
+    /// Stepping leaves the arena [clamped](Arena::clamp) instead of parked,
+    /// so a safepoint never waits for a collection to finish.
+    Parked {
+        /// The maximum time (in seconds) to spend at each safepoint
+        interval: f64
+    }
+}
+impl Default for SafepointMode {
+    fn default() -> Self {
+        SafepointMode::Step { interval: 0.001, multiplier: 1.0 }
+    }
+}
+
+/// The in-band header before every object in a [TraceFormat]
+#[repr(C)]
+struct ObjectHeader {
+    /// Either a pointer to the type's [TraceVtable],
+    /// or one of [FORWARDED] or [PADDING]
+    kind: usize,
+    /// The total size of the block, including the header
+    size: usize
+}
+const HEADER_SIZE: usize = mem::size_of::<ObjectHeader>();
+const FORWARDED: usize = 1;
+const PADDING: usize = 2;
+
+struct TraceVtable {
+    scan: unsafe fn(*mut TraceObject, &mut ScanFixState) -> Result<(), mps_res_t>
+}
+struct VtableFor<T>(PhantomData<T>);
+impl<T: Trace> VtableFor<T> {
+    const VTABLE: &'static TraceVtable = &TraceVtable { scan: scan_value::<T> };
+}
+unsafe fn scan_value<T: Trace>(obj: *mut TraceObject, state: &mut ScanFixState) -> Result<(), mps_res_t> {
+    (*(obj as *mut T)).visit(&mut MpsVisitor { state })
+}
+
+/// An opaque object in a [TraceFormat], just after its header
+pub enum TraceObject {}
+
+/// A single object format for all types implementing [Trace]
+///
+/// Every object has an in-band header giving its size and type.
+pub struct TraceFormat;
+impl TraceFormat {
+    #[inline]
+    unsafe fn header<'a>(obj: *mut TraceObject) -> &'a mut ObjectHeader {
+        &mut *(obj as *mut ObjectHeader).sub(1)
+    }
+    /// The total size of a block holding an object of type `T`
+    ///
+    /// There must be room for the forwarding pointer after the header.
+    #[inline]
+    fn block_size<T>() -> usize {
+        let size = HEADER_SIZE + mem::size_of::<T>().max(mem::size_of::<*mut u8>());
+        let align = <Self as RawFormatMethods>::ALIGNMENT;
+        (size + align - 1) & !(align - 1)
+    }
+}
+unsafe impl RawFormatMethods for TraceFormat {
+    type Obj = TraceObject;
+    /// Every padding object needs room for a header
+    const ALIGNMENT: usize = HEADER_SIZE;
+
+    unsafe extern "C" fn scan(mut state: ScanState, mut base: *mut TraceObject, limit: *mut TraceObject) -> mps_res_t {
+        state.fix_with(|state| {
+            while base < limit {
+                let kind = Self::header(base).kind;
+                if kind != FORWARDED && kind != PADDING {
+                    let vtable = &*(kind as *const TraceVtable);
+                    (vtable.scan)(base, state)?;
+                }
+                base = Self::skip(base);
+            }
+            Ok(())
+        })
+    }
+
+    unsafe extern "C" fn skip(addr: *mut TraceObject) -> *mut TraceObject {
+        (addr as *mut u8).add(Self::header(addr).size) as *mut TraceObject
+    }
+}
+unsafe impl MovingFormatMethods for TraceFormat {
+    unsafe extern "C" fn forward(old: *mut TraceObject, new: *mut TraceObject) {
+        // NOTE: Leave the size alone
+        Self::header(old).kind = FORWARDED;
+        (old as *mut *mut TraceObject).write(new);
+    }
+
+    unsafe extern "C" fn is_forwarded(old: *mut TraceObject) -> *mut TraceObject {
+        if Self::header(old).kind == FORWARDED {
+            (old as *mut *mut TraceObject).read()
+        } else {
+            ptr::null_mut()
+        }
+    }
+}
+unsafe impl PaddingFormatMethods for TraceFormat {
+    unsafe extern "C" fn pad(addr: *mut TraceObject, size: usize) {
+        // NOTE: Padding is given the base of the block (not the client pointer).
+        // Sizes are multiples of the alignment, so there is always room for the header.
+        (addr as *mut ObjectHeader).write(ObjectHeader { kind: PADDING, size });
+    }
+}
+
+/// Visits zerogc references, fixing them with the MPS
+struct MpsVisitor<'a> {
+    state: &'a mut ScanFixState
+}
+unsafe impl GcVisitor for MpsVisitor<'_> {
+    type Err = mps_res_t;
+
+    #[inline]
+    unsafe fn visit_gc<'gc, T, Id>(&mut self, gc: &mut Gc<'gc, T, Id>) -> Result<(), Self::Err>
+        where T: GcSafe + 'gc, Id: CollectorId {
+        // References to other collectors are none of our business
+        if TypeId::of::<Id>() != TypeId::of::<MpsCollectorId>() {
+            return Ok(())
+        }
+        let id = *Id::from_gc_ptr(gc);
+        let mut ptr = gc.as_raw_ptr();
+        self.state.fix(&mut ptr)?;
+        *gc = Gc::from_raw(id, NonNull::new_unchecked(ptr));
+        Ok(())
+    }
+}
+
+/// A garbage collector backed by the MPS,
+/// implementing zerogc's [GcSystem]
+pub struct MpsSystem {
+    // NOTE: Pool must be dropped before the arena
+    pool: ManuallyDrop<AutoMostlyCopyingPool<'static>>,
+    arena: ManuallyDrop<Box<Arena>>,
+    mode: SafepointMode
+}
+impl MpsSystem {
+    /// Create a new collector, with a newly built arena
+    ///
+    /// Panics if another system already exists.
+    pub fn create(builder: VirtualMemoryArenaBuilder, mode: SafepointMode) -> Result<Arc<MpsSystem>, MpsError> {
+        assert!(
+            ACTIVE_SYSTEM.load(Ordering::Acquire).is_null(),
+            "Only one MpsSystem may exist at a time"
+        );
+        let arena = Box::new(builder.build()?);
+        // The arena is boxed, so it will never move
+        let arena_ref = unsafe { &*(&*arena as *const Arena) };
+        let format = ObjectFormat::builder::<TraceFormat>(arena_ref)
+            .header_size(HEADER_SIZE)
+            .moving()
+            .build()?;
+        let pool = AutoMostlyCopyingPool::builder(arena_ref).build(format)?;
+        if let SafepointMode::Parked { .. } = mode {
+            arena.park();
+        }
+        let system = Arc::new(MpsSystem {
+            pool: ManuallyDrop::new(pool),
+            arena: ManuallyDrop::new(arena),
+            mode
+        });
+        let raw = &*system as *const MpsSystem as *mut MpsSystem;
+        if ACTIVE_SYSTEM.compare_exchange(
+            ptr::null_mut(), raw,
+            Ordering::AcqRel, Ordering::Acquire
+        ).is_err() {
+            panic!("Only one MpsSystem may exist at a time");
+        }
+        Ok(system)
+    }
+    /// The underlying arena
+    #[inline]
+    pub fn arena(&self) -> &Arena {
+        &self.arena
+    }
+    /// The pool all objects are allocated in
+    #[inline]
+    pub fn pool(&self) -> &AutoMostlyCopyingPool<'_> {
+        &self.pool
+    }
+    /// How safepoints interact with the MPS
+    #[inline]
+    pub fn safepoint_mode(&self) -> SafepointMode {
+        self.mode
+    }
+    /// Create a context for the current thread.
+    ///
+    /// The `cold_addr` is the top stack address to start scanning,
+    /// see [MpsThread::register_roots](crate::arena::MpsThread::register_roots).
+    ///
+    /// ## Safety
+    /// Undefined behavior if `cold_addr` doesn't point to the the top of the stack.
+    pub unsafe fn create_context(self: &Arc<Self>, cold_addr: *mut c_void) -> Result<MpsContext, MpsError> {
+        // The context holds the system (and its pool) alive
+        let pool: &'static AutoMostlyCopyingPool<'static> = &*(&*self.pool as *const _);
+        Ok(MpsContext {
+            raw: ManuallyDrop::new(MpsGcContext::register(pool, cold_addr)?),
+            cold_addr,
+            system: Arc::clone(self)
+        })
+    }
+}
+unsafe impl GcSystem for MpsSystem {
+    type Id = MpsCollectorId;
+    type Context = MpsContext;
+}
+impl Drop for MpsSystem {
+    fn drop(&mut self) {
+        // NOTE: Creation may have failed the compare_exchange
+        let _ = ACTIVE_SYSTEM.compare_exchange(
+            self as *mut MpsSystem, ptr::null_mut(),
+            Ordering::AcqRel, Ordering::Acquire
+        );
+        unsafe {
+            ManuallyDrop::drop(&mut self.pool);
+            ManuallyDrop::drop(&mut self.arena);
+        }
+    }
+}
+
+/// The currently active system
+///
+/// Only one [MpsSystem] may exist at a time,
+/// so that the [MpsCollectorId] doesn't need to be stored in each object.
+static ACTIVE_SYSTEM: AtomicPtr<MpsSystem> = AtomicPtr::new(ptr::null_mut());
+
+/// Identifies the active [MpsSystem]
+///
+/// This is a singleton, since only one system may exist at a time.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct MpsCollectorId {
+    _priv: ()
+}
+unsafe impl CollectorId for MpsCollectorId {
+    type System = MpsSystem;
+
+    #[inline]
+    fn from_gc_ptr<'a, 'gc, T>(_gc: &'a Gc<'gc, T, Self>) -> &'a Self where T: GcSafe + ?Sized + 'gc {
+        const ID: MpsCollectorId = MpsCollectorId { _priv: () };
+        &ID
+    }
+
+    #[inline]
+    unsafe fn gc_write_barrier<'gc, T, V>(
+        _owner: &Gc<'gc, T, Self>,
+        _value: &Gc<'gc, V, Self>,
+        _field_offset: usize
+    ) where T: GcSafe + ?Sized + 'gc, V: GcSafe + ?Sized + 'gc {
+        // NOTE: The MPS uses hardware write barriers
+    }
+
+    #[inline]
+    unsafe fn assume_valid_system(&self) -> &Self::System {
+        let system = ACTIVE_SYSTEM.load(Ordering::Acquire);
+        assert!(!system.is_null(), "No active MpsSystem");
+        &*system
+    }
+}
+unsafe impl Trace for MpsCollectorId {
+    const NEEDS_TRACE: bool = false;
+    const NEEDS_DROP: bool = false;
+    #[inline]
+    fn visit<V: GcVisitor>(&mut self, _visitor: &mut V) -> Result<(), V::Err> {
+        Ok(())
+    }
+}
+unsafe impl TraceImmutable for MpsCollectorId {
+    #[inline]
+    fn visit_immutable<V: GcVisitor>(&self, _visitor: &mut V) -> Result<(), V::Err> {
+        Ok(())
+    }
+}
+unsafe impl NullTrace for MpsCollectorId {}
+unsafe impl GcSafe for MpsCollectorId {}
+
+/// A thread's context for allocating with an [MpsSystem],
+/// implementing zerogc's [GcContext]
+pub struct MpsContext {
+    // NOTE: Must be dropped before the system
+    raw: ManuallyDrop<MpsGcContext<'static, TraceObject>>,
+    cold_addr: *mut c_void,
+    system: Arc<MpsSystem>
+}
+unsafe impl GcContext for MpsContext {
+    type System = MpsSystem;
+    type Id = MpsCollectorId;
+
+    /// The stack is an ambiguous root, so the value doesn't need to be
+    /// registered with the MPS.
+    unsafe fn basic_safepoint<T: Trace>(&mut self, _value: &mut &mut T) {
+        let arena = self.system.arena();
+        if arena.is_debug_mode() {
+            arena.full_collection();
+            return;
+        }
+        match self.system.mode {
+            SafepointMode::Step { interval, multiplier } => {
+                arena.step(interval, multiplier);
+            },
+            SafepointMode::Parked { interval } => {
+                // NOTE: Releasing would let the MPS collect outside of safepoints
+                arena.step(interval, 0.0);
+            }
+        }
+    }
+
+    /// The MPS can collect while a context is frozen,
+    /// so there is nothing to do.
+    unsafe fn freeze(&mut self) {}
+
+    unsafe fn unfreeze(&mut self) {}
+
+    /// Creates a child context, with its own registration and allocation point.
+    ///
+    /// Roots are found by scanning the (shared) stack,
+    /// so the value doesn't need to be registered.
+    unsafe fn recurse_context<T, F, R>(&self, value: &mut &mut T, func: F) -> R
+        where T: Trace, F: for<'gc> FnOnce(&'gc mut Self, &'gc mut T) -> R {
+        let mut child = self.system.create_context(self.cold_addr)
+            .expect("Failed to create child context");
+        func(&mut child, &mut **value)
+    }
+}
+impl MpsContext {
+    /// The system this context allocates in
+    #[inline]
+    pub fn system(&self) -> &Arc<MpsSystem> {
+        &self.system
+    }
+    /// Allocate a new object, returning an error if the MPS runs out of memory.
+    ///
+    /// Failed reservations are retried according to the context's [RetryPolicy].
+    pub fn try_alloc<'gc, T>(&'gc self, value: T) -> Result<Gc<'gc, T, MpsCollectorId>, MpsError>
+        where T: GcSafe + 'gc {
+        assert!(!mem::needs_drop::<T>(), "The MPS doesn't support finalizing objects");
+        assert!(
+            mem::align_of::<T>() <= TraceFormat::ALIGNMENT,
+            "Alignment of {} is unsupported",
+            std::any::type_name::<T>()
+        );
+        let size = TraceFormat::block_size::<T>();
+        let vtable = VtableFor::<T>::VTABLE as *const TraceVtable as usize;
+        unsafe {
+            let ap = self.raw.allocation_point();
+            let base = loop {
+                let base = ap.reserve(size)? as *mut u8;
+                (base as *mut ObjectHeader).write(ObjectHeader { kind: vtable, size });
+                // NOTE: Copying is fine, since `T` doesn't need to be dropped
+                (base.add(HEADER_SIZE) as *mut T).write(ptr::read(&value));
+                if ap.commit(base as *mut c_void, size) {
+                    break base
+                }
+            };
+            mem::forget(value);
+            Ok(Gc::from_raw(
+                MpsCollectorId { _priv: () },
+                NonNull::new_unchecked(base.add(HEADER_SIZE) as *mut T)
+            ))
+        }
+    }
+    /// Set the policy for retrying allocations that run out of memory.
+    ///
+    /// The closure configures a policy for the system's arena
+    /// (see [RetryPolicy::new]).
+    ///
+    /// See [AllocationPoint::set_retry_policy](crate::alloc::AllocationPoint::set_retry_policy).
+    #[inline]
+    pub fn set_retry_policy<F>(&self, configure: F)
+        where F: for<'a> FnOnce(RetryPolicy<'a>) -> RetryPolicy<'a> {
+        // NOTE: The context holds the system (and its arena) alive, just like its allocation point
+        let arena: &'static Arena = unsafe { &*(self.system.arena() as *const Arena) };
+        self.raw.allocation_point().set_retry_policy(Some(configure(RetryPolicy::new(arena))));
+    }
+    /// Remove the policy for retrying allocations,
+    /// so reservations fail as soon as they run out of memory.
+    #[inline]
+    pub fn clear_retry_policy(&self) {
+        self.raw.allocation_point().set_retry_policy(None);
+    }
+}
+unsafe impl GcSimpleAlloc for MpsContext {
+    /// Allocate a new object, aborting (like the standard library)
+    /// if the MPS runs out of memory.
+    ///
+    /// Use [MpsContext::try_alloc] to handle the error instead.
+    fn alloc<'gc, T>(&'gc self, value: T) -> Gc<'gc, T, Self::Id> where T: GcSafe + 'gc {
+        match self.try_alloc(value) {
+            Ok(gc) => gc,
+            Err(_) => handle_alloc_error(Layout::from_size_align(
+                TraceFormat::block_size::<T>(),
+                TraceFormat::ALIGNMENT
+            ).unwrap())
+        }
+    }
+}
+impl Drop for MpsContext {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.raw) }
+    }
+}
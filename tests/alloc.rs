@@ -0,0 +1,88 @@
+//! Allocating layouts and slices from an [AllocationPoint],
+//! including the rounding to the pool's alignment
+use std::alloc::Layout;
+
+use mps::alloc::AllocationPoint;
+use mps::arena::{Arena, VirtualMemoryArenaClass};
+use mps::pools::Pool;
+use mps::pools::manual_first_fit::ManualFirstFitPool;
+
+/// Larger than the natural alignment, so rounding is visible
+const ALIGNMENT: usize = 32;
+
+fn arena() -> Arena {
+    VirtualMemoryArenaClass::get().builder().build().unwrap()
+}
+
+fn pool(arena: &Arena) -> ManualFirstFitPool<'_> {
+    ManualFirstFitPool::builder(arena).align(ALIGNMENT).build().unwrap()
+}
+
+/// Allocate a block with the specified layout, filling it with a byte
+fn alloc(ap: &AllocationPoint, layout: Layout) -> usize {
+    unsafe {
+        ap.alloc_layout(layout, |ptr| ptr.write_bytes(0xAB, layout.size())).unwrap() as usize
+    }
+}
+
+#[test]
+fn layout_rounded_to_alignment() {
+    let arena = arena();
+    let pool = pool(&arena);
+    let ap = pool.create_allocation_point().unwrap();
+    assert_eq!(ap.alignment(), ALIGNMENT);
+    // Consecutive blocks are adjacent within the allocation point's buffer
+    let first = alloc(&ap, Layout::from_size_align(1, 1).unwrap());
+    let second = alloc(&ap, Layout::from_size_align(ALIGNMENT + 1, 8).unwrap());
+    let third = alloc(&ap, Layout::from_size_align(ALIGNMENT, 8).unwrap());
+    let fourth = alloc(&ap, Layout::new::<u8>());
+    for &block in &[first, second, third, fourth] {
+        assert_eq!(block % ALIGNMENT, 0, "Misaligned block {:#x}", block);
+    }
+    assert_eq!(second - first, ALIGNMENT);
+    assert_eq!(third - second, 2 * ALIGNMENT);
+    assert_eq!(fourth - third, ALIGNMENT);
+}
+
+#[test]
+#[should_panic(expected = "exceeds pool alignment")]
+fn layout_over_aligned() {
+    let arena = arena();
+    let pool = pool(&arena);
+    let ap = pool.create_allocation_point().unwrap();
+    alloc(&ap, Layout::from_size_align(8, 2 * ALIGNMENT).unwrap());
+}
+
+#[test]
+fn slices() {
+    let arena = arena();
+    let pool = pool(&arena);
+    let ap = pool.create_allocation_point().unwrap();
+    unsafe {
+        let slice = ap.alloc_slice::<u64, _>(5, |ptr| {
+            for index in 0..5 {
+                ptr.add(index).write(index as u64);
+            }
+        }).unwrap();
+        assert_eq!(&*slice, &[0, 1, 2, 3, 4]);
+        // An empty slice still gets a distinct (aligned) block
+        let empty = ap.alloc_slice::<u64, _>(0, |_| {}).unwrap();
+        assert_eq!(empty.len(), 0);
+        let start = empty as *mut u64 as usize;
+        assert_eq!(start % ALIGNMENT, 0);
+        assert_eq!(start - slice as *mut u64 as usize, 2 * ALIGNMENT);
+        let after = ap.alloc_slice::<u64, _>(1, |ptr| ptr.write(7)).unwrap();
+        assert_eq!(after as *mut u64 as usize - start, ALIGNMENT);
+    }
+}
+
+#[test]
+fn raw_round_trip() {
+    let arena = arena();
+    let pool = pool(&arena);
+    let ap = pool.create_allocation_point().unwrap();
+    let raw = ap.into_raw();
+    assert!(!raw.as_raw().is_null());
+    let ap = unsafe { AllocationPoint::from_raw_handle(raw, pool.alignment()) };
+    assert_eq!(alloc(&ap, Layout::new::<u64>()) % ALIGNMENT, 0);
+}
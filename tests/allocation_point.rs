@@ -0,0 +1,122 @@
+//! Creating allocation points with an [AllocationPointBuilder](mps::pools::AllocationPointBuilder),
+//! using the options of each pool class
+use std::ffi::c_void;
+use std::ptr;
+
+use mps::alloc::AllocationPoint;
+use mps::arena::{Arena, VirtualMemoryArenaClass};
+use mps::format::{MpsFormat, ObjectFormat};
+use mps::pools::{AmbiguousRank, ExactRank, ManualPool, Pool, WeakRank};
+use mps::pools::automatic_weak_linked::AutoWeakLinkedPool;
+use mps::pools::manual_first_fit::ManualFirstFitPool;
+use mps::pools::mark_sweep::AutoMarkSweep;
+
+#[derive(MpsFormat)]
+#[repr(usize)]
+enum Object {
+    #[mps(forward)]
+    Forwarded {
+        new: *mut Object,
+        size: usize
+    },
+    #[mps(pad)]
+    Padding {
+        size: usize
+    },
+    /// A (possibly weak) reference, and a value
+    Cell(#[mps(ref)] *mut c_void, u64)
+}
+
+fn arena() -> Arena {
+    VirtualMemoryArenaClass::get().builder().build().unwrap()
+}
+
+/// Allocate a cell, and check its value
+fn alloc_cell(ap: &AllocationPoint, value: u64) {
+    let cell = unsafe {
+        &*ap.alloc_with(|ptr: *mut Object| ptr::write(ptr, Object::Cell(ptr::null_mut(), value))).unwrap()
+    };
+    match *cell {
+        Object::Cell(_, actual) => assert_eq!(actual, value),
+        _ => panic!("Expected a cell")
+    }
+}
+
+#[test]
+fn awl_ranks() {
+    let arena = arena();
+    let format = ObjectFormat::managed_with::<Object>(&arena).unwrap();
+    let pool = AutoWeakLinkedPool::builder(&arena).build(format).unwrap();
+    let exact = pool.allocation_point_builder().rank(ExactRank).build().unwrap();
+    let weak = pool.allocation_point_builder().rank(WeakRank).build().unwrap();
+    alloc_cell(&exact, 1);
+    alloc_cell(&weak, 2);
+    arena.full_collection();
+}
+
+#[test]
+fn awl_hash_arrays() {
+    let arena = arena();
+    let format = ObjectFormat::managed_with::<Object>(&arena).unwrap();
+    let pool = AutoWeakLinkedPool::builder(&arena).build(format).unwrap();
+    for &hash_arrays in &[true, false] {
+        let ap = pool.allocation_point_builder()
+            .rank(WeakRank)
+            .hash_arrays(hash_arrays)
+            .build().unwrap();
+        alloc_cell(&ap, hash_arrays as u64);
+    }
+    // Hash arrays are also allowed on an exact allocation point
+    let ap = pool.allocation_point_builder()
+        .rank(ExactRank)
+        .hash_arrays(true)
+        .build().unwrap();
+    alloc_cell(&ap, 3);
+    arena.full_collection();
+}
+
+#[test]
+fn ams_ranks() {
+    let arena = arena();
+    let format = ObjectFormat::managed_with::<Object>(&arena).unwrap();
+    let pool = AutoMarkSweep::builder(&arena).allow_ambiguous(true).build(format).unwrap();
+    let exact = pool.allocation_point_builder().rank(ExactRank).build().unwrap();
+    let ambiguous = pool.allocation_point_builder().rank(AmbiguousRank).build().unwrap();
+    alloc_cell(&exact, 1);
+    alloc_cell(&ambiguous, 2);
+    arena.full_collection();
+}
+
+#[test]
+#[should_panic(expected = "The pool doesn't accept the rank")]
+fn ams_rejects_ambiguous_rank() {
+    let arena = arena();
+    let format = ObjectFormat::managed_with::<Object>(&arena).unwrap();
+    let pool = AutoMarkSweep::builder(&arena).allow_ambiguous(false).build(format).unwrap();
+    // Exact references are still fine
+    pool.allocation_point_builder().rank(ExactRank).build().unwrap();
+    pool.allocation_point_builder().rank(AmbiguousRank);
+}
+
+#[test]
+fn mvff_options() {
+    let arena = arena();
+    let pool = ManualFirstFitPool::builder(&arena)
+        .align(16)
+        .extend_by(1 << 16)
+        .mean_size(64)
+        .first_fit(false)
+        .build().unwrap();
+    assert_eq!(pool.alignment(), 16);
+    let ap = pool.allocation_point_builder().build().unwrap();
+    assert_eq!(ap.alignment(), 16);
+    unsafe {
+        let block = ap.alloc_with(|ptr: *mut [u64; 4]| ptr::write(ptr, [1, 2, 3, 4])).unwrap();
+        assert_eq!(block as usize % 16, 0);
+        assert_eq!(*block, [1, 2, 3, 4]);
+        // Blocks can also be allocated (and freed) directly
+        let direct = pool.alloc(64).unwrap();
+        assert_eq!(direct as usize % 16, 0);
+        pool.free(direct, 64);
+    }
+}
@@ -0,0 +1,45 @@
+//! Reporting an MPS assertion failure through an [AssertionHandler]
+//!
+//! The process aborts after the failure is reported,
+//! so the test runs itself again in a child process.
+#![cfg(all(target_os = "linux", not(feature = "variety-rash")))]
+use std::env;
+use std::os::unix::process::ExitStatusExt;
+use std::process::Command;
+
+use mps::arena::VirtualMemoryArenaClass;
+use mps::assertion::AssertionHandler;
+use mps_sys::mps_pool_t;
+
+/// Set in the child process, which fails the assertion
+const CHILD_VAR: &str = "MPS_TEST_ASSERTION_CHILD";
+
+fn fail_assertion() -> ! {
+    let arena = VirtualMemoryArenaClass::get().builder().build().unwrap();
+    AssertionHandler::new(|failure| {
+        eprintln!("callback: {}", failure.condition);
+        eprintln!("{}", failure);
+    }).dump_stats(&arena).install();
+    // Not a pool, so its signature check fails
+    let mut fake_pool = [0usize; 32];
+    unsafe { mps_sys::mps_pool_destroy(fake_pool.as_mut_ptr() as mps_pool_t); }
+    unreachable!("The assertion didn't fail");
+}
+
+#[test]
+fn reports_failure_and_aborts() {
+    if env::var_os(CHILD_VAR).is_some() {
+        fail_assertion();
+    }
+    let output = Command::new(env::current_exe().unwrap())
+        .args(["--exact", "reports_failure_and_aborts", "--nocapture", "--test-threads=1"])
+        .env(CHILD_VAR, "1")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.signal(), Some(libc::SIGABRT), "{}", stderr);
+    assert!(stderr.contains("callback: "), "{}", stderr);
+    assert!(stderr.contains("MPS assertion failed: "), "{}", stderr);
+    // The statistics of the arena are included
+    assert!(stderr.contains("mps_arena_committed_bytes "), "{}", stderr);
+}
@@ -0,0 +1,98 @@
+//! Adjusting the commit limit with a [CgroupWatcher]
+//!
+//! The limit passed to the watcher is always below the real cgroup limit (if any),
+//! so these don't depend on the cgroup the tests run in.
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mps::arena::{Arena, ArenaState, VirtualMemoryArenaClass};
+use mps::cgroup::CgroupWatcher;
+use mps::format::{MpsFormat, ObjectFormat};
+use mps::pools::Pool;
+use mps::pools::automatic_mostly_copying::AutoMostlyCopyingPool;
+
+#[derive(MpsFormat)]
+#[repr(usize)]
+enum Object {
+    #[mps(forward)]
+    Forwarded {
+        new: *mut Object,
+        size: usize
+    },
+    #[mps(pad)]
+    Padding {
+        size: usize
+    },
+    #[allow(dead_code)] // Only used for its size
+    Leaf(u64)
+}
+
+const INTERVAL: Duration = Duration::from_millis(1);
+
+/// Wait (for up to ten seconds) until the condition is true
+fn wait_until(mut condition: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        if condition() {
+            return true
+        }
+        thread::sleep(INTERVAL);
+    }
+    condition()
+}
+
+fn pool(arena: &Arena) -> AutoMostlyCopyingPool<'_> {
+    let format = ObjectFormat::managed_with::<Object>(arena).unwrap();
+    AutoMostlyCopyingPool::builder(arena).build(format).unwrap()
+}
+
+/// Allocate some objects, so collections have something to do
+fn fill(pool: &AutoMostlyCopyingPool) {
+    let ap = pool.create_allocation_point().unwrap();
+    for value in 0..1000 {
+        unsafe {
+            ap.alloc_with(|ptr: *mut Object| ptr.write(Object::Leaf(value))).unwrap();
+        }
+    }
+}
+
+#[test]
+fn lowers_commit_limit() {
+    let arena = VirtualMemoryArenaClass::get().builder().build().unwrap();
+    let max = arena.committed() + (64 << 20);
+    assert!(arena.commit_limit() > max);
+    let watcher = unsafe { CgroupWatcher::spawn(&arena, Some(max), 0.1, INTERVAL).unwrap() };
+    assert!(wait_until(|| arena.commit_limit() <= max), "Limit never set");
+    drop(watcher);
+}
+
+#[test]
+fn keeps_arena_parked() {
+    let arena = VirtualMemoryArenaClass::get().builder().build().unwrap();
+    let pool = pool(&arena);
+    fill(&pool);
+    arena.park();
+    let limit = arena.commit_limit();
+    let collections = arena.moved_collections();
+    // Nothing can be collected below a single byte, so the watcher keeps collecting
+    let watcher = unsafe { CgroupWatcher::spawn(&arena, Some(1), 0.0, INTERVAL).unwrap() };
+    assert!(wait_until(|| arena.moved_collections() > collections), "Never collected");
+    drop(watcher);
+    assert_eq!(arena.state(), ArenaState::Parked);
+    assert_eq!(arena.commit_limit(), limit);
+}
+
+#[test]
+fn never_collects_in_debug_mode() {
+    let mut builder = VirtualMemoryArenaClass::get().builder();
+    builder.debug_mode = true;
+    let arena = builder.build().unwrap();
+    let pool = pool(&arena);
+    fill(&pool);
+    let collections = arena.moved_collections();
+    let watcher = unsafe { CgroupWatcher::spawn(&arena, Some(1), 0.0, INTERVAL).unwrap() };
+    thread::sleep(100 * INTERVAL);
+    drop(watcher);
+    assert_eq!(arena.moved_collections(), collections);
+    assert_eq!(arena.state(), ArenaState::Parked);
+}
@@ -0,0 +1,86 @@
+//! Using the objects of an arena in debug mode
+//! without hitting any memory protection
+//!
+//! This counts the faults with a chained `SIGSEGV` handler,
+//! so it runs in its own process.
+#![cfg(target_os = "linux")]
+use std::ffi::c_void;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use mps::arena::VirtualMemoryArenaClass;
+use mps::format::{MpsFormat, ObjectFormat};
+use mps::gc::{Gc, GcContext};
+use mps::pools::automatic_mostly_copying::AutoMostlyCopyingPool;
+use mps::signal;
+
+#[derive(MpsFormat)]
+#[repr(usize)]
+enum Object {
+    #[mps(forward)]
+    Forwarded {
+        new: *mut Object,
+        size: usize
+    },
+    #[mps(pad)]
+    Padding {
+        size: usize
+    },
+    /// A value, and the next node in the list (or null)
+    Node(u64, #[mps(ref)] *mut Object)
+}
+
+const LENGTH: u64 = 1000;
+
+static FAULTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Count every fault, passing it on to the MPS
+unsafe fn count_fault(_info: &libc::siginfo_t, _context: *mut c_void) -> bool {
+    FAULTS.fetch_add(1, Ordering::Relaxed);
+    false
+}
+
+/// Allocate a list, collect, then read and write every node
+#[inline(never)]
+fn touch_list(context: &GcContext<Object>) {
+    let mut head: Option<Gc<Object>> = None;
+    for value in 0..LENGTH {
+        let next = head.map_or(ptr::null_mut(), |head| Gc::as_raw(head).as_ptr());
+        head = Some(context.alloc_with(|| Object::Node(value, next)).unwrap());
+    }
+    for _ in 0..3 {
+        context.arena().full_collection();
+        FAULTS.store(0, Ordering::Relaxed);
+        let mut current = Gc::as_raw(head.unwrap()).as_ptr();
+        let mut count = 0;
+        while !current.is_null() {
+            match unsafe { &mut *current } {
+                Object::Node(value, next) => {
+                    assert_eq!(*value, LENGTH - 1 - count);
+                    // Writing back the same value would still hit a write barrier
+                    unsafe { ptr::write_volatile(value, LENGTH - 1 - count); }
+                    current = *next;
+                },
+                _ => panic!("Expected a node")
+            }
+            count += 1;
+        }
+        assert_eq!(count, LENGTH);
+        assert_eq!(FAULTS.load(Ordering::Relaxed), 0, "Hit a barrier after a collection");
+    }
+}
+
+#[test]
+fn no_barriers_after_collection() {
+    unsafe { signal::install_chained_handler(count_fault).unwrap(); }
+    let mut builder = VirtualMemoryArenaClass::get().builder();
+    builder.debug_mode = true;
+    let arena = builder.build().unwrap();
+    let format = ObjectFormat::managed_with::<Object>(&arena).unwrap();
+    let pool = AutoMostlyCopyingPool::builder(&arena).build(format).unwrap();
+    let cold = 0usize;
+    let context = unsafe {
+        GcContext::<Object>::register(&pool, &cold as *const usize as *mut c_void).unwrap()
+    };
+    touch_list(&context);
+}
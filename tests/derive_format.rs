@@ -0,0 +1,184 @@
+//! Checking the format methods generated by `#[derive(MpsFormat)]`
+//!
+//! The methods are called directly on plain buffers, outside of the MPS.
+//! With the `format-check` feature, the objects are also scanned with a recording scan state.
+use std::alloc::{self, Layout};
+use std::ffi::{c_void, CStr};
+use std::mem;
+use std::ptr;
+
+use mps::format::{
+    ClassFormatMethods, MovingFormatMethods, MpsFormat,
+    PaddingFormatMethods, RawFormatMethods
+};
+
+#[derive(MpsFormat)]
+#[repr(usize)]
+enum Object {
+    #[mps(forward)]
+    Forwarded {
+        new: *mut Object,
+        size: usize
+    },
+    #[mps(pad)]
+    Padding {
+        size: usize
+    },
+    Pair(#[mps(ref)] *mut c_void, #[mps(ref)] *mut c_void),
+    #[allow(dead_code)] // Only used for its size
+    Numbers(u64, u64, u64)
+}
+
+/// An object with a variable number of references after it
+#[derive(MpsFormat)]
+#[repr(usize)]
+#[mps(align = 32)]
+enum Array {
+    #[mps(forward)]
+    Forwarded {
+        new: *mut Array,
+        size: usize
+    },
+    #[mps(pad)]
+    Padding {
+        size: usize
+    },
+    Refs {
+        len: usize,
+        #[mps(ref, trailing = len)]
+        elems: [*mut c_void; 0]
+    }
+}
+
+const ALIGNMENT: usize = <Object as RawFormatMethods>::ALIGNMENT;
+
+/// The size of every ordinary object (the enum rounded up to the alignment)
+fn object_size() -> usize {
+    (mem::size_of::<Object>() + ALIGNMENT - 1) & !(ALIGNMENT - 1)
+}
+
+/// A zeroed buffer of exactly the specified size, aligned to the format's alignment
+struct Buffer<T: RawFormatMethods> {
+    ptr: *mut T,
+    layout: Layout
+}
+impl<T: RawFormatMethods> Buffer<T> {
+    fn new(size: usize) -> Buffer<T> {
+        let layout = Layout::from_size_align(size, T::ALIGNMENT).unwrap();
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        assert!(!ptr.is_null());
+        Buffer { ptr: ptr as *mut T, layout }
+    }
+    fn end(&self) -> *mut T {
+        unsafe { (self.ptr as *mut u8).add(self.layout.size()) as *mut T }
+    }
+}
+impl<T: RawFormatMethods> Drop for Buffer<T> {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr as *mut u8, self.layout) }
+    }
+}
+
+/// Write an array holding the specified references
+///
+/// The elements are written through the object pointer, since they're past the end of the enum.
+unsafe fn write_array(obj: *mut Array, refs: &[*mut c_void]) {
+    obj.write(Array::Refs { len: refs.len(), elems: [] });
+    let offset = match *obj {
+        Array::Refs { ref elems, .. } => elems.as_ptr() as usize - obj as usize,
+        _ => unreachable!()
+    };
+    let elems = (obj as *mut u8).add(offset) as *mut *mut c_void;
+    ptr::copy_nonoverlapping(refs.as_ptr(), elems, refs.len());
+}
+/// The size of an array of the specified length (rounded up to the alignment)
+fn array_size(len: usize) -> usize {
+    let unrounded = mem::size_of::<Array>().max(2 * mem::size_of::<usize>() + len * mem::size_of::<*mut c_void>());
+    (unrounded + 31) & !31
+}
+
+#[test]
+fn skip_and_class() {
+    let buffer = Buffer::<Object>::new(object_size());
+    unsafe {
+        buffer.ptr.write(Object::Pair(ptr::null_mut(), ptr::null_mut()));
+        assert_eq!(Object::skip(buffer.ptr), buffer.end());
+        let class = CStr::from_ptr(Object::class_ptr(buffer.ptr) as *const _);
+        assert_eq!(class.to_str().unwrap(), "Pair");
+        assert!(Object::is_forwarded(buffer.ptr).is_null());
+    }
+}
+
+#[test]
+fn padding() {
+    // The smallest padding objects are smaller than the whole enum
+    assert!(ALIGNMENT < mem::size_of::<Object>());
+    for size in (ALIGNMENT..=2 * object_size()).step_by(ALIGNMENT) {
+        let buffer = Buffer::<Object>::new(size);
+        unsafe {
+            Object::pad(buffer.ptr, size);
+            assert_eq!(Object::skip(buffer.ptr), buffer.end(), "padding of {} bytes", size);
+            assert!(Object::class_ptr(buffer.ptr).is_null());
+            assert!(Object::is_forwarded(buffer.ptr).is_null());
+        }
+    }
+}
+
+#[test]
+fn forwarding() {
+    let old = Buffer::<Object>::new(object_size());
+    let new = Buffer::<Object>::new(object_size());
+    unsafe {
+        old.ptr.write(Object::Numbers(1, 2, 3));
+        ptr::copy_nonoverlapping(old.ptr as *const u8, new.ptr as *mut u8, object_size());
+        Object::forward(old.ptr, new.ptr);
+        assert_eq!(Object::is_forwarded(old.ptr), new.ptr);
+        // Forwarding objects keep the size of the original
+        assert_eq!(Object::skip(old.ptr), old.end());
+        assert!(Object::class_ptr(old.ptr).is_null());
+        assert!(Object::is_forwarded(new.ptr).is_null());
+    }
+}
+
+#[test]
+fn explicit_alignment() {
+    assert_eq!(<Array as RawFormatMethods>::ALIGNMENT, 32);
+}
+
+#[test]
+fn skip_trailing_array() {
+    for len in [0, 1, 5, 12] {
+        let size = array_size(len);
+        let buffer = Buffer::<Array>::new(size);
+        let refs: Vec<*mut c_void> = (1..=len).map(|i| (i * 0x1000) as *mut c_void).collect();
+        unsafe {
+            write_array(buffer.ptr, &refs);
+            assert_eq!(Array::skip(buffer.ptr), buffer.end(), "array of length {}", len);
+        }
+    }
+    // The elements extend past the end of the enum
+    assert!(array_size(5) > mem::size_of::<Array>());
+}
+
+#[cfg(feature = "format-check")]
+#[test]
+fn scan_with_recording() {
+    use mps::format_check::FormatCheck;
+    let mut pair = FormatCheck::<Object>::new();
+    let mut array = FormatCheck::<Array>::new();
+    unsafe {
+        pair.sample(object_size(), |obj| {
+            obj.write(Object::Pair(0x1000 as *mut c_void, 0x2000 as *mut c_void));
+            vec![0x1000 as *mut c_void, 0x2000 as *mut c_void]
+        });
+        for len in [0, 1, 5] {
+            array.sample(array_size(len), move |obj| {
+                let refs: Vec<*mut c_void> = (1..=len).map(|i| (i * 0x1000) as *mut c_void).collect();
+                write_array(obj, &refs);
+                refs
+            });
+        }
+    }
+    pair.padding().moving().class().assert_conforms();
+    array.padding().moving().class().assert_conforms();
+}
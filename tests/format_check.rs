@@ -0,0 +1,207 @@
+//! Checking format methods with a [FormatCheck]
+//!
+//! This runs outside of the MPS, so it only needs the `format-check` feature.
+#![cfg(feature = "format-check")]
+use std::ffi::c_void;
+use std::mem;
+use std::ptr;
+
+use mps::format::{
+    ClassFormatMethods, MovingFormatMethods, MpsFormat, PaddingFormatMethods,
+    RawFormatMethods, ScanState
+};
+use mps::format_check::{FormatCheck, FormatCheckError, ObjectKind};
+use mps_sys::mps_res_t;
+
+#[derive(MpsFormat)]
+#[repr(usize)]
+enum Object {
+    #[mps(forward)]
+    Forwarded {
+        new: *mut Object,
+        size: usize
+    },
+    #[mps(pad)]
+    Padding {
+        size: usize
+    },
+    Pair(#[mps(ref)] *mut c_void, #[mps(ref)] *mut c_void),
+    #[allow(dead_code)] // Only used for its size
+    Numbers(u64, u64, u64)
+}
+
+#[test]
+fn derived_format_conforms() {
+    let alignment = <Object as RawFormatMethods>::ALIGNMENT;
+    // The smallest padding objects are smaller than the whole enum
+    assert!(alignment < mem::size_of::<Object>());
+    let size = (mem::size_of::<Object>() + alignment - 1) & !(alignment - 1);
+    let mut check = FormatCheck::<Object>::new();
+    unsafe {
+        check.sample(size, |obj| {
+            obj.write(Object::Pair(0x1000 as *mut c_void, 0x2000 as *mut c_void));
+            vec![0x1000 as *mut c_void, 0x2000 as *mut c_void]
+        });
+        check.sample(size, |obj| {
+            obj.write(Object::Numbers(1, 2, 3));
+            Vec::new()
+        });
+    }
+    check.padding().moving().class().assert_conforms();
+}
+
+/// The bugs that [Words] can be built with
+const NO_BUG: u8 = 0;
+const WRONG_SIZE: u8 = 1;
+const MISSED_REFERENCE: u8 = 2;
+const NOT_FORWARDED: u8 = 3;
+const PADDING_CLASS: u8 = 4;
+
+/// The tags of the objects in a [Words] format
+const FORWARDED: usize = 0;
+const PADDING: usize = 1;
+const SINGLE_PADDING: usize = 2;
+const PAIR: usize = 3;
+
+/// The size of a pair (the tag and two references)
+const PAIR_SIZE: usize = 3 * mem::size_of::<usize>();
+
+static CLASS: usize = 0;
+
+/// A hand-written format of tagged words, with an optional bug
+///
+/// A forwarding object is followed by its new address and size,
+/// and a padding object by its size (unless it's a single word).
+struct Words<const BUG: u8>;
+unsafe impl<const BUG: u8> RawFormatMethods for Words<BUG> {
+    type Obj = usize;
+    const ALIGNMENT: usize = mem::align_of::<usize>();
+    unsafe extern "C" fn scan(mut state: ScanState, base: *mut usize, limit: *mut usize) -> mps_res_t {
+        state.fix_with(|fix| {
+            let mut obj = base;
+            while obj < limit {
+                if *obj == PAIR {
+                    let refs = obj.add(1) as *mut *mut c_void;
+                    fix.fix(&mut *refs)?;
+                    if BUG != MISSED_REFERENCE {
+                        fix.fix(&mut *refs.add(1))?;
+                    }
+                }
+                obj = Self::skip(obj);
+            }
+            Ok(())
+        })
+    }
+    unsafe extern "C" fn skip(addr: *mut usize) -> *mut usize {
+        let size = match *addr {
+            FORWARDED => *addr.add(2),
+            PADDING => *addr.add(1),
+            SINGLE_PADDING => mem::size_of::<usize>(),
+            _ if BUG == WRONG_SIZE => PAIR_SIZE - mem::size_of::<usize>(),
+            _ => PAIR_SIZE
+        };
+        (addr as *mut u8).add(size) as *mut usize
+    }
+}
+unsafe impl<const BUG: u8> MovingFormatMethods for Words<BUG> {
+    unsafe extern "C" fn forward(old: *mut usize, new: *mut usize) {
+        let size = (Self::skip(old) as usize) - (old as usize);
+        ptr::write(old as *mut [usize; 3], [FORWARDED, new as usize, size]);
+    }
+    unsafe extern "C" fn is_forwarded(old: *mut usize) -> *mut usize {
+        if *old == FORWARDED && BUG != NOT_FORWARDED {
+            *old.add(1) as *mut usize
+        } else {
+            ptr::null_mut()
+        }
+    }
+}
+unsafe impl<const BUG: u8> PaddingFormatMethods for Words<BUG> {
+    unsafe extern "C" fn pad(addr: *mut usize, size: usize) {
+        if size == mem::size_of::<usize>() {
+            *addr = SINGLE_PADDING;
+        } else {
+            ptr::write(addr as *mut [usize; 2], [PADDING, size]);
+        }
+    }
+}
+unsafe impl<const BUG: u8> ClassFormatMethods for Words<BUG> {
+    unsafe extern "C" fn class_ptr(obj: *mut usize) -> *mut c_void {
+        if *obj == PAIR || (BUG == PADDING_CLASS && *obj != FORWARDED) {
+            &CLASS as *const usize as *mut c_void
+        } else {
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Check a single pair with all the capabilities of the format
+fn errors<const BUG: u8>() -> Vec<FormatCheckError> {
+    let mut check = FormatCheck::<Words<BUG>>::new();
+    unsafe {
+        check.sample(PAIR_SIZE, |obj| {
+            ptr::write(obj as *mut [usize; 3], [PAIR, 0x1000, 0x2000]);
+            vec![0x1000 as *mut c_void, 0x2000 as *mut c_void]
+        });
+    }
+    check.padding().moving().class().errors()
+}
+
+#[test]
+fn correct_format() {
+    let errors = errors::<NO_BUG>();
+    assert!(errors.is_empty(), "{:?}", errors);
+}
+
+#[test]
+fn wrong_size() {
+    let errors = errors::<WRONG_SIZE>();
+    // The forwarding object takes its size from skip, so it's also wrong
+    let kinds: Vec<ObjectKind> = errors.iter().map(|error| match *error {
+        FormatCheckError::WrongSize { object, expected, actual } => {
+            assert_eq!((expected, actual), (PAIR_SIZE, PAIR_SIZE - mem::size_of::<usize>()));
+            object.kind
+        },
+        ref error => panic!("Unexpected error: {}", error)
+    }).collect();
+    assert_eq!(kinds, [ObjectKind::Sample(0), ObjectKind::Forwarded(0)]);
+}
+
+#[test]
+fn missed_reference() {
+    let errors = errors::<MISSED_REFERENCE>();
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    match errors[0] {
+        FormatCheckError::WrongReferences { object, ref expected, ref visited } => {
+            assert_eq!(object.kind, ObjectKind::Sample(0));
+            assert_eq!(*expected, [0x1000 as *mut c_void, 0x2000 as *mut c_void]);
+            assert_eq!(*visited, [0x1000 as *mut c_void]);
+        },
+        ref error => panic!("Unexpected error: {}", error)
+    }
+}
+
+#[test]
+fn not_forwarded() {
+    let errors = errors::<NOT_FORWARDED>();
+    assert!(matches!(errors[..], [FormatCheckError::NotForwarded {
+        object, actual, ..
+    }] if object.kind == ObjectKind::Forwarded(0) && actual.is_null()), "{:?}", errors);
+}
+
+#[test]
+fn padding_class() {
+    let errors = errors::<PADDING_CLASS>();
+    // Every padding size is checked, from a single word up to the size of the pair
+    let sizes: Vec<ObjectKind> = errors.iter().map(|error| match *error {
+        FormatCheckError::NonNullClass { object, class } => {
+            assert_eq!(class, &CLASS as *const usize as *mut c_void);
+            object.kind
+        },
+        ref error => panic!("Unexpected error: {}", error)
+    }).collect();
+    let expected: Vec<ObjectKind> = (1..=3)
+        .map(|words| ObjectKind::Padding(words * mem::size_of::<usize>()))
+        .collect();
+    assert_eq!(sizes, expected);
+}
@@ -0,0 +1,118 @@
+//! Allocating typed objects with a [GcContext], and keeping them alive across collections
+use std::ffi::c_void;
+use std::ptr;
+
+use mps::arena::{Arena, VirtualMemoryArenaClass};
+use mps::format::{MpsFormat, ObjectFormat};
+use mps::gc::{Gc, GcContext};
+use mps::pools::AutomaticPool;
+use mps::pools::automatic_mostly_copying::AutoMostlyCopyingPool;
+use mps::pools::mark_sweep::AutoMarkSweep;
+
+#[derive(MpsFormat)]
+#[repr(usize)]
+enum Object {
+    #[mps(forward)]
+    Forwarded {
+        new: *mut Object,
+        size: usize
+    },
+    #[mps(pad)]
+    Padding {
+        size: usize
+    },
+    /// A value, and the next node in the list (or null)
+    Node(u64, #[mps(ref)] *mut Object)
+}
+
+const LENGTH: u64 = 1000;
+
+fn arena() -> Arena {
+    VirtualMemoryArenaClass::get().builder().build().unwrap()
+}
+
+/// Register the current thread, and pass the context to `func`
+fn run<'arena, P: AutomaticPool<'arena>>(pool: &P, func: fn(&mut GcContext<Object>)) {
+    let cold = 0usize;
+    let mut context = unsafe {
+        GcContext::<Object>::register(pool, &cold as *const usize as *mut c_void).unwrap()
+    };
+    func(&mut context);
+}
+
+/// Allocate a list, keeping only its head on the stack
+///
+/// This is called from [run], so its frame is below the cold end of the stack root.
+#[inline(never)]
+fn check_list(context: &mut GcContext<Object>) {
+    let mut head: Option<Gc<Object>> = None;
+    for value in 0..LENGTH {
+        let next = head.map_or(ptr::null_mut(), |head| Gc::as_raw(head).as_ptr());
+        head = Some(context.alloc_with(|| Object::Node(value, next)).unwrap());
+    }
+    context.arena().full_collection();
+    context.arena().release();
+    let mut current: *const Object = &*head.unwrap();
+    let (mut count, mut expected) = (0, LENGTH);
+    while !current.is_null() {
+        match unsafe { &*current } {
+            Object::Node(value, next) => {
+                expected -= 1;
+                assert_eq!(*value, expected);
+                current = *next;
+            },
+            _ => panic!("Expected a node")
+        }
+        count += 1;
+    }
+    assert_eq!(count, LENGTH);
+}
+
+/// Keep references only on the heap, where the MPS can't see them
+///
+/// The objects are pinned, so they are neither collected nor moved.
+#[inline(never)]
+fn heap_references(context: &mut GcContext<Object>) {
+    {
+        let nodes: Vec<Gc<Object>> = (0..LENGTH)
+            .map(|value| context.alloc_with(|| Object::Node(value, ptr::null_mut())).unwrap())
+            .collect();
+        assert_eq!(context.pinned(), LENGTH as usize);
+        let addresses: Vec<*mut Object> = nodes.iter().map(|&node| Gc::as_raw(node).as_ptr()).collect();
+        context.arena().full_collection();
+        context.arena().release();
+        for (expected, &node) in nodes.iter().enumerate() {
+            assert_eq!(Gc::as_raw(node).as_ptr(), addresses[expected], "Pinned object moved");
+            match *node {
+                Object::Node(value, _) => assert_eq!(value, expected as u64),
+                _ => panic!("Expected a node")
+            }
+        }
+    }
+    context.unpin_all();
+    assert_eq!(context.pinned(), 0);
+}
+
+#[test]
+fn survives_collection_ams() {
+    let arena = arena();
+    let format = ObjectFormat::managed_with::<Object>(&arena).unwrap();
+    let pool = AutoMarkSweep::builder(&arena).build(format).unwrap();
+    run(&pool, check_list);
+}
+
+#[test]
+fn survives_collection_amc() {
+    let arena = arena();
+    let format = ObjectFormat::managed_with::<Object>(&arena).unwrap();
+    let pool = AutoMostlyCopyingPool::builder(&arena).build(format).unwrap();
+    run(&pool, check_list);
+}
+
+#[test]
+fn heap_references_amc() {
+    let arena = arena();
+    let format = ObjectFormat::managed_with::<Object>(&arena).unwrap();
+    let pool = AutoMostlyCopyingPool::builder(&arena).build(format).unwrap();
+    run(&pool, heap_references);
+}
@@ -0,0 +1,62 @@
+//! Aborting on a panic with [install_panic_hook]
+//!
+//! The hook aborts the process, so each test runs itself again in a child process.
+#![cfg(target_os = "linux")]
+use std::env;
+use std::os::unix::process::ExitStatusExt;
+use std::panic;
+use std::process::{Command, Output};
+
+use mps::arena::VirtualMemoryArenaClass;
+use mps::postmortem::install_panic_hook;
+
+/// Set in the child process, to the name of the test to run
+const CHILD_VAR: &str = "MPS_TEST_POSTMORTEM_CHILD";
+
+/// Run the named test in a child process, or return `None` if this is the child
+fn run_child(name: &str) -> Option<Output> {
+    if env::var_os(CHILD_VAR).is_some() {
+        return None;
+    }
+    Some(Command::new(env::current_exe().unwrap())
+        .args(["--exact", name, "--nocapture", "--test-threads=1"])
+        .env(CHILD_VAR, name)
+        .output()
+        .unwrap())
+}
+
+#[test]
+fn aborts_on_caught_panic() {
+    match run_child("aborts_on_caught_panic") {
+        Some(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            // The previous hook still prints the panic
+            assert!(stderr.contains("caught by the test"), "{}", stderr);
+            assert_eq!(output.status.signal(), Some(libc::SIGABRT), "{}", stderr);
+        },
+        None => {
+            let arena = VirtualMemoryArenaClass::get().builder().build().unwrap();
+            install_panic_hook(&arena);
+            let _ = panic::catch_unwind(|| panic!("caught by the test"));
+            unreachable!("The hook didn't abort");
+        }
+    }
+}
+
+#[test]
+fn forgets_dropped_arena() {
+    match run_child("forgets_dropped_arena") {
+        Some(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            assert!(output.status.success(), "{}", stderr);
+            assert!(stderr.contains("caught by the test"), "{}", stderr);
+        },
+        None => {
+            let arena = VirtualMemoryArenaClass::get().builder().build().unwrap();
+            install_panic_hook(&arena);
+            drop(arena);
+            // The hook only calls the previous one
+            assert!(panic::catch_unwind(|| panic!("caught by the test")).is_err());
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! Nesting and resetting ramp allocation patterns with [RampGuard]s
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use mps::alloc::AllocationPoint;
+use mps::arena::{Arena, VirtualMemoryArenaClass};
+use mps::format::{MpsFormat, ObjectFormat};
+use mps::pools::Pool;
+use mps::pools::automatic_mostly_copying::AutoMostlyCopyingPool;
+
+#[derive(MpsFormat)]
+#[repr(usize)]
+enum Object {
+    #[mps(forward)]
+    Forwarded {
+        new: *mut Object,
+        size: usize
+    },
+    #[mps(pad)]
+    Padding {
+        size: usize
+    },
+    #[allow(dead_code)] // Only used for its size
+    Leaf(u64)
+}
+
+fn arena() -> Arena {
+    VirtualMemoryArenaClass::get().builder().build().unwrap()
+}
+
+/// Allocate some short-lived leaves
+fn alloc_leaves(ap: &AllocationPoint) {
+    for value in 0..100 {
+        unsafe {
+            ap.alloc_with(|ptr: *mut Object| ptr::write(ptr, Object::Leaf(value))).unwrap();
+        }
+    }
+}
+
+#[test]
+fn nested_ramps() {
+    let arena = arena();
+    let format = ObjectFormat::managed_with::<Object>(&arena).unwrap();
+    let pool = AutoMostlyCopyingPool::builder(&arena).build(format).unwrap();
+    let ap = pool.create_allocation_point().unwrap();
+    assert_eq!(ap.ramp_depth(), 0);
+    {
+        let outer = ap.begin_ramp().unwrap();
+        assert_eq!(outer.depth(), 1);
+        alloc_leaves(&ap);
+        {
+            let inner = ap.begin_ramp_collect_all().unwrap();
+            assert_eq!(inner.depth(), 2);
+            assert_eq!(ap.ramp_depth(), 2);
+            alloc_leaves(&ap);
+        }
+        assert_eq!(ap.ramp_depth(), 1);
+        alloc_leaves(&ap);
+    }
+    assert_eq!(ap.ramp_depth(), 0);
+    arena.full_collection();
+}
+
+#[test]
+fn out_of_order_drop() {
+    let arena = arena();
+    let format = ObjectFormat::managed_with::<Object>(&arena).unwrap();
+    let pool = AutoMostlyCopyingPool::builder(&arena).build(format).unwrap();
+    let ap = pool.create_allocation_point().unwrap();
+    let outer = ap.begin_ramp().unwrap();
+    let inner = ap.begin_ramp().unwrap();
+    let err = panic::catch_unwind(AssertUnwindSafe(|| drop(outer))).unwrap_err();
+    let message = err.downcast_ref::<String>().unwrap();
+    assert!(message.contains("Nested ramps must end in reverse order"), "{}", message);
+    // Neither ramp was ended, so clean up with a reset
+    assert_eq!(ap.ramp_depth(), 2);
+    ap.reset_alloc_patterns();
+    drop(inner);
+    assert_eq!(ap.ramp_depth(), 0);
+}
+
+#[test]
+fn reset_makes_guards_stale() {
+    let arena = arena();
+    let format = ObjectFormat::managed_with::<Object>(&arena).unwrap();
+    let pool = AutoMostlyCopyingPool::builder(&arena).build(format).unwrap();
+    let ap = pool.create_allocation_point().unwrap();
+    let outer = ap.begin_ramp().unwrap();
+    let inner = ap.begin_ramp().unwrap();
+    alloc_leaves(&ap);
+    ap.reset_alloc_patterns();
+    assert_eq!(ap.ramp_depth(), 0);
+    // A new ramp starts from the top again
+    let fresh = ap.begin_ramp().unwrap();
+    assert_eq!(fresh.depth(), 1);
+    // The stale guards do nothing, even though they are dropped out of order
+    drop(outer);
+    drop(inner);
+    assert_eq!(ap.ramp_depth(), 1);
+    // Resetting through a guard makes the others stale too
+    let nested = ap.begin_ramp().unwrap();
+    fresh.reset();
+    assert_eq!(ap.ramp_depth(), 0);
+    drop(nested);
+    assert_eq!(ap.ramp_depth(), 0);
+}
@@ -0,0 +1,97 @@
+//! Tracking an arena's live handles with [Arena::handles]
+use mps::arena::{Arena, VirtualMemoryArenaClass};
+use mps::format::{MpsFormat, ObjectFormat};
+use mps::pools::Pool;
+use mps::pools::automatic_mostly_copying::AutoMostlyCopyingPool;
+use mps::pools::manual_first_fit::ManualFirstFitPool;
+use mps::registry::HandleKind;
+
+#[derive(MpsFormat)]
+#[repr(usize)]
+enum Object {
+    #[mps(forward)]
+    Forwarded {
+        new: *mut Object,
+        size: usize
+    },
+    #[mps(pad)]
+    Padding {
+        size: usize
+    },
+    #[allow(dead_code)] // Only used for its size
+    Leaf(u64)
+}
+
+fn arena() -> Arena {
+    VirtualMemoryArenaClass::get().builder().build().unwrap()
+}
+
+fn kinds(arena: &Arena) -> Vec<HandleKind> {
+    arena.handles().iter().map(|handle| handle.kind).collect()
+}
+
+#[test]
+fn lists_handles_in_order() {
+    let arena = arena();
+    assert!(arena.handles().is_empty());
+    let format = ObjectFormat::managed_with::<Object>(&arena).unwrap();
+    let pool = AutoMostlyCopyingPool::builder(&arena).build(format).unwrap();
+    let ap = pool.create_allocation_point().unwrap();
+    let thread = arena.register_thread().unwrap();
+    assert_eq!(kinds(&arena), [
+        HandleKind::Format, HandleKind::Pool,
+        HandleKind::AllocationPoint, HandleKind::Thread
+    ]);
+    let handles = arena.handles();
+    assert!(handles.windows(2).all(|pair| pair[0].id < pair[1].id));
+    assert_eq!(handles[0].class, None);
+    assert_eq!(handles[1].class, Some("amc"));
+    assert_eq!(handles[1].to_string(), format!("pool #{} (amc)", handles[1].id));
+    drop(thread);
+    drop(ap);
+    drop(pool);
+}
+
+#[test]
+fn unregisters_on_drop() {
+    let arena = arena();
+    let first = ManualFirstFitPool::builder(&arena).build().unwrap();
+    let ap = first.create_allocation_point().unwrap();
+    assert_eq!(kinds(&arena), [HandleKind::Pool, HandleKind::AllocationPoint]);
+    drop(ap);
+    assert_eq!(kinds(&arena), [HandleKind::Pool]);
+    let first_id = arena.handles()[0].id;
+    drop(first);
+    assert!(arena.handles().is_empty());
+    // Ids are never reused
+    let second = ManualFirstFitPool::builder(&arena).build().unwrap();
+    assert!(arena.handles()[0].id > first_id);
+    drop(second);
+    assert!(arena.handles().is_empty());
+}
+
+#[test]
+#[cfg(debug_assertions)]
+fn panics_when_dropped_with_live_handles() {
+    use std::env;
+    use std::process::Command;
+
+    const NAME: &str = "panics_when_dropped_with_live_handles";
+    /// Set in the child process, to the name of the test to run
+    const CHILD_VAR: &str = "MPS_TEST_REGISTRY_CHILD";
+    if env::var_os(CHILD_VAR).is_none() {
+        // Leaking the arena in the child doesn't matter
+        let output = Command::new(env::current_exe().unwrap())
+            .args(["--exact", NAME, "--nocapture", "--test-threads=1"])
+            .env(CHILD_VAR, NAME)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!output.status.success(), "{}", stderr);
+        assert!(stderr.contains("Arena dropped while its handles are still alive: pool #0 (mvff)"), "{}", stderr);
+        return;
+    }
+    let arena = arena();
+    std::mem::forget(ManualFirstFitPool::builder(&arena).build().unwrap());
+    drop(arena);
+}
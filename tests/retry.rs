@@ -0,0 +1,105 @@
+//! Recovering from the commit limit with a [RetryPolicy]
+use std::cell::Cell;
+use std::rc::Rc;
+
+use mps::MpsError;
+use mps::alloc::AllocationPoint;
+use mps::arena::{Arena, VirtualMemoryArenaClass};
+use mps::format::{MpsFormat, ObjectFormat};
+use mps::pools::Pool;
+use mps::pools::automatic_mostly_copying::AutoMostlyCopyingPool;
+use mps::retry::RetryPolicy;
+
+#[derive(MpsFormat)]
+#[repr(usize)]
+#[allow(clippy::large_enum_variant)] // Large blobs reach the limit quickly
+enum Object {
+    #[mps(forward)]
+    Forwarded {
+        new: *mut Object,
+        size: usize
+    },
+    #[mps(pad)]
+    Padding {
+        size: usize
+    },
+    #[allow(dead_code)] // Only used for its size
+    Blob([u64; 511])
+}
+
+/// The room left for garbage, above the memory committed before allocating
+const MARGIN: usize = 4 * 1024 * 1024;
+
+fn arena() -> Arena {
+    VirtualMemoryArenaClass::get().builder().build().unwrap()
+}
+
+/// Allocate a blob that is immediately garbage
+#[inline(never)]
+fn alloc_blob(ap: &AllocationPoint) -> Result<(), MpsError> {
+    unsafe {
+        ap.alloc_with(|ptr: *mut Object| ptr.write(Object::Blob([0; 511])))?;
+    }
+    Ok(())
+}
+
+/// Allocate garbage until the commit limit is reached
+fn fill_with_garbage(ap: &AllocationPoint) {
+    for _ in 0..(64 * MARGIN / std::mem::size_of::<Object>()) {
+        match alloc_blob(ap) {
+            Ok(()) => {},
+            Err(MpsError::CommitLimit) => return,
+            Err(e) => panic!("Unexpected error: {:?}", e)
+        }
+    }
+    panic!("Never reached the commit limit");
+}
+
+/// A policy that counts its attempts,
+/// retrying only if `retry` is true
+fn counting_policy(arena: &Arena, retry: bool) -> (RetryPolicy<'_>, Rc<Cell<u32>>) {
+    let attempts = Rc::new(Cell::new(0));
+    let counter = Rc::clone(&attempts);
+    let policy = RetryPolicy::new(arena)
+        .max_attempts(3)
+        .on_out_of_memory(move |oom| {
+            assert!(matches!(*oom.error, MpsError::CommitLimit), "{:?}", oom.error);
+            assert_eq!(oom.size, std::mem::size_of::<Object>());
+            counter.set(oom.attempt);
+            retry
+        });
+    (policy, attempts)
+}
+
+#[test]
+fn retries_after_collection() {
+    let arena = arena();
+    let format = ObjectFormat::managed_with::<Object>(&arena).unwrap();
+    let pool = AutoMostlyCopyingPool::builder(&arena).build(format).unwrap();
+    let ap = pool.create_allocation_point().unwrap();
+    arena.set_commit_limit(arena.committed() + MARGIN).unwrap();
+    fill_with_garbage(&ap);
+    let (policy, attempts) = counting_policy(&arena, true);
+    assert!(ap.set_retry_policy(Some(policy)).is_none());
+    // The collection frees the garbage
+    alloc_blob(&ap).unwrap();
+    assert!(attempts.get() >= 1);
+    // Only failed reservations use the policy
+    attempts.set(0);
+    alloc_blob(&ap).unwrap();
+    assert_eq!(attempts.get(), 0);
+}
+
+#[test]
+fn handler_can_give_up() {
+    let arena = arena();
+    let format = ObjectFormat::managed_with::<Object>(&arena).unwrap();
+    let pool = AutoMostlyCopyingPool::builder(&arena).build(format).unwrap();
+    let ap = pool.create_allocation_point().unwrap();
+    arena.set_commit_limit(arena.committed() + MARGIN).unwrap();
+    fill_with_garbage(&ap);
+    let (policy, attempts) = counting_policy(&arena, false);
+    ap.set_retry_policy(Some(policy.collect(false)));
+    assert!(matches!(alloc_blob(&ap), Err(MpsError::CommitLimit)));
+    assert_eq!(attempts.get(), 1);
+}
@@ -0,0 +1,92 @@
+//! Allocating and freeing through a [SegregatedCache],
+//! including the inline free lists and the fill/empty slow paths
+use std::collections::HashSet;
+use std::ptr;
+
+use mps::arena::{Arena, VirtualMemoryArenaClass};
+use mps::pools::manual_first_fit::ManualFirstFitPool;
+use mps::segregated_cache::{SegregatedCache, SizeClass};
+use mps_sys::mps_addr_t;
+
+/// The number of blocks cached for each class
+const CACHED_COUNT: usize = 4;
+
+fn arena() -> Arena {
+    VirtualMemoryArenaClass::get().builder().build().unwrap()
+}
+
+fn classes() -> Vec<SizeClass> {
+    [16, 64, 256].iter().map(|&block_size| SizeClass {
+        block_size, cached_count: CACHED_COUNT, frequency: 1
+    }).collect()
+}
+
+/// Fill a block with a byte, and check it afterwards
+unsafe fn fill(block: mps_addr_t, size: usize, byte: u8) {
+    ptr::write_bytes(block as *mut u8, byte, size);
+}
+unsafe fn check(block: mps_addr_t, size: usize, byte: u8) {
+    let bytes = std::slice::from_raw_parts(block as *const u8, size);
+    assert!(bytes.iter().all(|&b| b == byte), "Block {:?} was overwritten", block);
+}
+
+#[test]
+fn round_trip() {
+    let arena = arena();
+    let pool = ManualFirstFitPool::builder(&arena).build().unwrap();
+    let cache = SegregatedCache::create(&pool, &classes()).unwrap();
+    // Every class, sizes between classes, and a size larger than every class
+    for &size in &[8, 16, 24, 64, 128, 256, 1024] {
+        unsafe {
+            let block = cache.alloc(size).unwrap();
+            assert!(!block.is_null());
+            assert_eq!(block as usize % std::mem::align_of::<*mut u8>(), 0);
+            fill(block, size, 0xAB);
+            check(block, size, 0xAB);
+            cache.free(block, size);
+        }
+    }
+    cache.flush();
+}
+
+#[test]
+fn reuses_freed_blocks() {
+    let arena = arena();
+    let pool = ManualFirstFitPool::builder(&arena).build().unwrap();
+    let cache = SegregatedCache::create(&pool, &classes()).unwrap();
+    unsafe {
+        let block = cache.alloc(64).unwrap();
+        cache.free(block, 64);
+        // The free list for the class isn't full, so the block stays in the cache
+        assert_eq!(cache.alloc(64).unwrap(), block);
+        cache.free(block, 64);
+    }
+}
+
+#[test]
+fn fill_and_empty() {
+    let arena = arena();
+    let pool = ManualFirstFitPool::builder(&arena).build().unwrap();
+    let cache = SegregatedCache::create(&pool, &classes()).unwrap();
+    let count = CACHED_COUNT * 4;
+    for round in 0..3u8 {
+        unsafe {
+            // More blocks than the cache holds, so the free lists are filled and emptied
+            let blocks: Vec<mps_addr_t> = (0..count)
+                .map(|_| cache.alloc(256).unwrap())
+                .collect();
+            let distinct: HashSet<mps_addr_t> = blocks.iter().copied().collect();
+            assert_eq!(distinct.len(), count, "Live blocks were allocated twice");
+            for (index, &block) in blocks.iter().enumerate() {
+                fill(block, 256, round.wrapping_add(index as u8));
+            }
+            for (index, &block) in blocks.iter().enumerate() {
+                check(block, 256, round.wrapping_add(index as u8));
+            }
+            for &block in &blocks {
+                cache.free(block, 256);
+            }
+        }
+    }
+    cache.flush();
+}
@@ -0,0 +1,51 @@
+//! Chaining a `SIGSEGV` handler on top of the MPS protection handler
+//!
+//! Each ordering is tested in its own process,
+//! because the MPS only installs its handler once.
+#![cfg(target_os = "linux")]
+use std::ffi::c_void;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use mps::arena::VirtualMemoryArenaClass;
+use mps::signal::{self, ProtectionHandler};
+
+static GUARD: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+static FAULT: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+
+unsafe fn handle_guard_fault(info: &libc::siginfo_t, _context: *mut c_void) -> bool {
+    let guard = GUARD.load(Ordering::Acquire);
+    if info.si_addr() != guard {
+        return false;
+    }
+    FAULT.store(info.si_addr(), Ordering::Release);
+    libc::mprotect(guard, page_size(), libc::PROT_READ | libc::PROT_WRITE) == 0
+}
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+#[test]
+fn chained_after_mps() {
+    assert_eq!(signal::protection_handler(), ProtectionHandler::NotInstalled);
+    let arena = VirtualMemoryArenaClass::get().builder().build().unwrap();
+    assert_eq!(signal::protection_handler(), ProtectionHandler::Current);
+    unsafe {
+        signal::install_chained_handler(handle_guard_fault).unwrap();
+    }
+    assert_eq!(signal::protection_handler(), ProtectionHandler::Chained);
+    unsafe {
+        let guard = libc::mmap(
+            ptr::null_mut(), page_size(), libc::PROT_NONE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0
+        );
+        assert_ne!(guard, libc::MAP_FAILED);
+        GUARD.store(guard, Ordering::Release);
+        ptr::write_volatile(guard as *mut u8, 42);
+        assert_eq!(FAULT.load(Ordering::Acquire), guard);
+        assert_eq!(ptr::read_volatile(guard as *mut u8), 42);
+        libc::munmap(guard, page_size());
+    }
+    // The MPS still works underneath the chained handler
+    arena.full_collection();
+}
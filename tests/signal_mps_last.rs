@@ -0,0 +1,58 @@
+//! Creating the first arena when another `SIGSEGV` handler is already installed
+//!
+//! Each ordering is tested in its own process,
+//! because the MPS only installs its handler once.
+#![cfg(target_os = "linux")]
+use std::ffi::c_void;
+use std::mem;
+use std::os::raw::c_int;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use mps::arena::VirtualMemoryArenaClass;
+use mps::signal::{self, ProtectionHandler};
+
+static GUARD: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+static FAULT: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+
+/// Imitates a crash reporter, which needs the real fault address
+unsafe extern "C" fn existing_handler(_signal: c_int, info: *mut libc::siginfo_t, _context: *mut c_void) {
+    let guard = GUARD.load(Ordering::Acquire);
+    if (*info).si_addr() == guard {
+        FAULT.store(guard, Ordering::Release);
+        libc::mprotect(guard, page_size(), libc::PROT_READ | libc::PROT_WRITE);
+    } else {
+        libc::signal(libc::SIGSEGV, libc::SIG_DFL);
+    }
+}
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+#[test]
+fn existing_handler_before_mps() {
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = existing_handler as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO;
+        libc::sigemptyset(&mut action.sa_mask);
+        assert_eq!(libc::sigaction(libc::SIGSEGV, &action, ptr::null_mut()), 0);
+    }
+    assert_eq!(signal::protection_handler(), ProtectionHandler::NotInstalled);
+    let arena = VirtualMemoryArenaClass::get().builder().build().unwrap();
+    assert_eq!(signal::protection_handler(), ProtectionHandler::Chained);
+    unsafe {
+        let guard = libc::mmap(
+            ptr::null_mut(), page_size(), libc::PROT_NONE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0
+        );
+        assert_ne!(guard, libc::MAP_FAILED);
+        GUARD.store(guard, Ordering::Release);
+        ptr::write_volatile(guard as *mut u8, 42);
+        // The fault reached the existing handler with its real address
+        assert_eq!(FAULT.load(Ordering::Acquire), guard);
+        assert_eq!(ptr::read_volatile(guard as *mut u8), 42);
+        libc::munmap(guard, page_size());
+    }
+    arena.full_collection();
+}
@@ -0,0 +1,87 @@
+//! Reading the statistics of an arena and its pools with [Arena::stats]
+use mps::arena::{Arena, VirtualMemoryArenaClass};
+use mps::format::{MpsFormat, ObjectFormat};
+use mps::pools::{ManualPool, Pool};
+use mps::pools::automatic_mostly_copying::AutoMostlyCopyingPool;
+use mps::pools::manual_first_fit::ManualFirstFitPool;
+use mps::registry::HandleKind;
+
+#[derive(MpsFormat)]
+#[repr(usize)]
+enum Object {
+    #[mps(forward)]
+    Forwarded {
+        new: *mut Object,
+        size: usize
+    },
+    #[mps(pad)]
+    Padding {
+        size: usize
+    },
+    #[allow(dead_code)] // Only used for its size
+    Leaf(u64)
+}
+
+/// The ids of the arena's live pools, in order of creation
+fn pool_ids(arena: &Arena) -> Vec<usize> {
+    arena.handles().iter()
+        .filter(|handle| handle.kind == HandleKind::Pool)
+        .map(|handle| handle.id)
+        .collect()
+}
+
+#[test]
+fn two_pools() {
+    let arena = VirtualMemoryArenaClass::get().builder().build().unwrap();
+    let manual = ManualFirstFitPool::builder(&arena).build().unwrap();
+    let format = ObjectFormat::managed_with::<Object>(&arena).unwrap();
+    let automatic = AutoMostlyCopyingPool::builder(&arena).build(format).unwrap();
+    let block = unsafe { manual.alloc(4096).unwrap() };
+    let stats = arena.stats();
+    // The format isn't a pool
+    assert_eq!(stats.pools.len(), 2);
+    assert_eq!(stats.pools.iter().map(|pool| pool.id).collect::<Vec<_>>(), pool_ids(&arena));
+    assert_eq!(stats.pools[0].class, "mvff");
+    assert_eq!(stats.pools[1].class, "amc");
+    assert!(stats.pools.iter().all(|pool| pool.name.is_none()));
+    assert_eq!(stats.pools[0].total_size, manual.total_size());
+    assert!(stats.pools[0].total_size - stats.pools[0].free_size >= 4096);
+    assert_eq!(stats.pools[1].total_size, automatic.total_size());
+    assert!(stats.committed >= stats.pools[0].total_size);
+    assert_eq!(stats.commit_limit, arena.commit_limit());
+    unsafe { manual.free(block, 4096) };
+    // Dropped pools disappear from the snapshot
+    let manual_id = stats.pools[0].id;
+    drop(manual);
+    let stats = arena.stats();
+    assert_eq!(stats.pools.len(), 1);
+    assert_eq!(stats.pools[0].class, "amc");
+    assert_ne!(stats.pools[0].id, manual_id);
+}
+
+#[test]
+fn named_pools() {
+    let arena = VirtualMemoryArenaClass::get().builder().build().unwrap();
+    let named = ManualFirstFitPool::builder(&arena).name("strings \"cache\"").build().unwrap();
+    let unnamed = ManualFirstFitPool::builder(&arena).build().unwrap();
+    let ap = named.allocation_point_builder().name("string builder").build().unwrap();
+    let handles = arena.handles();
+    assert_eq!(handles[0].name.as_deref(), Some("strings \"cache\""));
+    assert_eq!(handles[1].name, None);
+    assert_eq!(handles[2].kind, HandleKind::AllocationPoint);
+    assert_eq!(handles[2].name.as_deref(), Some("string builder"));
+    let stats = arena.stats();
+    assert_eq!(stats.pools[0].name.as_deref(), Some("strings \"cache\""));
+    assert_eq!(stats.pools[1].name, None);
+    let metrics = stats.prometheus().to_string();
+    assert!(metrics.contains(&format!(
+        "mps_pool_total_size_bytes{{pool=\"{}\",class=\"mvff\",name=\"strings \\\"cache\\\"\"}} ",
+        stats.pools[0].id
+    )), "{}", metrics);
+    assert!(metrics.contains(&format!(
+        "mps_pool_total_size_bytes{{pool=\"{}\",class=\"mvff\"}} ", stats.pools[1].id
+    )), "{}", metrics);
+    drop(ap);
+    drop(unnamed);
+    drop(named);
+}
@@ -0,0 +1,130 @@
+//! Allocating under a [StressMode], and verifying the heap
+#![cfg(feature = "stress")]
+use std::ffi::c_void;
+use std::ptr;
+
+use mps::arena::{Arena, VirtualMemoryArenaClass};
+use mps::format::{MpsFormat, ObjectFormat};
+use mps::gc::{Gc, GcContext};
+use mps::pools::AutomaticPool;
+use mps::pools::automatic_mostly_copying::AutoMostlyCopyingPool;
+use mps::pools::mark_sweep::AutoMarkSweep;
+use mps::stress::{verify_heap, HeapError, StressMode};
+
+#[derive(MpsFormat)]
+#[repr(usize)]
+enum Object {
+    #[mps(forward)]
+    Forwarded {
+        new: *mut Object,
+        size: usize
+    },
+    #[mps(pad)]
+    Padding {
+        size: usize
+    },
+    /// A value, and the next node in the list (or null)
+    Node(u64, #[mps(ref)] *mut Object)
+}
+
+/// Every allocation collects and verifies the whole heap, so keep this short
+const LENGTH: u64 = 100;
+
+fn arena() -> Arena {
+    VirtualMemoryArenaClass::get().builder().build().unwrap()
+}
+
+/// Register the current thread, and pass the context to `func`
+fn run<'arena, P: AutomaticPool<'arena>>(pool: &P, func: fn(&GcContext<Object>)) {
+    let cold = 0usize;
+    let context = unsafe {
+        GcContext::<Object>::register(pool, &cold as *const usize as *mut c_void).unwrap()
+    };
+    func(&context);
+}
+
+/// Allocate a list with a collection (and verification) after every allocation
+///
+/// This is called from [run], so its frame is below the cold end of the stack root.
+#[inline(never)]
+fn stressed_list(context: &GcContext<Object>) {
+    let ap = context.allocation_point();
+    ap.set_stress_mode(Some(StressMode::new(context.arena(), 1).verify(true)));
+    let mut head: Option<Gc<Object>> = None;
+    for value in 0..LENGTH {
+        let next = head.map_or(ptr::null_mut(), |head| Gc::as_raw(head).as_ptr());
+        head = Some(context.alloc_with(|| Object::Node(value, next)).unwrap());
+    }
+    let mode = ap.set_stress_mode(None).unwrap();
+    assert_eq!(mode.allocations(), LENGTH as usize);
+    let mut current: *const Object = &*head.unwrap();
+    let mut expected = LENGTH;
+    while !current.is_null() {
+        match unsafe { &*current } {
+            Object::Node(value, next) => {
+                expected -= 1;
+                assert_eq!(*value, expected);
+                current = *next;
+            },
+            _ => panic!("Expected a node")
+        }
+    }
+    assert_eq!(expected, 0);
+    assert!(verify_heap(context.arena()).is_empty());
+}
+
+#[test]
+fn stressed_list_ams() {
+    let arena = arena();
+    let format = ObjectFormat::managed_with::<Object>(&arena).unwrap();
+    let pool = AutoMarkSweep::builder(&arena).build(format).unwrap();
+    run(&pool, stressed_list);
+}
+
+#[test]
+fn stressed_list_amc() {
+    let arena = arena();
+    let format = ObjectFormat::managed_with::<Object>(&arena).unwrap();
+    let pool = AutoMostlyCopyingPool::builder(&arena).build(format).unwrap();
+    run(&pool, stressed_list);
+}
+
+/// Plant a reference into the middle of a live object, and check that verification finds it
+///
+/// A reference to a collected object can't be produced reliably
+/// (the stack is scanned ambiguously), but it looks the same to the verifier.
+#[inline(never)]
+fn dangling_reference(context: &GcContext<Object>) {
+    let target = context.alloc_with(|| Object::Node(1, ptr::null_mut())).unwrap();
+    let holder = context.alloc_with(|| Object::Node(2, ptr::null_mut())).unwrap();
+    let target = Gc::as_raw(target).as_ptr();
+    let holder = Gc::as_raw(holder).as_ptr();
+    let dangling = unsafe { (target as *mut u8).add(std::mem::size_of::<usize>()) as *mut Object };
+    let set_next = |next: *mut Object| match unsafe { &mut *holder } {
+        Object::Node(_, old) => *old = next,
+        _ => unreachable!()
+    };
+    // NOTE: The MPS must never scan the planted reference, so no collection may run meanwhile
+    context.arena().park();
+    set_next(dangling);
+    let errors = verify_heap(context.arena());
+    set_next(ptr::null_mut());
+    context.arena().release();
+    match errors[..] {
+        [HeapError::Dangling { object, reference }] => {
+            assert_eq!(object, holder as *mut c_void);
+            assert_eq!(reference, dangling as *mut c_void);
+        },
+        _ => panic!("Unexpected errors: {:?}", errors)
+    }
+    // The target must stay alive until the reference is removed
+    assert!(matches!(unsafe { &*target }, Object::Node(1, _)));
+}
+
+#[test]
+fn dangling_reference_ams() {
+    let arena = arena();
+    let format = ObjectFormat::managed_with::<Object>(&arena).unwrap();
+    let pool = AutoMarkSweep::builder(&arena).build(format).unwrap();
+    run(&pool, dangling_reference);
+}
@@ -0,0 +1,35 @@
+//! Checking [VARIETY] against the `variety-*` features
+//!
+//! Run with each of the features to check them all,
+//! like `cargo test --test variety --features variety-rash`.
+use mps::{Variety, VARIETY};
+
+#[test]
+fn matches_mps_sys() {
+    assert_eq!(VARIETY.name(), mps::__sys::MPS_VARIETY);
+}
+
+#[test]
+#[cfg(feature = "variety-cool")]
+fn cool_feature() {
+    assert_eq!(VARIETY, Variety::Cool);
+}
+
+#[test]
+#[cfg(feature = "variety-hot")]
+fn hot_feature() {
+    assert_eq!(VARIETY, Variety::Hot);
+}
+
+#[test]
+#[cfg(feature = "variety-rash")]
+fn rash_feature() {
+    assert_eq!(VARIETY, Variety::Rash);
+}
+
+#[test]
+#[cfg(not(any(feature = "variety-cool", feature = "variety-hot", feature = "variety-rash")))]
+fn no_feature() {
+    // Depends on whether the build script was run with debug info
+    assert_ne!(VARIETY, Variety::Rash);
+}
@@ -0,0 +1,88 @@
+//! Allocating through zerogc's collector API, backed by the MPS
+#![cfg(feature = "zerogc")]
+use std::ffi::c_void;
+use std::sync::Mutex;
+
+use mps::arena::VirtualMemoryArenaClass;
+use mps::zerogc::{MpsContext, MpsSystem, SafepointMode};
+use zerogc::GcContext;
+
+/// Only one system may exist at a time
+static SYSTEM_LOCK: Mutex<()> = Mutex::new(());
+
+const VALUES: u64 = 1000;
+
+/// Create a system and a context for the current thread, and pass the context to `func`
+fn with_context(mode: SafepointMode, debug_mode: bool, func: fn(&mut MpsContext)) {
+    let _guard = SYSTEM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut builder = VirtualMemoryArenaClass::get().builder();
+    builder.debug_mode = debug_mode;
+    let system = MpsSystem::create(builder, mode).unwrap();
+    let cold = 0usize;
+    let mut context = unsafe { system.create_context(&cold as *const usize as *mut c_void).unwrap() };
+    func(&mut context);
+}
+
+/// Run a full collection, and let the MPS collect again
+fn collect(context: &MpsContext) {
+    let arena = context.system().arena();
+    arena.full_collection();
+    arena.release();
+}
+
+/// Allocate some values, and check they survive a safepoint and a full collection
+///
+/// The values are only referenced by raw pointers on the stack (which is an ambiguous root),
+/// because a safepoint needs the context to be borrowed mutably.
+/// This is called from [with_context], so its frame is below the cold end of the stack root.
+#[inline(never)]
+fn allocate_and_survive(context: &mut MpsContext) {
+    let values: Vec<*const u64> = (0..VALUES)
+        .map(|value| &*context.try_alloc(value).unwrap() as *const u64)
+        .collect();
+    // NOTE: The vector is invisible to the MPS, so keep the first and last values on the stack
+    let (first, last) = (values[0], values[values.len() - 1]);
+    let mut root = 0u64;
+    unsafe { context.basic_safepoint(&mut &mut root); }
+    collect(context);
+    assert_eq!(unsafe { *first }, 0);
+    assert_eq!(unsafe { *last }, VALUES - 1);
+}
+
+/// Allocate in a child context, then check both the child and parent values
+#[inline(never)]
+fn recurse(context: &mut MpsContext) {
+    let parent = &*context.try_alloc(1u64).unwrap() as *const u64;
+    let mut root = 2u64;
+    let child = unsafe {
+        context.recurse_context(&mut &mut root, |child, root| {
+            let value = &*child.try_alloc(*root + 1).unwrap() as *const u64;
+            child.basic_safepoint(&mut &mut *root);
+            assert_eq!(*value, 3);
+            value
+        })
+    };
+    collect(context);
+    assert_eq!(unsafe { *parent }, 1);
+    assert_eq!(unsafe { *child }, 3);
+}
+
+#[test]
+fn step_mode() {
+    with_context(SafepointMode::default(), false, allocate_and_survive);
+}
+
+#[test]
+fn parked_mode() {
+    with_context(SafepointMode::Parked { interval: 0.01 }, false, allocate_and_survive);
+}
+
+#[test]
+fn debug_mode_collects_at_safepoints() {
+    with_context(SafepointMode::Parked { interval: 0.01 }, true, allocate_and_survive);
+}
+
+#[test]
+fn recurse_context() {
+    with_context(SafepointMode::default(), false, recurse);
+}